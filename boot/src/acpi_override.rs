@@ -0,0 +1,268 @@
+//! ACPI table overrides.
+//!
+//! After downloading the kernel, we fetch a list of ACPI tables from the boot
+//! server (see [`ACPI_LIST_FILENAME`]). This is a text file with one filename
+//! per line (blank lines and lines starting with '#' are ignored). Each file
+//! is a raw ACPI table (ie. compiled with `iasl`), and the signature in its
+//! header decides what happens to it:
+//!
+//! - `DSDT` replaces the DSDT (in a copy of the FADT)
+//! - `SSDT` is always added alongside the existing SSDTs
+//! - Anything else replaces the first table with the same signature (or is
+//!   added if there isn't one)
+//!
+//! If anything changed, we build a new XSDT and RSDP, and pass the new RSDP
+//! to the kernel. Firmware tables are never modified in place. All of the
+//! new tables are allocated as `EfiACPIReclaimMemory`, just like the tables
+//! from firmware.
+//!
+//! The list is optional: when it doesn't exist, the kernel gets the RSDP
+//! from firmware.
+
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+use uefi::{ println, CStr8, cstr8 };
+use uefi::boot::{ AllocateType, MemoryType, PAGE_SIZE, allocate_pages };
+
+use crate::net::NetClient;
+
+/// Fixed remote filename of the ACPI table list on the boot server
+pub const ACPI_LIST_FILENAME: &'static CStr8 = cstr8!("mrld-acpi");
+
+/// Maximum length of a table filename
+const MAX_NAME_LEN: usize = 64;
+
+/// Size of the common ACPI table header (in bytes)
+const SDT_HEADER_SIZE: usize = 36;
+
+/// Size of an ACPI 2.0+ RSDP (in bytes)
+const RSDP_SIZE: usize = 36;
+
+/// Offset of the 32-bit 'DSDT' field in the FADT
+const FADT_DSDT_OFFSET: usize = 40;
+/// Offset of the 64-bit 'X_DSDT' field in the FADT
+const FADT_X_DSDT_OFFSET: usize = 140;
+
+/// Return a new checksum for 'data', such that the sum of all bytes is zero
+/// (assuming the old checksum byte was zero).
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)).wrapping_neg()
+}
+
+/// Allocate and zero ACPI reclaim memory for a new table.
+fn allocate_table(size: usize) -> uefi::Result<&'static mut [u8]> {
+    let ptr: NonNull<u8> = allocate_pages(
+        AllocateType::AnyPages,
+        MemoryType::ACPI_RECLAIM,
+        size.div_ceil(PAGE_SIZE),
+    )?;
+    let buf = unsafe {
+        NonNull::slice_from_raw_parts(ptr, size).as_mut()
+    };
+    buf.fill(0);
+    Ok(buf)
+}
+
+/// Return a reference to the ACPI table at 'addr' (using the length from
+/// its header).
+unsafe fn table_at(addr: u64) -> &'static [u8] {
+    let len = (addr as *const u8).add(4).cast::<u32>().read_unaligned();
+    core::slice::from_raw_parts(addr as *const u8, len as usize)
+}
+
+/// Set the checksum in the header of an ACPI table.
+fn fix_table_checksum(table: &mut [u8]) {
+    table[9] = 0;
+    table[9] = checksum(table);
+}
+
+/// Return the signature of an ACPI table (or "????" if it isn't ASCII).
+fn signature(table: &[u8]) -> &str {
+    core::str::from_utf8(&table[..4]).unwrap_or("????")
+}
+
+/// Download a single ACPI table, and make sure it looks valid.
+fn download_table(net: &mut NetClient, name: &str)
+    -> Option<&'static mut [u8]>
+{
+    // TFTP expects a NUL-terminated filename
+    let mut buf = [0u8; MAX_NAME_LEN + 1];
+    if name.len() > MAX_NAME_LEN {
+        println!("[!] ACPI table name '{}' is too long", name);
+        return None;
+    }
+    buf[..name.len()].copy_from_slice(name.as_bytes());
+    let filename = CStr8::from_bytes_with_nul(&buf[..=name.len()]).ok()?;
+
+    let (ptr, size) = match net.download(filename, MemoryType::ACPI_RECLAIM) {
+        Ok(res) => res,
+        Err(e) => {
+            println!("[!] Couldn't download ACPI table '{}': {}", name, e);
+            return None;
+        },
+    };
+    let data = unsafe { NonNull::slice_from_raw_parts(ptr, size).as_mut() };
+    if size < SDT_HEADER_SIZE {
+        println!("[!] ACPI table '{}' is too small ({}B)", name, size);
+        return None;
+    }
+    let len = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+    if len < SDT_HEADER_SIZE || len > size {
+        println!("[!] ACPI table '{}' has a bad length ({}B, file is {}B)",
+            name, len, size
+        );
+        return None;
+    }
+    let table = &mut data[..len];
+    if checksum(table) != 0 {
+        println!("[!] ACPI table '{}' has a bad checksum, fixing it", name);
+        fix_table_checksum(table);
+    }
+    Some(table)
+}
+
+/// Download the tables in the ACPI table list and apply them.
+/// Returns the address of the RSDP that should be passed to the kernel.
+pub fn apply_overrides(net: &mut NetClient, rsdp_addr: u64) -> u64 {
+    let res = net.download(ACPI_LIST_FILENAME, MemoryType::LOADER_DATA);
+    let (list_ptr, list_size) = match res {
+        Ok(res) => res,
+        Err(e) => {
+            println!("[*] No ACPI table list ({:?}), using firmware tables",
+                e.status()
+            );
+            return rsdp_addr;
+        },
+    };
+    let list = unsafe {
+        NonNull::slice_from_raw_parts(list_ptr, list_size).as_ref()
+    };
+    let Ok(list) = core::str::from_utf8(list) else {
+        println!("[!] ACPI table list isn't valid UTF-8?");
+        return rsdp_addr;
+    };
+
+    // Read the list of tables from the original XSDT
+    let rsdp = unsafe {
+        core::slice::from_raw_parts(rsdp_addr as *const u8, RSDP_SIZE)
+    };
+    if rsdp[15] < 2 {
+        println!("[!] ACPI 1.0 RSDP (no XSDT), ignoring ACPI overrides");
+        return rsdp_addr;
+    }
+    let xsdt_addr = u64::from_le_bytes(rsdp[24..32].try_into().unwrap());
+    let xsdt = unsafe { table_at(xsdt_addr) };
+    let mut entries: Vec<u64> = xsdt[SDT_HEADER_SIZE..].chunks_exact(8)
+        .map(|e| u64::from_le_bytes(e.try_into().unwrap()))
+        .collect();
+
+    println!("[*] Downloading ACPI tables ...");
+    let mut dsdt: Option<u64> = None;
+    let mut changed = false;
+    for line in list.lines() {
+        let name = line.trim();
+        if name.is_empty() || name.starts_with('#') {
+            continue;
+        }
+        let Some(table) = download_table(net, name) else {
+            continue;
+        };
+        let addr = table.as_ptr() as u64;
+        let sig = signature(table);
+        match sig {
+            "DSDT" => {
+                dsdt = Some(addr);
+                println!("  '{}': DSDT at {:016x}", name, addr);
+            },
+            "SSDT" => {
+                entries.push(addr);
+                println!("  '{}': added SSDT at {:016x}", name, addr);
+            },
+            "RSD " | "RSDT" | "XSDT" | "FACS" => {
+                println!("[!] Can't override {} with '{}'", sig, name);
+                continue;
+            },
+            _ => {
+                let old = entries.iter_mut().find(|e| {
+                    unsafe { &table_at(**e)[..4] == sig.as_bytes() }
+                });
+                match old {
+                    Some(old) => {
+                        println!("  '{}': replaced {} at {:016x} with {:016x}",
+                            name, sig, *old, addr
+                        );
+                        *old = addr;
+                    },
+                    None => {
+                        println!("  '{}': added {} at {:016x}", name, sig, addr);
+                        entries.push(addr);
+                    },
+                }
+            },
+        }
+        changed = true;
+    }
+    if !changed {
+        return rsdp_addr;
+    }
+
+    // The DSDT is only referenced by the FADT, so we need a new FADT
+    if let Some(dsdt) = dsdt {
+        let fadt = entries.iter_mut().find(|e| {
+            unsafe { &table_at(**e)[..4] == b"FACP" }
+        });
+        match fadt {
+            Some(fadt) => {
+                let old = unsafe { table_at(*fadt) };
+                let Ok(new) = allocate_table(old.len()) else {
+                    println!("[!] Couldn't allocate a new FADT");
+                    return rsdp_addr;
+                };
+                new.copy_from_slice(old);
+                let dsdt32 = u32::try_from(dsdt).unwrap_or(0);
+                new[FADT_DSDT_OFFSET..FADT_DSDT_OFFSET + 4]
+                    .copy_from_slice(&dsdt32.to_le_bytes());
+                if new.len() >= FADT_X_DSDT_OFFSET + 8 {
+                    new[FADT_X_DSDT_OFFSET..FADT_X_DSDT_OFFSET + 8]
+                        .copy_from_slice(&dsdt.to_le_bytes());
+                }
+                fix_table_checksum(new);
+                *fadt = new.as_ptr() as u64;
+            },
+            None => println!("[!] No FADT, ignoring the new DSDT"),
+        }
+    }
+
+    // Build a new XSDT (keeping the header from the original)
+    let xsdt_len = SDT_HEADER_SIZE + entries.len() * 8;
+    let Ok(new_xsdt) = allocate_table(xsdt_len) else {
+        println!("[!] Couldn't allocate a new XSDT");
+        return rsdp_addr;
+    };
+    new_xsdt[..SDT_HEADER_SIZE].copy_from_slice(&xsdt[..SDT_HEADER_SIZE]);
+    new_xsdt[4..8].copy_from_slice(&(xsdt_len as u32).to_le_bytes());
+    for (idx, entry) in entries.iter().enumerate() {
+        let off = SDT_HEADER_SIZE + idx * 8;
+        new_xsdt[off..off + 8].copy_from_slice(&entry.to_le_bytes());
+    }
+    fix_table_checksum(new_xsdt);
+
+    // Build a new RSDP pointing to the new XSDT (and no RSDT, since the
+    // old one would still list the old tables)
+    let Ok(new_rsdp) = allocate_table(RSDP_SIZE) else {
+        println!("[!] Couldn't allocate a new RSDP");
+        return rsdp_addr;
+    };
+    new_rsdp.copy_from_slice(rsdp);
+    new_rsdp[16..20].copy_from_slice(&0u32.to_le_bytes());
+    new_rsdp[20..24].copy_from_slice(&(RSDP_SIZE as u32).to_le_bytes());
+    new_rsdp[24..32].copy_from_slice(&(new_xsdt.as_ptr() as u64).to_le_bytes());
+    new_rsdp[8] = 0;
+    new_rsdp[8] = checksum(&new_rsdp[..20]);
+    new_rsdp[32] = 0;
+    new_rsdp[32] = checksum(new_rsdp);
+
+    let addr = new_rsdp.as_ptr() as u64;
+    println!("[*] Using new RSDP at {:016x} ({} tables)", addr, entries.len());
+    addr
+}
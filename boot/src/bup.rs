@@ -1,5 +1,7 @@
 //! System bring-up during UEFI boot services. 
 
+use alloc::format;
+use alloc::string::String;
 use core::ptr::NonNull;
 use uefi::println;
 use uefi::mem::memory_map::*;
@@ -7,6 +9,12 @@ use uefi::boot::{
     AllocateType,
     MemoryType
 };
+use mrld::MrldKernelSegment;
+use mrld::image::{ KERNEL_ALIGN, KERNEL_VIRT_BASE };
+use mrld::paging::PTFlag;
+use mrld::physmem::MrldMemoryKind;
+
+use crate::pxe::LoadedKernel;
 
 /// Wait [indefinitely] for user input, then shut down the machine.
 pub fn wait_for_shutdown() -> ! {
@@ -42,13 +50,43 @@ pub fn dump_memory_map() -> uefi::Result<()> {
 
 /// Return the physical address of the RSDP table.
 pub fn get_rsdp_addr() -> u64 { 
-    use uefi::table::cfg::ACPI2_GUID;
+    use uefi::table::cfg::ConfigTableEntry;
     uefi::system::with_config_table(|tbl| {
-        let rdsp = tbl.iter().find(|e| e.guid == ACPI2_GUID).unwrap();
+        let rdsp = tbl.iter()
+            .find(|e| e.guid == ConfigTableEntry::ACPI2_GUID)
+            .unwrap();
         rdsp.address as u64
     })
 }
 
+/// Collect the firmware vendor/revision and the SMBIOS entry points.
+pub fn get_firmware_info() -> mrld::MrldFirmwareInfo { 
+    use uefi::table::cfg::ConfigTableEntry;
+    let mut res = mrld::MrldFirmwareInfo::new_empty();
+
+    // The vendor is a UCS-2 string, just replace anything that isn't ASCII
+    let vendor = uefi::system::firmware_vendor();
+    for (idx, c) in vendor.iter().take(res.vendor.len()).enumerate() { 
+        let c = u16::from(*c);
+        res.vendor[idx] = if c < 0x80 { c as u8 } else { b'?' };
+    }
+    res.revision = uefi::system::firmware_revision();
+    let rev = uefi::system::uefi_revision();
+    res.uefi_revision = (rev.major() as u32) << 16 | rev.minor() as u32;
+
+    uefi::system::with_config_table(|tbl| { 
+        for entry in tbl { 
+            if entry.guid == ConfigTableEntry::SMBIOS_GUID { 
+                res.smbios_addr = entry.address as u64;
+            }
+            if entry.guid == ConfigTableEntry::SMBIOS3_GUID { 
+                res.smbios3_addr = entry.address as u64;
+            }
+        }
+    });
+    res
+}
+
 /// Switch the UEFI console to mode 0.
 pub fn do_console_init() {
     //use uefi::proto::console::text::OutputMode;
@@ -59,79 +97,381 @@ pub fn do_console_init() {
     });
 }
 
-/// Build a small set of page tables.
+/// Query the Graphics Output Protocol for the current video mode and 
+/// return a description of the framebuffer (if one exists). 
+///
+/// NOTE: We're careful not to open GOP exclusively here, otherwise the 
+/// firmware would disconnect the console that's drawing to it. 
+pub fn get_framebuffer() -> Option<mrld::MrldFramebuffer> { 
+    use mrld::{ MrldFramebuffer, MrldPixelFormat };
+    use uefi::proto::console::gop::{ GraphicsOutput, PixelFormat };
+    use uefi::boot::{ 
+        OpenProtocolParams,
+        OpenProtocolAttributes,
+        get_handle_for_protocol,
+        open_protocol,
+    };
+
+    let handle = get_handle_for_protocol::<GraphicsOutput>().ok()?;
+    let mut gop = unsafe { 
+        open_protocol::<GraphicsOutput>(
+            OpenProtocolParams { 
+                handle,
+                agent: uefi::boot::image_handle(),
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        ).ok()?
+    };
+
+    let info = gop.current_mode_info();
+    let (width, height) = info.resolution();
+    let mut res = MrldFramebuffer::new_empty();
+    res.width = width;
+    res.height = height;
+    res.stride = info.stride();
+    res.format = match info.pixel_format() { 
+        PixelFormat::Rgb => MrldPixelFormat::Rgb,
+        PixelFormat::Bgr => MrldPixelFormat::Bgr,
+        PixelFormat::Bitmask => {
+            let mask = info.pixel_bitmask().unwrap();
+            res.red_mask = mask.red;
+            res.green_mask = mask.green;
+            res.blue_mask = mask.blue;
+            MrldPixelFormat::Bitmask
+        },
+        // There's no linear framebuffer in this mode
+        PixelFormat::BltOnly => return None,
+    };
+
+    let mut fb = gop.frame_buffer();
+    res.base = fb.as_mut_ptr() as u64;
+    res.size = fb.size();
+    Some(res)
+}
+
+/// Lowest physical address considered when placing the kernel image. 
+/// This keeps the kernel out of the way of anything that wants low memory
+/// (ie. the AP trampoline, or legacy DMA).
+pub const KERNEL_PHYS_MIN: u64 = 0x0000_0000_0100_0000;
+
+/// Policy for choosing the physical base address of the kernel image.
+#[derive(Clone, Copy, Debug)]
+pub enum KernelPlacement { 
+    /// Always use this [2MiB-aligned] physical address
+    Fixed(u64),
+    /// Use the lowest suitable free region
+    Lowest,
+    /// Use a random suitable free region
+    Random,
+}
+
+/// The placement policy used when loading the kernel.
 ///
-/// 0x0000_0000_0000_0000 - 0x0000_0080_0000_0000:  identity mapped
-/// 0xffff_ffff_8000_0000 - 0xffff_ffff_8400_0000:  mrld kernel
+/// NOTE: Change this when you're experimenting with kernel placement. 
+/// `Fixed(0x0400_0000)` reproduces the old behavior.
+pub const KERNEL_PLACEMENT: KernelPlacement = { 
+    if cfg!(feature = "kaslr") { 
+        KernelPlacement::Random
+    } else { 
+        KernelPlacement::Lowest
+    }
+};
+
+/// Measure the TSC frequency (in Hz) against the firmware's stall timer. 
+pub fn measure_tsc_freq() -> u64 { 
+    let start = mrld::x86::rdtsc();
+    uefi::boot::stall(core::time::Duration::from_millis(10));
+    (mrld::x86::rdtsc() - start) * 100
+}
+
+/// Return a NUL-terminated filename for uploading to the boot server, 
+/// named after the current time (ie. `<prefix>-YYYYMMDD-HHMMSS.txt`). 
+/// Without an RTC, the TSC is used instead. 
+pub fn timestamped_filename(prefix: &str) -> String { 
+    match uefi::runtime::get_time() {
+        Ok(t) => format!("{}-{:04}{:02}{:02}-{:02}{:02}{:02}.txt\0", prefix,
+            t.year(), t.month(), t.day(), t.hour(), t.minute(), t.second()
+        ),
+        Err(_) => format!("{}-{:016x}.txt\0", prefix, mrld::x86::rdtsc()),
+    }
+}
+
+/// Return a random 64-bit value (from RDRAND if we have it, otherwise from
+/// the TSC, which is better than nothing). 
+pub fn random_u64() -> u64 { 
+    mrld::x86::rdrand().unwrap_or_else(|| mrld::x86::rdtsc())
+}
+
+/// Choose a 2MiB-aligned physical base address for a kernel image with 
+/// the given size (in bytes) by walking the UEFI memory map. 
 ///
-/// NOTE: This is probably fine; we'll probably just be rebuilding these 
-/// after booting into the kernel anyway.
+/// NOTE: We don't try to merge adjacent free regions here. 
+pub fn choose_kernel_phys_base(placement: KernelPlacement, size: u64) 
+    -> Option<u64> 
+{
+    let mm = uefi::boot::memory_map(MemoryType::LOADER_DATA).ok()?;
+
+    // Yields the lowest base address in each free region that can hold 
+    // the kernel, along with the number of 2MiB-aligned candidate slots.
+    let candidates = || mm.entries()
+        .filter(|e| e.ty == MemoryType::CONVENTIONAL)
+        .filter_map(|e| { 
+            let start = e.phys_start.max(KERNEL_PHYS_MIN)
+                .next_multiple_of(KERNEL_ALIGN);
+            let end = e.phys_start + e.page_count * uefi::boot::PAGE_SIZE as u64;
+            if start >= end || (end - start) < size { 
+                return None;
+            }
+            Some((start, (end - start - size) / KERNEL_ALIGN + 1))
+        });
+
+    match placement { 
+        KernelPlacement::Fixed(addr) => { 
+            assert!(addr & (KERNEL_ALIGN - 1) == 0);
+            Some(addr)
+        },
+        KernelPlacement::Lowest => { 
+            candidates().map(|(base, _)| base).min()
+        },
+        KernelPlacement::Random => { 
+            let total: u64 = candidates().map(|(_, slots)| slots).sum();
+            if total == 0 { 
+                return None;
+            }
+            let mut pick = random_u64() % total;
+            for (base, slots) in candidates() { 
+                if pick < slots { 
+                    return Some(base + pick * KERNEL_ALIGN);
+                }
+                pick -= slots;
+            }
+            None
+        },
+    }
+}
+
+/// Choose the virtual base address of a kernel image with the given size.
 ///
-pub unsafe fn build_page_tables() -> NonNull<u8> {
-    use mrld::paging::*;
+/// Without the 'kaslr' feature, this is always [`KERNEL_VIRT_BASE`]. 
+/// Otherwise, this is a random 2MiB-aligned address such that the image 
+/// stays inside the 1GiB above [`KERNEL_VIRT_BASE`] (which keeps the whole
+/// mapping in a single PD, and keeps 'code-model=kernel' happy). 
+pub fn choose_kernel_virt_base(size: u64) -> u64 { 
+    if !cfg!(feature = "kaslr") { 
+        return KERNEL_VIRT_BASE;
+    }
+    let slots = ((1 << 30) - size) / KERNEL_ALIGN + 1;
+    KERNEL_VIRT_BASE + (random_u64() % slots) * KERNEL_ALIGN
+}
 
-    let pml4t_ptr: NonNull<u8> = uefi::boot::allocate_pages(
-        AllocateType::AnyPages,
-        MemoryType::LOADER_DATA,
-        1
-    ).unwrap();
-    let ident_pdp_ptr: NonNull<u8> = uefi::boot::allocate_pages(
-        AllocateType::AnyPages,
-        MemoryType::LOADER_DATA,
-        1
-    ).unwrap();
-
-    let kernel_pdp_ptr: NonNull<u8> = uefi::boot::allocate_pages(
-        AllocateType::AnyPages,
-        MemoryType::LOADER_DATA,
-        1
-    ).unwrap();
-    let kernel_pd_ptr: NonNull<u8> = uefi::boot::allocate_pages(
-        AllocateType::AnyPages,
-        MemoryType::LOADER_DATA,
-        1
-    ).unwrap();
-
-
-    //let mut builder = PTBuilder::new();
-    let pml4t = PageTable::<PML4>::mut_ref_from_ptr(pml4t_ptr.as_ptr());
-
-    // Use 1GiB pages to identity map the low ~512GiB of physical memory.
-    let pdpt = PageTable::<PDP>::mut_ref_from_ptr(ident_pdp_ptr.as_ptr());
-    for idx in 0..512 { 
-        pdpt.set_entry(PageTableIdx::new(idx), PageTableEntry::new(
-            (idx as u64 * (1<<30)),
-            PTFlag::P | PTFlag::RW | PTFlag::PS
-        ));
+/// Returns 'true' if the CPU supports the no-execute bit 
+/// (CPUID Fn8000_0001 EDX[20]).
+pub fn has_nx() -> bool { 
+    mrld::x86::cpuid(0x8000_0001, 0).edx & (1 << 20) != 0
+}
+
+/// Size of the identity mapping when 1GiB pages are supported
+pub const IDENTITY_MAP_SIZE: u64 = 512 << 30;
+
+/// Extra page table pages reserved for mapping the boot arguments, 
+/// framebuffer, symbol table, and memory map
+const EXTRA_TABLE_PAGES: usize = 16;
+
+/// Provisional page tables used to enter the kernel.
+///
+/// - An identity mapping of physical memory:
+///     - With 1GiB pages, this covers the low 512GiB 
+///     - Otherwise (without PDPE1GB), this uses 2MiB pages and only covers 
+///       the top of the UEFI memory map (and at least the low 4GiB)
+///
+/// - The kernel image, mapped with 4KiB pages so that each segment gets 
+///   the permissions from the ELF (writable only with `PF_W`, and 
+///   no-execute without `PF_X` when the CPU supports it)
+///
+/// - Anything else the kernel reads before switching to its own page 
+///   tables (see [`BootPageTables::map_identity`])
+///
+/// All of the tables come from a single pool allocated up front (with the 
+/// type for [`MrldMemoryKind::KernelPaging`], so that the kernel doesn't 
+/// reuse it before switching to its own tables). This means that we can 
+/// keep adding mappings after exiting boot services. 
+pub struct BootPageTables { 
+    /// Pool of pages for page tables
+    pool: NonNull<u8>,
+    /// Number of pages in the pool
+    pool_pages: usize,
+    /// Number of pages used from the pool
+    used: usize,
+    /// Physical address of the PML4 table
+    pml4: u64,
+    /// Use 1GiB pages for the identity mapping
+    huge: bool,
+    /// Use the NX bit
+    nx: bool,
+}
+impl BootPageTables { 
+    /// Build page tables for the given kernel. 
+    pub unsafe fn new(kernel: &LoadedKernel) -> uefi::Result<Self> { 
+        use mrld::paging::PageSize;
+        let huge = mrld::x86::has_1gib_pages();
+        let nx = has_nx();
+
+        // Without 1GiB pages, only map as much as we need to
+        let gib = u64::from(PageSize::Size1GiB);
+        let identity_size = if huge { 
+            IDENTITY_MAP_SIZE
+        } else { 
+            let mm = uefi::boot::memory_map(MemoryType::LOADER_DATA)?;
+            mm.entries()
+                .map(|e| e.phys_start + e.page_count * uefi::boot::PAGE_SIZE as u64)
+                .max().unwrap_or(0)
+                .max(4 << 30)
+                .next_multiple_of(gib)
+                .min(IDENTITY_MAP_SIZE)
+        };
+
+        // PML4, identity PDP (and PDs), and kernel PDP/PD/PTs
+        let identity_pages = if huge { 1 } else { 1 + (identity_size / gib) as usize };
+        let kernel_pages = 2 + kernel.size.div_ceil(KERNEL_ALIGN) as usize;
+        let pool_pages = 1 + identity_pages + kernel_pages + EXTRA_TABLE_PAGES;
+        let pool: NonNull<u8> = uefi::boot::allocate_pages(
+            AllocateType::AnyPages,
+            MrldMemoryKind::KernelPaging.as_uefi_type(),
+            pool_pages,
+        )?;
+        pool.as_ptr().write_bytes(0, pool_pages * uefi::boot::PAGE_SIZE);
+
+        let mut res = Self { pool, pool_pages, used: 0, pml4: 0, huge, nx };
+        res.pml4 = res.alloc_table();
+        res.map_identity(0, identity_size);
+        res.map_kernel(kernel);
+        println!("[*] Boot page tables: {}GiB identity ({}), NX {}, {}/{} pages",
+            identity_size >> 30, if huge { "1GiB pages" } else { "2MiB pages" },
+            if nx { "enabled" } else { "unsupported" }, res.used, res.pool_pages,
+        );
+        Ok(res)
+    }
+
+    /// Return the physical address of the next [zeroed] page in the pool.
+    unsafe fn alloc_table(&mut self) -> u64 { 
+        assert!(self.used < self.pool_pages, "ran out of boot page tables?");
+        let ptr = self.pool.as_ptr().add(self.used * uefi::boot::PAGE_SIZE);
+        self.used += 1;
+        ptr as u64
+    }
+
+    /// Map a single page. Does nothing if 'vaddr' is already covered by a 
+    /// larger page. 
+    unsafe fn map_page(&mut self, vaddr: u64, paddr: u64, 
+        pagesz: mrld::paging::PageSize, flags: PTFlag)
+    { 
+        use mrld::paging::*;
+        let (pml4_idx, pdp_idx, pd_idx, pt_idx) = VirtAddr::from_u64(vaddr)
+            .decompose();
+        let leaf_flags = PTFlag::P | flags;
+        let table_flags = PTFlag::P | PTFlag::RW;
+
+        let pml4 = PageTable::<PML4>::mut_ref_from_ptr(self.pml4 as *mut u8);
+        if pml4.get(pml4_idx).invalid() { 
+            let next = self.alloc_table();
+            pml4.set_entry(pml4_idx, PageTableEntry::new(next, table_flags));
+        }
+        let pdp = PageTable::<PDP>::mut_ref_from_ptr(
+            pml4.get(pml4_idx).address() as *mut u8
+        );
+        if pagesz == PageSize::Size1GiB { 
+            if pdp.get(pdp_idx).invalid() { 
+                pdp.set_entry(pdp_idx, PageTableEntry::new(paddr, leaf_flags | PTFlag::PS));
+            }
+            return;
+        }
+        if pdp.get(pdp_idx).terminal() { 
+            return;
+        }
+        if pdp.get(pdp_idx).invalid() { 
+            let next = self.alloc_table();
+            pdp.set_entry(pdp_idx, PageTableEntry::new(next, table_flags));
+        }
+        let pd = PageTable::<PD>::mut_ref_from_ptr(
+            pdp.get(pdp_idx).address() as *mut u8
+        );
+        if pagesz == PageSize::Size2MiB { 
+            if pd.get(pd_idx).invalid() { 
+                pd.set_entry(pd_idx, PageTableEntry::new(paddr, leaf_flags | PTFlag::PS));
+            }
+            return;
+        }
+        if pd.get(pd_idx).terminal() { 
+            return;
+        }
+        if pd.get(pd_idx).invalid() { 
+            let next = self.alloc_table();
+            pd.set_entry(pd_idx, PageTableEntry::new(next, table_flags));
+        }
+        let pt = PageTable::<PT>::mut_ref_from_ptr(
+            pd.get(pd_idx).address() as *mut u8
+        );
+        if pt.get(pt_idx).invalid() { 
+            pt.set_entry(pt_idx, PageTableEntry::new(paddr, leaf_flags));
+        }
     }
-    pml4t.set_entry(PageTableIdx::new(0), PageTableEntry::new(
-        pdpt.as_ptr() as u64,
-        PTFlag::P | PTFlag::RW
-    ));
-
-    // Create a handful of 2MiB pages for the kernel mapping. 
-    let v = VirtAddr::from_u64(0xffff_ffff_8000_0000);
-    let (pml4_idx, pdp_idx, pd_idx, pt_idx) = v.decompose();
-    let pdpt = PageTable::<PDP>::mut_ref_from_ptr(kernel_pdp_ptr.as_ptr());
-    let pdt = PageTable::<PD>::mut_ref_from_ptr(kernel_pd_ptr.as_ptr());
-
-    pml4t.set_entry(pml4_idx, PageTableEntry::new(
-        pdpt.as_ptr() as u64,
-        PTFlag::P | PTFlag::RW
-    ));
-    pdpt.set_entry(pdp_idx, PageTableEntry::new(
-        pdt.as_ptr() as u64,
-        PTFlag::P | PTFlag::RW
-    ));
-
-    // FIXME: This assumes a physical address
-    for idx in 0..32 { 
-        pdt.set_entry(PageTableIdx::new(idx), PageTableEntry::new(
-            0x0400_0000 + (idx as u64 * (1 << 21)),
-            PTFlag::P | PTFlag::RW | PTFlag::PS
-        ));
+
+    /// Identity map the physical range ['addr', 'addr + size') (rounded out
+    /// to 4KiB pages), using the largest pages that fit. 
+    ///
+    /// NOTE: This doesn't allocate, so it's safe to use after exiting boot 
+    /// services (as long as there are pages left in the pool). 
+    pub unsafe fn map_identity(&mut self, addr: u64, size: u64) { 
+        use mrld::paging::PageSize;
+        let mut cur = addr & !0xfff;
+        let end = (addr + size).next_multiple_of(0x1000);
+        while cur < end { 
+            let pagesz = [PageSize::Size1GiB, PageSize::Size2MiB]
+                .into_iter()
+                .filter(|sz| self.huge || *sz != PageSize::Size1GiB)
+                .find(|sz| { 
+                    let sz = u64::from(*sz);
+                    cur & (sz - 1) == 0 && end - cur >= sz
+                })
+                .unwrap_or(PageSize::Size4KiB);
+            self.map_page(cur, cur, pagesz, PTFlag::RW);
+            cur += u64::from(pagesz);
+        }
+    }
+
+    /// Map each segment of the kernel image with 4KiB pages. 
+    unsafe fn map_kernel(&mut self, kernel: &LoadedKernel) { 
+        use mrld::paging::PageSize;
+        for seg in kernel.segments() { 
+            let mut flags = PTFlag::empty();
+            if seg.flags & MrldKernelSegment::PF_W != 0 { 
+                flags |= PTFlag::RW;
+            }
+            if self.nx && seg.flags & MrldKernelSegment::PF_X == 0 { 
+                flags |= PTFlag::NX;
+            }
+            let vaddr = seg.vaddr & !0xfff;
+            let paddr = seg.paddr & !0xfff;
+            let end = (seg.vaddr + seg.memsz).next_multiple_of(0x1000);
+            for off in (0..(end - vaddr)).step_by(0x1000) { 
+                self.map_page(vaddr + off, paddr + off, PageSize::Size4KiB, flags);
+            }
+        }
+    }
+
+    /// Switch to these page tables (enabling EFER.NXE if we're using NX).
+    ///
+    /// NOTE: The caller must be running from the identity mapping. 
+    pub unsafe fn activate(&self) { 
+        use mrld::x86::msr::Msr;
+        if self.nx { 
+            const EFER_NXE: u64 = 1 << 11;
+            let efer = Msr::rdmsr(Msr::EFER);
+            Msr::wrmsr(Msr::EFER, efer | EFER_NXE);
+        }
+        mrld::x86::CR3::write(self.pml4);
     }
-    pml4t_ptr
 }
 
 pub unsafe fn dump_dtrs() {
@@ -0,0 +1,93 @@
+//! Chainloading other EFI applications.
+//!
+//! The boot menu can download an EFI image from the boot server (ie. a UEFI
+//! shell, a vendor diagnostic, or a Linux kernel with an EFI stub) and start
+//! it with `LoadImage()`/`StartImage()`, optionally with load options (the
+//! "command line" for the image). When the image returns, we go back to the
+//! boot menu.
+//!
+//! NOTE: Network services are stopped before starting the image, so that it
+//! can use the NIC itself.
+
+use alloc::vec::Vec;
+use uefi::{ println, CStr8, CString16 };
+use uefi::boot::{
+    LoadImageSource,
+    MemoryType,
+    PAGE_SIZE,
+    free_pages,
+    image_handle,
+    load_image,
+    open_protocol_exclusive,
+    start_image,
+    unload_image,
+};
+use uefi::proto::loaded_image::LoadedImage;
+use core::ptr::NonNull;
+
+use crate::net::{ NetClient, Transport };
+
+/// Maximum length of an image name
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Maximum length of the load options
+pub const MAX_OPTIONS_LEN: usize = 256;
+
+/// Download an EFI image and run it.
+///
+/// Returns the status from the image, or an error if we couldn't download
+/// or load it.
+pub fn chainload(transport: Transport, tsc_freq: u64, name: &str,
+    options: &str) -> uefi::Result<()>
+{
+    // TFTP expects a NUL-terminated filename
+    let mut buf: Vec<u8> = name.as_bytes().to_vec();
+    buf.push(0);
+    let filename = CStr8::from_bytes_with_nul(&buf)
+        .map_err(|_| uefi::Error::new(uefi::Status::INVALID_PARAMETER, ()))?;
+
+    // Load options are a UCS-2 string, which must live until the image 
+    // returns.
+    let options = CString16::try_from(options)
+        .map_err(|_| uefi::Error::new(uefi::Status::INVALID_PARAMETER, ()))?;
+
+    let mut net = NetClient::open(transport, tsc_freq)?;
+    let res = net.download(filename, MemoryType::LOADER_DATA);
+    if let Err(e) = net.stop() {
+        println!("[!] Couldn't stop network services: {}", e);
+    }
+    let (ptr, size) = res?;
+
+    // The firmware makes its own copy of the image
+    let image = unsafe { NonNull::slice_from_raw_parts(ptr, size).as_ref() };
+    let handle = load_image(image_handle(), LoadImageSource::FromBuffer {
+        buffer: image,
+        file_path: None,
+    });
+    unsafe {
+        let _ = free_pages(ptr, size.div_ceil(PAGE_SIZE));
+    }
+    let handle = handle?;
+
+    if !options.is_empty() {
+        // Don't leave the image loaded if we can't start it
+        let mut loaded = match open_protocol_exclusive::<LoadedImage>(handle) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                let _ = unload_image(handle);
+                return Err(e);
+            },
+        };
+        unsafe {
+            loaded.set_load_options(
+                options.as_ptr() as *const u8,
+                options.num_bytes() as u32,
+            );
+        }
+    }
+
+    println!("[*] Starting '{}' ({}B) ...", name, size);
+    start_image(handle)?;
+    println!("[*] '{}' returned", name);
+    Ok(())
+}
@@ -0,0 +1,92 @@
+//! Uploading the kernel crash log.
+//!
+//! The kernel keeps a copy of its log output in a fixed region of physical
+//! memory (see [`mrld::crashlog`]). We reserve that region before anything
+//! else can allocate it, and if the previous kernel panicked (or never shut
+//! down cleanly), we upload the log to the boot server as
+//! `crashlog-YYYYMMDD-HHMMSS.txt` before downloading the next kernel.
+//!
+//! NOTE: This only works across warm reboots. Firmware is free to clear
+//! memory during a cold boot.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+use uefi::{ println, CStr8 };
+use uefi::boot::{ AllocateType, PAGE_SIZE };
+use mrld::crashlog::{ CrashLog, CRASHLOG_PHYS_BASE, CRASHLOG_SIZE };
+use mrld::physmem::MrldMemoryKind;
+
+use crate::bup;
+use crate::net::NetClient;
+
+/// Number of lines printed to the console when the upload fails
+const TAIL_LINES: usize = 20;
+
+/// Reserve the crash log region.
+/// Returns the physical address of the region (or zero if unavailable).
+pub fn reserve() -> u64 {
+    let res: uefi::Result<NonNull<u8>> = uefi::boot::allocate_pages(
+        AllocateType::Address(CRASHLOG_PHYS_BASE),
+        MrldMemoryKind::CrashLog.as_uefi_type(),
+        CRASHLOG_SIZE / PAGE_SIZE,
+    );
+    match res {
+        Ok(ptr) => ptr.as_ptr() as u64,
+        Err(e) => {
+            println!("[!] Couldn't reserve the crash log region: {}", e);
+            0
+        },
+    }
+}
+
+/// If the crash log region contains a log from a kernel that didn't shut
+/// down cleanly, upload it to the boot server. The log is cleared afterwards.
+pub fn upload(net: &mut NetClient, region: u64) {
+    if region != CRASHLOG_PHYS_BASE {
+        return;
+    }
+    let mut log = unsafe { CrashLog::new() };
+    let state = log.state();
+    if !state.is_crash() {
+        log.clear();
+        return;
+    }
+
+    let hdr = log.header();
+    println!("[!] Found a crash log from the last boot ({:?}, {}B written)",
+        state, hdr.written
+    );
+
+    let mut text = format!("# mrld crash log\n# state: {:?}\n# build: {}\n\n",
+        state, hdr.build_id.as_str()
+    );
+    let mut data = vec![0u8; CrashLog::DATA_SIZE];
+    let len = log.read(&mut data);
+    data.truncate(len);
+    text.push_str(&String::from_utf8_lossy(&data));
+
+    // Name the log after the current time (if we have an RTC)
+    let name = bup::timestamped_filename("crashlog");
+    let filename = CStr8::from_bytes_with_nul(name.as_bytes()).unwrap();
+
+    // NOTE: Only clear the log after it's been uploaded, otherwise this is
+    // the only copy of it
+    match net.upload(filename, text.as_bytes()) {
+        Ok(()) => {
+            println!("[*] Uploaded crash log as '{}'", filename);
+            log.clear();
+        },
+        Err(e) => {
+            println!("[!] Couldn't upload the crash log ({:?}), last {} lines:",
+                e.status(), TAIL_LINES
+            );
+            let lines: Vec<&str> = text.lines().collect();
+            for line in &lines[lines.len().saturating_sub(TAIL_LINES)..] {
+                println!("  {}", line);
+            }
+        },
+    }
+}
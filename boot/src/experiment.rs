@@ -0,0 +1,384 @@
+//! Running experiments with UEFI MP Services (without the kernel).
+//!
+//! Quick microbenchmarks don't need the whole kernel, and comparing against
+//! firmware SMP is a useful reference point. From the boot menu, we can
+//! pick one of the experiments in [`EXPERIMENTS`] and run it on a set of
+//! processors while boot services are still up (see `src/smp.rs`):
+//!
+//! - In blocking mode, the experiment runs on one processor at a time
+//! - In non-blocking mode, the experiment is started on all of the selected
+//!   APs at once (and then on the BSP, if it was selected), and we wait for
+//!   an event from each AP
+//!
+//! Each run of an experiment returns a single value. The results are printed
+//! as a table, and can also be uploaded to the boot server with TFTP as
+//! `mpexp-<experiment>-YYYYMMDD-HHMMSS.txt`.
+//!
+//! NOTE: Experiments run on APs must not call into UEFI (including
+//! printing to the console), since boot services are not MP-safe.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::fmt::Write;
+use core::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+use core::time::Duration;
+use uefi::{ println, CStr8, Event };
+use uefi::boot::{ EventType, Tpl };
+use uefi::proto::console::text::Key;
+use uefi::proto::pi::mp::MpServices;
+
+use crate::bup;
+use crate::menu;
+use crate::net::{ NetClient, Transport };
+use crate::smp::{ self, Processor };
+
+/// How long to wait for an experiment on an AP before giving up
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of iterations for the microbenchmarks
+const ITERATIONS: u64 = 1000;
+
+/// An experiment that can be run on any processor.
+pub struct Experiment {
+    /// Short name (used in the name of uploaded results)
+    pub name: &'static str,
+    /// Description of the value returned by the experiment
+    pub desc: &'static str,
+    /// The experiment itself
+    pub func: fn() -> u64,
+}
+
+/// Experiments that can be selected from the menu.
+pub const EXPERIMENTS: &[Experiment] = &[
+    Experiment {
+        name: "apic-id",
+        desc: "x2APIC ID (from CPUID leaf 0xb)",
+        func: exp_apic_id,
+    },
+    Experiment {
+        name: "tsc",
+        desc: "Current TSC value",
+        func: exp_tsc,
+    },
+    Experiment {
+        name: "rdtsc",
+        desc: "Average cost of RDTSC (in TSC ticks)",
+        func: exp_rdtsc,
+    },
+    Experiment {
+        name: "cpuid",
+        desc: "Average cost of CPUID leaf 0 (in TSC ticks)",
+        func: exp_cpuid,
+    },
+];
+
+fn exp_apic_id() -> u64 {
+    mrld::x86::cpuid(0xb, 0).edx as u64
+}
+
+fn exp_tsc() -> u64 {
+    mrld::x86::rdtsc()
+}
+
+fn exp_rdtsc() -> u64 {
+    let start = mrld::x86::rdtsc();
+    for _ in 0..ITERATIONS {
+        core::hint::black_box(mrld::x86::rdtsc());
+    }
+    (mrld::x86::rdtsc() - start) / ITERATIONS
+}
+
+fn exp_cpuid() -> u64 {
+    let start = mrld::x86::rdtsc();
+    for _ in 0..ITERATIONS {
+        core::hint::black_box(mrld::x86::cpuid(0, 0));
+    }
+    (mrld::x86::rdtsc() - start) / ITERATIONS
+}
+
+/// State shared with a processor running an experiment.
+struct Slot {
+    /// The experiment
+    func: fn() -> u64,
+    /// TSC value when the experiment started
+    start: AtomicU64,
+    /// TSC value when the experiment returned
+    end: AtomicU64,
+    /// Value returned by the experiment
+    value: AtomicU64,
+    /// Set when the experiment has returned
+    done: AtomicBool,
+}
+impl Slot {
+    fn new(func: fn() -> u64) -> Self {
+        Self {
+            func,
+            start: AtomicU64::new(0),
+            end: AtomicU64::new(0),
+            value: AtomicU64::new(0),
+            done: AtomicBool::new(false),
+        }
+    }
+
+    fn as_arg(&self) -> *mut c_void {
+        self as *const Self as *mut c_void
+    }
+}
+
+/// Run the experiment in a [`Slot`] (on any processor).
+extern "efiapi" fn run_slot(arg: *mut c_void) {
+    let slot = unsafe { &*(arg as *const Slot) };
+    slot.start.store(mrld::x86::rdtsc(), Ordering::Relaxed);
+    let value = (slot.func)();
+    slot.end.store(mrld::x86::rdtsc(), Ordering::Relaxed);
+    slot.value.store(value, Ordering::Relaxed);
+    slot.done.store(true, Ordering::Release);
+}
+
+/// Create an event for a non-blocking call.
+fn create_event() -> uefi::Result<Event> {
+    unsafe {
+        uefi::boot::create_event(EventType::empty(), Tpl::CALLBACK, None, None)
+    }
+}
+
+/// Run an experiment on the selected processors.
+/// Returns one slot for each entry in 'cpus'.
+fn run_experiment(mp: &MpServices, procs: &[Processor], cpus: &[usize],
+    exp: &Experiment, blocking: bool) -> Vec<Slot>
+{
+    let slots: Vec<Slot> = cpus.iter().map(|_| Slot::new(exp.func)).collect();
+    let is_bsp = |cpu: usize| procs[cpu].is_bsp;
+
+    if blocking {
+        for (cpu, slot) in cpus.iter().zip(slots.iter()) {
+            if is_bsp(*cpu) {
+                run_slot(slot.as_arg());
+                continue;
+            }
+            let res = mp.startup_this_ap(
+                *cpu, run_slot, slot.as_arg(), None, Some(TIMEOUT)
+            );
+            if let Err(e) = res {
+                println!("[!] Processor {} failed: {:?}", cpu, e.status());
+            }
+        }
+        return slots;
+    }
+
+    // Start the experiment on all of the APs first
+    let mut events: Vec<(usize, Event)> = Vec::new();
+    for (cpu, slot) in cpus.iter().zip(slots.iter()) {
+        if is_bsp(*cpu) {
+            continue;
+        }
+        let res = create_event().and_then(|event| {
+            let wait = unsafe { event.unsafe_clone() };
+            mp.startup_this_ap(
+                *cpu, run_slot, slot.as_arg(), Some(event), Some(TIMEOUT)
+            ).map(|_| wait)
+        });
+        match res {
+            Ok(event) => events.push((*cpu, event)),
+            Err(e) => println!("[!] Processor {} failed: {:?}", cpu, e.status()),
+        }
+    }
+
+    // Run on the BSP while the APs are busy
+    for (cpu, slot) in cpus.iter().zip(slots.iter()) {
+        if is_bsp(*cpu) {
+            run_slot(slot.as_arg());
+        }
+    }
+
+    // The event is also signaled when an AP times out
+    for (cpu, event) in events {
+        let mut wait = [ unsafe { event.unsafe_clone() } ];
+        if let Err(e) = uefi::boot::wait_for_event(&mut wait) {
+            println!("[!] Couldn't wait for processor {}: {:?}", cpu, e.status());
+        }
+        let _ = uefi::boot::close_event(event);
+    }
+    slots
+}
+
+/// Format the results of an experiment as text.
+fn format_results(procs: &[Processor], cpus: &[usize], slots: &[Slot],
+    exp: &Experiment, blocking: bool) -> String
+{
+    let mut res = String::new();
+    let _ = writeln!(res, "# mrld MpServices experiment");
+    let _ = writeln!(res, "# experiment: {} ({})", exp.name, exp.desc);
+    let _ = writeln!(res, "# mode: {}",
+        if blocking { "blocking" } else { "non-blocking" }
+    );
+    let _ = writeln!(res, "# firmware: {} rev {:08x}",
+        uefi::system::firmware_vendor(), uefi::system::firmware_revision()
+    );
+    let _ = writeln!(res, "{:>4} {:>8} {:>4} {:>4} {:>4} {:>20} {:>20} {:>12}",
+        "cpu", "apic_id", "pkg", "core", "thr", "value", "start_tsc", "ticks"
+    );
+    for (cpu, slot) in cpus.iter().zip(slots.iter()) {
+        let p = &procs[*cpu];
+        if !slot.done.load(Ordering::Acquire) {
+            let _ = writeln!(res, "{:>4} {:>8} {:>4} {:>4} {:>4} {:>20}",
+                p.number, p.apic_id, p.package, p.core, p.thread, "timeout"
+            );
+            continue;
+        }
+        let start = slot.start.load(Ordering::Relaxed);
+        let end = slot.end.load(Ordering::Relaxed);
+        let _ = writeln!(res, "{:>4} {:>8} {:>4} {:>4} {:>4} {:>20} {:>20} {:>12}",
+            p.number, p.apic_id, p.package, p.core, p.thread,
+            slot.value.load(Ordering::Relaxed), start, end - start
+        );
+    }
+    res
+}
+
+/// Print the processor topology.
+fn dump_topology(procs: &[Processor]) {
+    println!("[*] Processors:");
+    for p in procs {
+        println!("  #{:<3} apic_id={:<4} pkg={} core={} thread={}{}{}{}",
+            p.number, p.apic_id, p.package, p.core, p.thread,
+            if p.is_bsp { " BSP" } else { "" },
+            if p.is_enabled { "" } else { " disabled" },
+            if p.is_healthy { "" } else { " unhealthy" },
+        );
+    }
+}
+
+/// Parse a list of processors (ie. `all`, `1,3`, or `0-3,6`).
+/// Disabled processors are skipped.
+fn parse_cpus(s: &str, procs: &[Processor]) -> Option<Vec<usize>> {
+    let s = s.trim();
+    if s == "all" {
+        return Some(procs.iter()
+            .filter(|p| p.is_enabled)
+            .map(|p| p.number)
+            .collect()
+        );
+    }
+    let mut res = Vec::new();
+    for part in s.split(',') {
+        let part = part.trim();
+        let (lo, hi) = match part.split_once('-') {
+            Some((lo, hi)) => (lo.trim().parse().ok()?, hi.trim().parse().ok()?),
+            None => {
+                let n: usize = part.parse().ok()?;
+                (n, n)
+            },
+        };
+        if lo > hi || hi >= procs.len() {
+            return None;
+        }
+        res.extend((lo..=hi).filter(|n| procs[*n].is_enabled));
+    }
+    res.sort_unstable();
+    res.dedup();
+    Some(res)
+}
+
+/// Upload results to the boot server with TFTP.
+fn upload_results(exp: &Experiment, text: &str, tsc_freq: u64) {
+    let name = bup::timestamped_filename(&format!("mpexp-{}", exp.name));
+    let filename = CStr8::from_bytes_with_nul(name.as_bytes()).unwrap();
+    let res = NetClient::open(Transport::Tftp, tsc_freq).and_then(|mut net| {
+        let res = net.upload(filename, text.as_bytes());
+        if let Err(e) = net.stop() {
+            println!("[!] Couldn't stop network services: {}", e);
+        }
+        res
+    });
+    match res {
+        Ok(()) => println!("[*] Uploaded results as '{}'", filename),
+        Err(e) => println!("[!] Couldn't upload results: {:?}", e.status()),
+    }
+}
+
+/// Show the experiment menu (until the user goes back to the boot menu).
+pub fn run(tsc_freq: u64) {
+    let mp = match smp::open() {
+        Ok(mp) => mp,
+        Err(e) => {
+            println!("[!] No MP Services protocol: {:?}", e.status());
+            return;
+        },
+    };
+    let procs = match smp::topology(&mp) {
+        Ok(procs) => procs,
+        Err(e) => {
+            println!("[!] Couldn't read the processor topology: {:?}", e.status());
+            return;
+        },
+    };
+    dump_topology(&procs);
+
+    let mut exp_idx = 0;
+    let mut cpus_str = String::from("all");
+    let mut cpus = parse_cpus(&cpus_str, &procs).unwrap();
+    let mut blocking = true;
+    // Results from the last run (and the experiment that produced them)
+    let mut last: Option<(&Experiment, String)> = None;
+
+    loop {
+        let exp = &EXPERIMENTS[exp_idx];
+        println!();
+        println!("[*] MpServices experiments ({} processors):", procs.len());
+        for (idx, e) in EXPERIMENTS.iter().enumerate() {
+            println!("  {}     - {}{}: {}", idx,
+                if idx == exp_idx { "*" } else { " " }, e.name, e.desc
+            );
+        }
+        println!("  p     - Processors: {} ({} selected)", cpus_str, cpus.len());
+        println!("  b     - Mode: {}",
+            if blocking { "blocking" } else { "non-blocking" }
+        );
+        println!("  t     - Dump the processor topology");
+        println!("  u     - Upload the last results (with TFTP)");
+        println!("  q     - Back to the boot menu");
+        println!("  Enter - Run");
+
+        let Key::Printable(c) = menu::wait_key() else {
+            continue;
+        };
+        match char::from(c) {
+            c @ '0'..='9' => {
+                let idx = c as usize - '0' as usize;
+                if idx < EXPERIMENTS.len() {
+                    exp_idx = idx;
+                }
+            },
+            'p' => {
+                println!("  Processors (ie. 'all', '1,3', '0-3'):");
+                let Some(line) = menu::edit_line(&cpus_str, 64) else {
+                    continue;
+                };
+                match parse_cpus(&line, &procs) {
+                    Some(new) => {
+                        cpus_str = line;
+                        cpus = new;
+                    },
+                    None => println!("[!] Invalid processor list '{}'", line),
+                }
+            },
+            'b' => blocking = !blocking,
+            't' => dump_topology(&procs),
+            'u' => match &last {
+                Some((exp, text)) => upload_results(exp, text, tsc_freq),
+                None => println!("[!] No results yet"),
+            },
+            'q' => break,
+            '\r' | '\n' => {
+                let slots = run_experiment(&mp, &procs, &cpus, exp, blocking);
+                let text = format_results(&procs, &cpus, &slots, exp, blocking);
+                println!("{}", text);
+                last = Some((exp, text));
+            },
+            _ => {},
+        }
+    }
+}
@@ -0,0 +1,229 @@
+//! HTTP downloads.
+//!
+//! TFTP is slow for large (debug) kernels, so we can also download files
+//! with the UEFI HTTP protocols. When the bootloader itself was loaded with
+//! UEFI HTTP boot, the device path of the boot device ends with a URI node
+//! (ie. `http://10.200.200.2:8080/mrld-boot.efi`), and all other files are
+//! expected to live in the same directory on the same server.
+//!
+//! See `cargo xtask http` for a tiny server that serves the `pxe/` directory.
+
+use alloc::format;
+use alloc::string::{ String, ToString };
+use core::ptr::NonNull;
+use core::time::Duration;
+use uefi::{ print, println, CStr8, Handle, Identify, Status };
+use uefi::boot::{
+    AllocateType,
+    MemoryType,
+    OpenProtocolAttributes,
+    OpenProtocolParams,
+    PAGE_SIZE,
+    ScopedProtocol,
+    SearchType,
+    allocate_pages,
+    free_pages,
+    image_handle,
+    locate_device_path,
+    locate_handle_buffer,
+    open_protocol,
+    open_protocol_exclusive,
+};
+use uefi::proto::device_path::{ DevicePath, DeviceType, DeviceSubType };
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::proto::network::http::{ HttpBinding, HttpHelper };
+use uefi::proto::network::ip4config2::Ip4Config2;
+use uefi_raw::protocol::network::http::{ HttpMethod, HttpStatusCode };
+
+use crate::pxe::{ MAX_ATTEMPTS, INITIAL_BACKOFF_MS };
+
+/// Default port for the HTTP server (see `cargo xtask http`)
+pub const DEFAULT_PORT: u16 = 8080;
+
+/// Return the device path of the device we were loaded from.
+fn boot_device_path() -> Option<ScopedProtocol<DevicePath>> {
+    let device = open_protocol_exclusive::<LoadedImage>(image_handle())
+        .ok()?
+        .device()?;
+    unsafe {
+        open_protocol::<DevicePath>(
+            OpenProtocolParams {
+                handle: device,
+                agent: image_handle(),
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        ).ok()
+    }
+}
+
+/// If we were loaded with HTTP boot, return the URL of the directory
+/// containing the bootloader (with a trailing '/').
+pub fn boot_url() -> Option<String> {
+    let dp = boot_device_path()?;
+    let node = dp.node_iter().find(|node| {
+        node.device_type() == DeviceType::MESSAGING &&
+        node.sub_type() == DeviceSubType::MESSAGING_URI
+    })?;
+    let url = core::str::from_utf8(node.data()).ok()?;
+    let url = url.trim_end_matches('\0');
+    Some(url[..=url.rfind('/')?].to_string())
+}
+
+/// Helper for downloading files from an HTTP server.
+pub struct HttpClient {
+    /// The HTTP protocol (bound to a NIC)
+    http: HttpHelper,
+    /// URL of the directory on the server (with a trailing '/')
+    base_url: String,
+    /// Approximate TSC frequency (in ticks per millisecond)
+    tsc_per_ms: u64,
+}
+impl HttpClient {
+    /// Find a NIC with HTTP support, and make sure it has an IP address.
+    /// 'tsc_freq' (in Hz) is only used for reporting throughput.
+    pub fn open(base_url: String, tsc_freq: u64) -> uefi::Result<Self> {
+        let nic = Self::find_nic()?;
+
+        // Run DHCP (if we don't already have an address)
+        let mut ip4 = Ip4Config2::new(nic)?;
+        ip4.ifup()?;
+
+        let mut http = HttpHelper::new(nic)?;
+        http.configure()?;
+        println!("[*] Using HTTP server {}", base_url);
+
+        let tsc_per_ms = (tsc_freq / 1000).max(1);
+        Ok(Self { http, base_url, tsc_per_ms })
+    }
+
+    /// Find a NIC with HTTP support, preferring the one we were loaded from.
+    fn find_nic() -> uefi::Result<Handle> {
+        if let Some(dp) = boot_device_path() {
+            if let Ok(handle) = locate_device_path::<HttpBinding>(&mut &*dp) {
+                return Ok(handle);
+            }
+        }
+        let handles = locate_handle_buffer(
+            SearchType::ByProtocol(&HttpBinding::GUID)
+        )?;
+        handles.first().copied()
+            .ok_or(uefi::Error::new(Status::NOT_FOUND, ()))
+    }
+
+    /// Download a file into newly-allocated pages with the given memory
+    /// type. Returns a pointer to the file and its size (in bytes).
+    ///
+    /// Failures (other than a missing file) are retried up to
+    /// [`MAX_ATTEMPTS`] times.
+    pub fn download(&mut self, filename: &CStr8, mem_ty: MemoryType)
+        -> uefi::Result<(NonNull<u8>, usize)>
+    {
+        let url = format!("{}{}", self.base_url, filename);
+        let mut delay = INITIAL_BACKOFF_MS;
+        for attempt in 1..=MAX_ATTEMPTS {
+            match self.try_download(&url, mem_ty) {
+                Ok(res) => return Ok(res),
+                Err(e) if attempt < MAX_ATTEMPTS && e.status() != Status::NOT_FOUND => {
+                    println!("[!] Downloading '{}' failed ({:?}), retrying in {}ms ({}/{})",
+                        url, e.status(), delay, attempt, MAX_ATTEMPTS
+                    );
+                    uefi::boot::stall(Duration::from_millis(delay));
+                    delay *= 2;
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        unreachable!();
+    }
+
+    /// Upload a file to the HTTP server (with a PUT request). 
+    pub fn upload(&mut self, filename: &CStr8, data: &[u8]) -> uefi::Result<()> {
+        let url = format!("{}{}", self.base_url, filename);
+        let mut body = data.to_vec();
+        self.http.request(HttpMethod::PUT, &url, Some(&mut body))?;
+        let rsp = self.http.response_first(false)?;
+        if rsp.status != HttpStatusCode::STATUS_200_OK &&
+            rsp.status != HttpStatusCode::STATUS_201_CREATED
+        {
+            println!("[!] HTTP server returned {:?} for '{}'", rsp.status, url);
+            return Err(uefi::Error::new(Status::PROTOCOL_ERROR, ()));
+        }
+        Ok(())
+    }
+
+    /// Make a single attempt to download a file.
+    /// The buffer is freed if the download fails.
+    fn try_download(&mut self, url: &str, mem_ty: MemoryType)
+        -> uefi::Result<(NonNull<u8>, usize)>
+    {
+        let start = mrld::x86::rdtsc();
+        self.http.request_get(url)?;
+        let rsp = self.http.response_first(true)?;
+        if rsp.status == HttpStatusCode::STATUS_404_NOT_FOUND {
+            return Err(uefi::Error::new(Status::NOT_FOUND, ()));
+        }
+        if rsp.status != HttpStatusCode::STATUS_200_OK {
+            println!("[!] HTTP server returned {:?} for '{}'", rsp.status, url);
+            return Err(uefi::Error::new(Status::PROTOCOL_ERROR, ()));
+        }
+
+        // We need to know the size of the file up front
+        let size: usize = rsp.headers.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, v)| v.trim().parse().ok())
+            .ok_or_else(|| {
+                println!("[!] HTTP response for '{}' has no Content-Length?", url);
+                uefi::Error::new(Status::PROTOCOL_ERROR, ())
+            })?;
+
+        let pages = size.div_ceil(PAGE_SIZE).max(1);
+        let ptr: NonNull<u8> = allocate_pages(
+            AllocateType::AnyPages,
+            mem_ty,
+            pages,
+        )?;
+        let buf = unsafe {
+            NonNull::slice_from_raw_parts(ptr, size).as_mut()
+        };
+
+        // Copy each chunk of the body into the buffer
+        let mut received = 0;
+        let mut last_pct = 0;
+        let mut chunk = rsp.body;
+        loop {
+            if received + chunk.len() > size {
+                println!();
+                println!("[!] HTTP response for '{}' is larger than expected", url);
+                unsafe { let _ = free_pages(ptr, pages); }
+                return Err(uefi::Error::new(Status::PROTOCOL_ERROR, ()));
+            }
+            buf[received..received + chunk.len()].copy_from_slice(&chunk);
+            received += chunk.len();
+
+            let pct = if size == 0 { 100 } else { (received * 100) / size };
+            if pct / 10 > last_pct / 10 {
+                last_pct = pct;
+                print!("\r  {:3}% ({}/{}KiB)", pct, received / 1024, size / 1024);
+            }
+            if received == size {
+                break;
+            }
+            chunk = match self.http.response_more() {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    println!();
+                    unsafe { let _ = free_pages(ptr, pages); }
+                    return Err(e);
+                },
+            };
+        }
+        println!();
+
+        let ms = ((mrld::x86::rdtsc() - start) / self.tsc_per_ms).max(1);
+        println!("  '{}': {}KiB in {}ms ({}KiB/s)",
+            url, size / 1024, ms, (size as u64 * 1000 / 1024) / ms
+        );
+        Ok((ptr, size))
+    }
+}
@@ -3,7 +3,7 @@
 //! The process is here is probably going to be something like:
 //!
 //! - Set up arguments passed to the kernel
-//! - Download the kernel over PXE
+//! - Download the kernel over PXE (TFTP or HTTP)
 //! - Load the kernel into physical memory
 //! - Set up and switch into a new set of page tables
 //! - Set up and switch into new interrupt tables
@@ -16,32 +16,57 @@
 // We're using the 'global_allocator' feature in the 'uefi' crate. 
 // We can probably reclaim memory later when the kernel is running.
 #![feature(allocator_api)]
+extern crate alloc;
 
 mod bup;
 mod pxe;
+mod http;
+mod net;
+mod modules;
+mod manifest;
+mod menu;
+mod chainload;
 mod smp;
+mod experiment;
+mod crashlog;
+mod acpi_override;
 
 use core::ptr::NonNull;
 use uefi::prelude::*;
 use uefi::println;
 use uefi::boot::{ AllocateType, MemoryType };
 use uefi::mem::memory_map::*;
-use mrld::{ MrldBootArgs, };
+use mrld::{ MrldBootArgs, MrldBootTimeline };
+use mrld::physmem::MrldMemoryKind;
 
 #[entry]
 fn efi_main() -> Status {
+    // Keep track of how long each stage of the boot process takes
+    let mut timeline = MrldBootTimeline::new_empty();
+    timeline.record("efi_main");
+
     uefi::helpers::init().unwrap();
     bup::do_console_init();
 
+    // Reserve the crash log region before anything else can allocate it
+    let crashlog_addr = crashlog::reserve();
+
     println!("[*] HELO from the mrld boot-stub :^)");
     println!("  Firmware Vendor:   {}", uefi::system::firmware_vendor());
     println!("  Firmware Revision: {}", uefi::system::firmware_revision());
+    timeline.tsc_freq = bup::measure_tsc_freq();
+    println!("  TSC frequency:     {}MHz", timeline.tsc_freq / 1_000_000);
+    timeline.record("console");
+
+    // Give the user a chance to change options for this boot
+    let opts = menu::run(timeline.tsc_freq);
+    timeline.record("menu");
 
     // Allocate for boot arguments and synthesize a mutable reference to them.
     let boot_args: &mut MrldBootArgs = unsafe { 
         let ptr: NonNull<u8> = uefi::boot::allocate_pages(
             AllocateType::AnyPages,
-            MemoryType::LOADER_DATA,
+            MrldMemoryKind::BootArgs.as_uefi_type(),
             (core::mem::size_of::<MrldBootArgs>() / uefi::boot::PAGE_SIZE) + 1
         ).unwrap();
 
@@ -52,45 +77,155 @@ fn efi_main() -> Status {
 
     // Fill in the physical address of the RDSP table.
     // NOTE: We can parse ACPI tables in the kernel later if we need to.
-    boot_args.rsdp_addr = { 
-        use uefi::table::cfg::ACPI2_GUID;
-        uefi::system::with_config_table(|tbl| {
-            let rdsp = tbl.iter().find(|e| e.guid == ACPI2_GUID).unwrap();
-            rdsp.address as u64
-        })
-    };
+    boot_args.rsdp_addr = bup::get_rsdp_addr();
+
+    // Tell the kernel which firmware (and machine) it's running on. 
+    boot_args.firmware = bup::get_firmware_info();
+    println!("[*] SMBIOS entry points: 2.x={:016x} 3.x={:016x}", 
+        boot_args.firmware.smbios_addr, boot_args.firmware.smbios3_addr
+    );
+
+    // Pass the GOP framebuffer to the kernel (if we have one). 
+    if let Some(fb) = bup::get_framebuffer() { 
+        println!("[*] Framebuffer at {:016x} ({}x{}, stride {}, {:?})",
+            fb.base, fb.width, fb.height, fb.stride, fb.format
+        );
+        boot_args.framebuffer = fb;
+    } else { 
+        println!("[!] No linear framebuffer available");
+    }
+
+    // Pass the kernel command line
+    boot_args.cmdline = opts.cmdline;
+    boot_args.crashlog = crashlog_addr;
+
+    // Download the kernel image (with TFTP or HTTP).
+    let mut net = net::NetClient::open(opts.transport, timeline.tsc_freq).map_err(|e| {
+        println!("[!] Error connecting to the boot server: {}", e);
+        bup::wait_for_shutdown();
+    }).unwrap();
+    timeline.record("dhcp");
+
+    // Upload the log from the last boot (if the kernel crashed)
+    crashlog::upload(&mut net, crashlog_addr);
+    timeline.record("crashlog upload");
 
-    // Download the kernel image via PXE.
-    let img = pxe::KernelImage::download().map_err(|e| {
+    let kernel_file = opts.kernel_file();
+    let img = pxe::KernelImage::download(&mut net, kernel_file).map_err(|e| {
         println!("[!] Error downloading kernel: {}", e);
         bup::wait_for_shutdown();
     }).unwrap();
-    println!("[!] Downloaded kernel ...");
+    println!("[!] Downloaded kernel '{}' ...", kernel_file.name);
+    timeline.record("kernel download");
+
+    // Check the kernel against its manifest (if we have one)
+    match net.download(kernel_file.manifest, MemoryType::LOADER_DATA) { 
+        Ok((ptr, size)) => { 
+            let data = unsafe { 
+                NonNull::slice_from_raw_parts(ptr, size).as_ref()
+            };
+            let manifest = manifest::KernelManifest::parse(data)
+                .and_then(|m| manifest::verify(&m, img.as_slice()).map(|_| m))
+                .map_err(|e| { 
+                    println!("[!] Kernel failed verification: {}", e);
+                    bup::wait_for_shutdown();
+                }).unwrap();
+            println!("[*] Verified kernel (build {})", manifest.build_id.as_str());
+            boot_args.kernel_build_id = manifest.build_id;
+        },
+        Err(e) => { 
+            println!("[!] No kernel manifest ({:?}), skipping verification", 
+                e.status()
+            );
+        },
+    }
+    timeline.record("kernel verify");
+
+    // Download any modules that should be passed to the kernel.
+    modules::download_modules(&mut net, boot_args);
+    timeline.record("modules");
+
+    // Replace or add ACPI tables (if the boot server has any)
+    boot_args.rsdp_addr = acpi_override::apply_overrides(
+        &mut net, boot_args.rsdp_addr
+    );
+    if let Err(e) = net.stop() { 
+        println!("[!] Couldn't stop network services: {}", e);
+    }
+    timeline.record("acpi overrides");
 
-    // Load the kernel into physical memory and find the entrypoint
-    let kernel_entrypt = unsafe { img.load().unwrap() };
+    // Validate the kernel and load it into physical memory
+    let kernel = unsafe { img.load(bup::KERNEL_PLACEMENT) }.map_err(|e| { 
+        println!("[!] Error loading kernel: {}", e);
+        bup::wait_for_shutdown();
+    }).unwrap();
     println!("[!] Loaded kernel into memory ...");
 
-    // Build a new set of page tables
-    let pml4_ptr = unsafe { 
-        let res = bup::build_page_tables();
-        //dump_pgtable(res.as_ptr());
-        res
-    };
+    // Tell the kernel where it was loaded
+    boot_args.kernel_phys_base = kernel.phys_base;
+    boot_args.kernel_virt_base = kernel.virt_base;
+    boot_args.kernel_size = kernel.size;
+    let segs = kernel.segments();
+    boot_args.kernel_segments[..segs.len()].copy_from_slice(segs);
+    boot_args.num_kernel_segments = segs.len();
+    let kernel_entrypt = unsafe { kernel.entrypoint() };
+
+    // Pass the kernel symbol table along (for symbolizing backtraces)
+    match unsafe { img.load_symbols(&kernel) } { 
+        Some(syms) => boot_args.kernel_symbols = syms,
+        None => println!("[!] Kernel has no symbol table?"),
+    }
+    timeline.record("elf load");
+
+    // Build a new set of page tables, and make sure the kernel can read 
+    // everything we're passing to it before it builds its own. 
+    let mut page_tables = unsafe { bup::BootPageTables::new(&kernel) }
+        .map_err(|e| { 
+            println!("[!] Error building page tables: {}", e);
+            bup::wait_for_shutdown();
+        }).unwrap();
+    unsafe { 
+        page_tables.map_identity(
+            boot_args.as_ptr() as u64, size_of::<MrldBootArgs>() as u64
+        );
+        let fb = &boot_args.framebuffer;
+        if fb.base != 0 { 
+            page_tables.map_identity(fb.base, fb.size as u64);
+        }
+        let syms = &boot_args.kernel_symbols;
+        page_tables.map_identity(syms.symtab, syms.symtab_size);
+        page_tables.map_identity(syms.strtab, syms.strtab_size);
+    }
     println!("[!] Wrote provisional page tables ...");
+    timeline.record("page tables");
 
 
     unsafe { 
-        // Exit UEFI boot services
-        let uefi_map = uefi::boot::exit_boot_services(None);
+        // Exit UEFI boot services. 
+        // The kernel needs the memory map when it calls SetVirtualAddressMap,
+        // so keep it somewhere that won't be treated as available memory. 
+        let uefi_map = uefi::boot::exit_boot_services(
+            Some(MrldMemoryKind::BootArgs.as_uefi_type())
+        );
+        timeline.record("exit_boot_services");
 
         // Pass the UEFI memory map to the kernel
         boot_args.uefi_map = uefi_map.buffer().as_ptr() as u64;
         boot_args.uefi_map_desc_size = uefi_map.meta().desc_size;
         boot_args.uefi_map_size = uefi_map.meta().map_size;
+        boot_args.uefi_map_desc_version = uefi_map.meta().desc_version;
+
+        // Pass the UEFI runtime services table to the kernel
+        boot_args.uefi_runtime = uefi::table::system_table_raw()
+            .map(|st| st.as_ref().runtime_services as u64)
+            .unwrap_or(0);
 
         // Switch to the new set of page tables
-        mrld::x86::CR3::write(pml4_ptr.as_ptr() as u64);
+        page_tables.map_identity(boot_args.uefi_map, boot_args.uefi_map_size as u64);
+        page_tables.activate();
+
+        // Pass the boot timeline to the kernel
+        boot_args.timeline = timeline;
 
         // Transfer control into the kernel
         kernel_entrypt(boot_args.as_ptr());
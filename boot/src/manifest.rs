@@ -0,0 +1,15 @@
+//! Kernel manifests.
+//!
+//! The manifest format and parser are in [`mrld::manifest`] (so they can be
+//! tested on the host). We only compute the digest of the kernel here.
+
+use sha2::{ Digest, Sha256 };
+pub use mrld::manifest::{ KernelManifest, ManifestError };
+
+/// Check a kernel image against a manifest.
+pub fn verify(manifest: &KernelManifest, data: &[u8])
+    -> Result<(), ManifestError>
+{
+    let digest: [u8; 32] = Sha256::digest(data).into();
+    manifest.check(data.len(), &digest)
+}
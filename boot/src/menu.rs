@@ -0,0 +1,213 @@
+//! Interactive boot menu.
+//!
+//! Before downloading the kernel, we count down for a few seconds. If a key
+//! is pressed during the countdown, we open a text menu that can change a
+//! few options for this boot only (without touching the PXE server):
+//!
+//! - Pick a kernel image (see [`KERNEL_FILES`])
+//! - Edit the kernel command line
+//! - Pick TFTP or HTTP for downloading files
+//! - Download and run another EFI application (see `src/chainload.rs`)
+//! - Run experiments with UEFI MP Services (see `src/experiment.rs`)
+//! - Dump the UEFI memory map or the current GDTR/IDTR
+//! - Reboot or shut down the machine
+
+use alloc::string::String;
+use core::time::Duration;
+use uefi::{ print, println, Status };
+use uefi::proto::console::text::{ Key, ScanCode };
+use uefi::runtime::ResetType;
+use mrld::MrldCmdline;
+
+use crate::bup;
+use crate::chainload;
+use crate::experiment;
+use crate::net::Transport;
+use crate::pxe::{ KernelFile, KERNEL_FILES };
+
+/// How long to wait for a keypress before booting (in seconds)
+pub const COUNTDOWN_SECS: u64 = 3;
+
+/// Options selected with the boot menu.
+pub struct BootOptions {
+    /// Index of the selected kernel image in [`KERNEL_FILES`]
+    pub kernel: usize,
+    /// Kernel command line
+    pub cmdline: MrldCmdline,
+    /// How files are downloaded from the boot server
+    pub transport: Transport,
+}
+impl BootOptions {
+    pub fn new_default() -> Self {
+        Self { 
+            kernel: 0, 
+            cmdline: MrldCmdline::new_empty(), 
+            transport: Transport::Auto,
+        }
+    }
+
+    /// Return the selected kernel image.
+    pub fn kernel_file(&self) -> &'static KernelFile {
+        &KERNEL_FILES[self.kernel]
+    }
+}
+
+/// Return the next key (if one has been pressed).
+fn poll_key() -> Option<Key> {
+    uefi::system::with_stdin(|stdin| stdin.read_key().ok().flatten())
+}
+
+/// Wait [indefinitely] for the next key.
+pub fn wait_key() -> Key {
+    loop {
+        let key_event = uefi::system::with_stdin(|stdin| {
+            stdin.wait_for_key_event().unwrap()
+        });
+        let mut events = [ key_event ];
+        uefi::boot::wait_for_event(&mut events).unwrap();
+        if let Some(key) = poll_key() {
+            return key;
+        }
+    }
+}
+
+/// Count down for [`COUNTDOWN_SECS`].
+/// Returns 'true' if a key was pressed.
+fn countdown() -> bool {
+    // Ignore anything pressed before we got here
+    while poll_key().is_some() {}
+
+    for secs in (1..=COUNTDOWN_SECS).rev() {
+        print!("\r[*] Press any key for the boot menu ({}) ", secs);
+        for _ in 0..10 {
+            if poll_key().is_some() {
+                println!();
+                return true;
+            }
+            uefi::boot::stall(Duration::from_millis(100));
+        }
+    }
+    println!();
+    false
+}
+
+/// Edit a line of text (up to 'max_len' bytes). 
+/// Returns [`None`] if escape was pressed. 
+pub fn edit_line(line: &str, max_len: usize) -> Option<String> {
+    let mut buf = String::from(line);
+
+    print!("  > {}", buf);
+    loop {
+        match wait_key() {
+            Key::Special(ScanCode::ESCAPE) => {
+                println!();
+                return None;
+            },
+            Key::Special(_) => {},
+            Key::Printable(c) => match char::from(c) {
+                '\r' | '\n' => break,
+                '\u{8}' => {
+                    if buf.pop().is_some() {
+                        print!("\u{8} \u{8}");
+                    }
+                },
+                c if c.is_ascii() && !c.is_ascii_control() => {
+                    if buf.len() < max_len {
+                        buf.push(c);
+                        print!("{}", c);
+                    }
+                },
+                _ => {},
+            },
+        }
+    }
+    println!();
+    Some(buf)
+}
+
+/// Edit a command line. Pressing escape discards any changes.
+fn edit_cmdline(cmdline: &MrldCmdline) -> MrldCmdline {
+    edit_line(cmdline.as_str(), MrldCmdline::LEN)
+        .map(|s| MrldCmdline::new(&s))
+        .unwrap_or(*cmdline)
+}
+
+/// Ask for an EFI image and load options, then run the image.
+fn run_chainload(transport: Transport, tsc_freq: u64, name: &mut String, 
+    options: &mut String)
+{
+    println!("  EFI image (Escape to cancel):");
+    let Some(new_name) = edit_line(name, chainload::MAX_NAME_LEN) else { 
+        return;
+    };
+    *name = new_name;
+    if name.is_empty() { 
+        return;
+    }
+    println!("  Load options:");
+    let Some(new_options) = edit_line(options, chainload::MAX_OPTIONS_LEN) else { 
+        return;
+    };
+    *options = new_options;
+
+    if let Err(e) = chainload::chainload(transport, tsc_freq, name, options) {
+        println!("[!] '{}' failed: {}", name, e);
+    }
+}
+
+/// Show the boot menu if a key is pressed during the countdown.
+/// Returns the options for this boot.
+///
+/// 'tsc_freq' (from [`bup::measure_tsc_freq`]) is passed on to anything
+/// that connects to the boot server.
+pub fn run(tsc_freq: u64) -> BootOptions {
+    let mut opts = BootOptions::new_default();
+    if !countdown() {
+        return opts;
+    }
+
+    // Remember the last chainloaded image (and its options)
+    let mut efi_name = String::new();
+    let mut efi_options = String::new();
+
+    loop {
+        println!();
+        println!("[*] mrld boot menu:");
+        println!("  k     - Kernel image: {}", opts.kernel_file().name);
+        println!("  c     - Command line: '{}'", opts.cmdline.as_str());
+        println!("  t     - Transport:    {:?}", opts.transport);
+        println!("  e     - Run another EFI application");
+        println!("  x     - Run experiments with UEFI MP Services");
+        println!("  m     - Dump the UEFI memory map");
+        println!("  d     - Dump the GDTR/IDTR");
+        println!("  r     - Reboot");
+        println!("  s     - Shut down");
+        println!("  Enter - Boot");
+
+        let Key::Printable(c) = wait_key() else {
+            continue;
+        };
+        match char::from(c) {
+            'k' => opts.kernel = (opts.kernel + 1) % KERNEL_FILES.len(),
+            'c' => opts.cmdline = edit_cmdline(&opts.cmdline),
+            't' => opts.transport = opts.transport.next(),
+            'e' => { 
+                run_chainload(opts.transport, tsc_freq, 
+                    &mut efi_name, &mut efi_options
+                );
+            },
+            'x' => experiment::run(tsc_freq),
+            'm' => {
+                if let Err(e) = bup::dump_memory_map() {
+                    println!("[!] Couldn't read the memory map: {}", e);
+                }
+            },
+            'd' => unsafe { bup::dump_dtrs() },
+            'r' => uefi::runtime::reset(ResetType::COLD, Status::SUCCESS, None),
+            's' => uefi::runtime::reset(ResetType::SHUTDOWN, Status::SUCCESS, None),
+            '\r' | '\n' => break,
+            _ => {},
+        }
+    }
+    opts
+}
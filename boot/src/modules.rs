@@ -0,0 +1,82 @@
+//! Boot modules.
+//!
+//! After downloading the kernel, we fetch a list of modules from the boot
+//! server (see [`MODULE_LIST_FILENAME`]). This is a text file with one
+//! filename per line (blank lines and lines starting with '#' are ignored).
+//! Each file is downloaded into memory and described to the kernel with a
+//! [`MrldBootModule`].
+//!
+//! The module list is optional: when it doesn't exist, we just don't load
+//! any modules.
+
+use uefi::{ println, CStr8, cstr8, boot::MemoryType };
+use mrld::{ MrldBootArgs, MrldBootModule, MAX_BOOT_MODULES };
+use mrld::physmem::MrldMemoryKind;
+use core::ptr::NonNull;
+
+use crate::net::NetClient;
+
+/// Fixed remote filename of the module list on the boot server
+pub const MODULE_LIST_FILENAME: &'static CStr8 = cstr8!("mrld-modules");
+
+/// Download each file in the module list and describe them in the boot
+/// arguments passed to the kernel.
+pub fn download_modules(net: &mut NetClient, args: &mut MrldBootArgs) {
+    let res = net.download(MODULE_LIST_FILENAME, MemoryType::LOADER_DATA);
+    let (list_ptr, list_size) = match res {
+        Ok(res) => res,
+        Err(e) => {
+            println!("[*] No module list ({:?}), skipping modules", e.status());
+            return;
+        },
+    };
+    let list = unsafe {
+        NonNull::slice_from_raw_parts(list_ptr, list_size).as_ref()
+    };
+    let Ok(list) = core::str::from_utf8(list) else {
+        println!("[!] Module list isn't valid UTF-8?");
+        return;
+    };
+
+    println!("[*] Downloading modules ...");
+    for line in list.lines() {
+        let name = line.trim();
+        if name.is_empty() || name.starts_with('#') {
+            continue;
+        }
+        if args.num_modules >= MAX_BOOT_MODULES {
+            println!("[!] More than {} modules, ignoring the rest",
+                MAX_BOOT_MODULES
+            );
+            break;
+        }
+
+        // TFTP expects a NUL-terminated filename
+        let mut buf = [0u8; MrldBootModule::NAME_LEN + 1];
+        if name.len() > MrldBootModule::NAME_LEN {
+            println!("[!] Module name '{}' is too long", name);
+            continue;
+        }
+        buf[..name.len()].copy_from_slice(name.as_bytes());
+        let Ok(filename) = CStr8::from_bytes_with_nul(&buf[..=name.len()])
+        else {
+            println!("[!] Invalid module name '{}'", name);
+            continue;
+        };
+
+        let mem_ty = MrldMemoryKind::BootModule.as_uefi_type();
+        match net.download(filename, mem_ty) {
+            Ok((ptr, size)) => {
+                let addr = ptr.as_ptr() as u64;
+                println!("  Module '{}' at {:016x} ({}B)", name, addr, size);
+                args.modules[args.num_modules] = MrldBootModule::new(
+                    name, addr, size as u64
+                );
+                args.num_modules += 1;
+            },
+            Err(e) => {
+                println!("[!] Couldn't download module '{}': {}", name, e);
+            },
+        }
+    }
+}
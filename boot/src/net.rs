@@ -0,0 +1,95 @@
+//! Downloading files from the boot server.
+//!
+//! Files can be downloaded with TFTP (see [`PxeClient`]) or HTTP (see
+//! [`HttpClient`]). By default, we use whichever one was used to load the
+//! bootloader, but the transport can also be picked in the boot menu.
+
+use alloc::format;
+use core::ptr::NonNull;
+use uefi::{ println, CStr8 };
+use uefi::boot::MemoryType;
+
+use crate::http::{ self, HttpClient };
+use crate::pxe::PxeClient;
+
+/// How files are downloaded from the boot server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    /// Use whichever transport was used to load the bootloader
+    Auto,
+    /// Always use TFTP
+    Tftp,
+    /// Always use HTTP
+    Http,
+}
+impl Transport {
+    /// Return the next transport (for cycling through them in the menu).
+    pub fn next(self) -> Self {
+        match self {
+            Self::Auto => Self::Tftp,
+            Self::Tftp => Self::Http,
+            Self::Http => Self::Auto,
+        }
+    }
+}
+
+/// A connection to the boot server.
+pub enum NetClient {
+    Tftp(PxeClient),
+    Http(HttpClient),
+}
+impl NetClient {
+    /// Connect to the boot server. 'tsc_freq' is the TSC frequency (in Hz)
+    /// from [`crate::bup::measure_tsc_freq`], which we use for reporting
+    /// throughput.
+    pub fn open(transport: Transport, tsc_freq: u64) -> uefi::Result<Self> {
+        let boot_url = http::boot_url();
+        match (transport, boot_url) {
+            (Transport::Tftp, _) | (Transport::Auto, None) => {
+                Ok(Self::Tftp(PxeClient::open(tsc_freq)?))
+            },
+            (Transport::Http, Some(url)) | (Transport::Auto, Some(url)) => {
+                Ok(Self::Http(HttpClient::open(url, tsc_freq)?))
+            },
+            // We were loaded with TFTP, so assume that the HTTP server is
+            // running on the same machine as the TFTP server.
+            (Transport::Http, None) => {
+                let pxe = PxeClient::open(tsc_freq)?;
+                let url = format!("http://{}:{}/",
+                    pxe.server_ip(), http::DEFAULT_PORT
+                );
+                if let Err(e) = pxe.stop() {
+                    println!("[!] Couldn't stop PXE services: {}", e);
+                }
+                Ok(Self::Http(HttpClient::open(url, tsc_freq)?))
+            },
+        }
+    }
+
+    /// Download a file into newly-allocated pages with the given memory
+    /// type. Returns a pointer to the file and its size (in bytes).
+    pub fn download(&mut self, filename: &CStr8, mem_ty: MemoryType)
+        -> uefi::Result<(NonNull<u8>, usize)>
+    {
+        match self {
+            Self::Tftp(pxe) => pxe.download(filename, mem_ty),
+            Self::Http(http) => http.download(filename, mem_ty),
+        }
+    }
+
+    /// Upload a file to the boot server. 
+    pub fn upload(&mut self, filename: &CStr8, data: &[u8]) -> uefi::Result<()> {
+        match self {
+            Self::Tftp(pxe) => pxe.upload(filename, data),
+            Self::Http(http) => http.upload(filename, data),
+        }
+    }
+
+    /// Close the connection.
+    pub fn stop(self) -> uefi::Result<()> {
+        match self {
+            Self::Tftp(pxe) => pxe.stop(),
+            Self::Http(_) => Ok(()),
+        }
+    }
+}
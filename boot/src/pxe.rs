@@ -1,64 +1,116 @@
 //! 'mrld' PXE kernel loader
+//!
+//! Downloads are done with TFTP using the PXE Base Code protocol. 
+//!
+//! - We prefer the NIC that the bootloader was loaded from, and fall back to
+//!   any other NIC with PXE support
+//! - If PXE hasn't been started (or hasn't done DHCP yet), we do it ourselves
+//! - Transient failures are retried with exponential backoff (see 
+//!   [`MAX_ATTEMPTS`] and [`INITIAL_BACKOFF_MS`])
+//! - Progress is reported with the PXE Base Code Callback protocol, which 
+//!   firmware calls for each packet it receives
 
 use uefi::{
-    println, CStr8, cstr8,
+    print, println, CStr8, cstr8, guid, Guid, Handle, Identify, Status,
     boot::{
         AllocateType,
         MemoryType,
-        get_handle_for_protocol,
+        PAGE_SIZE,
+        SearchType,
+        allocate_pages,
+        free_pages,
+        image_handle,
+        install_protocol_interface,
+        locate_handle_buffer,
+        uninstall_protocol_interface,
+        ScopedProtocol,
         open_protocol_exclusive,
     },
     proto::{
+        loaded_image::LoadedImage,
         network::{
             //IpAddress,
             pxe::{ BaseCode, DhcpV4Packet },
         },
     },
 };
+use core::ffi::c_void;
 use core::ptr::NonNull;
 use core::net::{ IpAddr, Ipv4Addr };
+use core::sync::atomic::{ AtomicUsize, Ordering };
+use core::time::Duration;
+use mrld::{ MrldKernelSegment, MrldKernelSymbols, MAX_KERNEL_SEGMENTS };
+use mrld::physmem::MrldMemoryKind;
+use mrld::compress::CompressedHeader;
+use mrld::image::{ KernelElf, ImageError, page_range, KERNEL_VIRT_BASE };
+use elf::{ ElfBytes, endian::LittleEndian, abi::* };
 
-/// Helper for allocating/downloading/loading an 'mrld' kernel ELF. 
-pub struct KernelImage { 
-    /// Pointer to the kernel ELF
-    pub ptr: NonNull<u8>,
-    /// Size of the kernel ELF (in bytes)
-    pub size: usize,
+use crate::net::NetClient;
+use crate::bup::{ self, KernelPlacement };
+
+/// Maximum number of attempts for each download
+pub const MAX_ATTEMPTS: usize = 5;
+
+/// Delay before retrying a failed download (doubled after each attempt)
+pub const INITIAL_BACKOFF_MS: u64 = 500;
+
+/// Helper for downloading files from the PXE server with TFTP. 
+pub struct PxeClient { 
+    /// The PXE Base Code protocol
+    base_code: ScopedProtocol<BaseCode>,
+    /// Handle for the NIC we're using
+    handle: Handle,
+    /// Address of the TFTP server
+    server_ip: IpAddr,
+    /// Set when we've installed [`PXE_CALLBACK`] on 'handle'
+    has_callback: bool,
+    /// Approximate TSC frequency (in ticks per millisecond)
+    tsc_per_ms: u64,
 }
-impl KernelImage {
-    /// Fixed remote filename on the PXE server
-    pub const REMOTE_FILENAME: &'static CStr8 = cstr8!("mrld-kernel-debug");
+impl PxeClient { 
+    /// Find a NIC with PXE support and find the TFTP server. 
+    ///
+    /// We try the NIC that the bootloader was loaded from first. 
+    /// 'tsc_freq' (in Hz) is only used for reporting throughput. 
+    pub fn open(tsc_freq: u64) -> uefi::Result<Self> { 
+        let boot_dev = open_protocol_exclusive::<LoadedImage>(image_handle())
+            .ok()
+            .and_then(|img| img.device());
+        if let Some(handle) = boot_dev { 
+            match Self::open_handle(handle, tsc_freq) { 
+                Ok(res) => return Ok(res),
+                Err(e) => println!("[!] Couldn't use PXE on the boot device: {}", e),
+            }
+        }
 
-    pub fn as_mut_slice(&mut self) -> &mut [u8] { 
-        unsafe { 
-            NonNull::slice_from_raw_parts(self.ptr, self.size).as_mut()
+        let handles = locate_handle_buffer(
+            SearchType::ByProtocol(&BaseCode::GUID)
+        )?;
+        let mut last_err = uefi::Error::new(Status::NOT_FOUND, ());
+        for handle in handles.iter().filter(|h| Some(**h) != boot_dev) { 
+            match Self::open_handle(*handle, tsc_freq) { 
+                Ok(res) => return Ok(res),
+                Err(e) => { 
+                    println!("[!] Couldn't use PXE on {:?}: {}", handle, e);
+                    last_err = e;
+                },
+            }
         }
+        Err(last_err)
     }
-}
 
-impl KernelImage {
-    /// Download the kernel image with the UEFI PXE protocol.
-    ///
-    /// NOTE: Currently, we *expect* the bootloader itself has been loaded 
-    /// over PXE, and we return an error if PXE is not already started. 
-    pub fn download() -> uefi::Result<Self> { 
-        let handle = get_handle_for_protocol::<BaseCode>()?;
+    /// Start PXE on a particular NIC (if necessary) and find the TFTP server.
+    fn open_handle(handle: Handle, tsc_freq: u64) -> uefi::Result<Self> { 
         let mut base_code = open_protocol_exclusive::<BaseCode>(handle)?;
 
         match base_code.start(false) { 
-            Ok(_) => {
-                println!("[!] PXE services were not already started?");
-                return Err(uefi::Error::new(uefi::Status::NOT_READY, ()));
-            },
-            Err(e) => {
-                match e.status() { 
-                    uefi::Status::ALREADY_STARTED => {},
-                    _ => return Err(e),
-                }
-            },
+            Ok(_) => println!("[*] Started PXE services"),
+            Err(e) if e.status() == Status::ALREADY_STARTED => {},
+            Err(e) => return Err(e),
         }
         if !base_code.mode().dhcp_ack_received() { 
-            return Err(uefi::Error::new(uefi::Status::NOT_READY, ()));
+            println!("[*] Running DHCP ...");
+            base_code.dhcp(false)?;
         }
 
         // Get the address of the DHCP server, which we *assume* is also 
@@ -66,90 +118,676 @@ impl KernelImage {
         let ack: &DhcpV4Packet = base_code.mode().dhcp_ack().as_ref();
         if ack.bootp_si_addr == [0, 0, 0, 0] { 
             println!("[!] DHCPv4 ACK had no server address (SIADDR)?");
-            return Err(uefi::Error::new(uefi::Status::NOT_FOUND, ()));
+            return Err(uefi::Error::new(Status::NOT_FOUND, ()));
         }
-
         let server_ip = IpAddr::V4(Ipv4Addr::from_octets(ack.bootp_si_addr));
-        let kernel_sz = base_code.tftp_get_file_size(
-            &server_ip, 
-            &Self::REMOTE_FILENAME
-        )?;
-        let ptr: NonNull<u8> = uefi::boot::allocate_pages(
-            AllocateType::AnyPages,
-            MemoryType::LOADER_DATA, 
-            (kernel_sz as usize / uefi::boot::PAGE_SIZE) + 1,
-        )?;
+        println!("[*] Using TFTP server {} (client {})", server_ip, 
+            Ipv4Addr::from_octets(ack.bootp_yi_addr)
+        );
 
+        let has_callback = Self::enable_callback(handle, &mut base_code);
+        if !has_callback { 
+            println!("[!] Couldn't enable PXE callbacks, no download progress");
+        }
+
+        let tsc_per_ms = (tsc_freq / 1000).max(1);
+        Ok(Self { base_code, handle, server_ip, has_callback, tsc_per_ms })
+    }
 
-        let mut res = KernelImage { 
-            size: kernel_sz as usize,
-            ptr,
+    /// Install [`PXE_CALLBACK`] on the NIC and ask firmware to use it.
+    fn enable_callback(handle: Handle, base_code: &mut BaseCode) -> bool { 
+        let res = unsafe { 
+            install_protocol_interface(Some(handle), 
+                &PxeCallback::GUID, 
+                &PXE_CALLBACK as *const PxeCallback as *const c_void
+            )
         };
-        base_code.tftp_read_file(
-            &server_ip, 
-            &Self::REMOTE_FILENAME,
-            Some(res.as_mut_slice())
-        )?;
+        if res.is_err() { 
+            return false;
+        }
+        if base_code.set_parameters(None, None, None, None, Some(true)).is_err() {
+            Self::disable_callback(handle);
+            return false;
+        }
+        true
+    }
 
-        match base_code.stop() {
-            Ok(_) => {},
-            Err(e) => {
-                match e.status() { 
-                    _ => return Err(e),
-                }
-            },
+    /// Uninstall [`PXE_CALLBACK`] from the NIC. 
+    fn disable_callback(handle: Handle) { 
+        unsafe { 
+            let _ = uninstall_protocol_interface(handle, 
+                &PxeCallback::GUID, 
+                &PXE_CALLBACK as *const PxeCallback as *const c_void
+            );
         }
+    }
 
-        Ok(res)
+    /// Return the address of the TFTP server. 
+    pub fn server_ip(&self) -> IpAddr { 
+        self.server_ip
     }
 
-    /// Load the kernel into physical memory and return the entrypoint.
-    ///
-    /// Conventions
-    /// ===========
+    /// Returns 'true' if a failed download is worth retrying. 
     ///
-    /// - The *load address* of a segment is a physical address (which we can
-    ///   expect to be identity mapped when running in the bootloader here)
+    /// NOTE: A TFTP error means that the server actually responded 
+    /// (ie. because the file doesn't exist), so we don't retry those. 
+    fn is_transient(status: Status) -> bool { 
+        matches!(status, 
+            Status::TIMEOUT | 
+            Status::NO_RESPONSE | 
+            Status::DEVICE_ERROR | 
+            Status::ICMP_ERROR | 
+            Status::NOT_READY |
+            Status::ABORTED
+        )
+    }
+
+    /// Download a file into newly-allocated pages with the given memory 
+    /// type. Returns a pointer to the file and its size (in bytes). 
     ///
-    /// - Non-loadable segments are ignored
+    /// Transient failures are retried up to [`MAX_ATTEMPTS`] times. 
+    pub fn download(&mut self, filename: &CStr8, mem_ty: MemoryType) 
+        -> uefi::Result<(NonNull<u8>, usize)> 
+    {
+        let mut delay = INITIAL_BACKOFF_MS;
+        for attempt in 1..=MAX_ATTEMPTS { 
+            match self.try_download(filename, mem_ty) { 
+                Ok(res) => return Ok(res),
+                Err(e) if attempt < MAX_ATTEMPTS && Self::is_transient(e.status()) => {
+                    println!("[!] Downloading '{}' failed ({:?}), retrying in {}ms ({}/{})",
+                        filename, e.status(), delay, attempt, MAX_ATTEMPTS
+                    );
+                    uefi::boot::stall(Duration::from_millis(delay));
+                    delay *= 2;
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        unreachable!();
+    }
+
+    /// Make a single attempt to download a file. 
+    /// The buffer is freed if the download fails. 
+    fn try_download(&mut self, filename: &CStr8, mem_ty: MemoryType) 
+        -> uefi::Result<(NonNull<u8>, usize)> 
+    {
+        let size = self.base_code.tftp_get_file_size(
+            &self.server_ip, 
+            filename
+        )? as usize;
+        let pages = size.div_ceil(PAGE_SIZE).max(1);
+        let ptr: NonNull<u8> = allocate_pages(
+            AllocateType::AnyPages,
+            mem_ty,
+            pages,
+        )?;
+
+        let buf = unsafe { 
+            NonNull::slice_from_raw_parts(ptr, size).as_mut()
+        };
+        PROGRESS.start(size);
+        let start = mrld::x86::rdtsc();
+        let res = self.base_code.tftp_read_file(
+            &self.server_ip, 
+            filename,
+            Some(buf)
+        );
+        let ms = ((mrld::x86::rdtsc() - start) / self.tsc_per_ms).max(1);
+        if self.has_callback { 
+            println!();
+        }
+        if let Err(e) = res { 
+            unsafe { let _ = free_pages(ptr, pages); }
+            return Err(e);
+        }
+        println!("  '{}': {}KiB in {}ms ({}KiB/s)", 
+            filename, size / 1024, ms, (size as u64 * 1000 / 1024) / ms
+        );
+        Ok((ptr, size))
+    }
+
+    /// Upload a file to the TFTP server. 
     ///
-    /// - The entrypoint has the type [`mrld::MrldKernelEntrypoint`]
+    /// NOTE: The TFTP server needs to accept writes (see `start-network.sh`).
+    /// The TFTP server built into QEMU is read-only. 
+    pub fn upload(&mut self, filename: &CStr8, data: &[u8]) -> uefi::Result<()> { 
+        self.base_code.tftp_write_file(&self.server_ip, filename, true, data)
+    }
+
+    /// Stop PXE services. 
+    pub fn stop(mut self) -> uefi::Result<()> { 
+        if self.has_callback { 
+            let _ = self.base_code.set_parameters(None, None, None, None, Some(false));
+            Self::disable_callback(self.handle);
+        }
+        self.base_code.stop()
+    }
+}
+
+/// Progress of the current TFTP download (updated by [`pxe_callback`]).
+struct Progress { 
+    /// Expected size of the file (in bytes)
+    total: AtomicUsize,
+    /// Number of bytes received so far
+    received: AtomicUsize,
+    /// The last percentage we printed
+    last_pct: AtomicUsize,
+}
+impl Progress { 
+    fn start(&self, total: usize) { 
+        self.total.store(total, Ordering::Relaxed);
+        self.received.store(0, Ordering::Relaxed);
+        self.last_pct.store(0, Ordering::Relaxed);
+    }
+
+    /// Account for some received bytes, printing the progress every 10%. 
+    fn update(&self, len: usize) { 
+        let total = self.total.load(Ordering::Relaxed);
+        let received = self.received.fetch_add(len, Ordering::Relaxed) + len;
+        if total == 0 { 
+            return;
+        }
+        let pct = (received.min(total) * 100) / total;
+        if pct / 10 > self.last_pct.load(Ordering::Relaxed) / 10 { 
+            self.last_pct.store(pct, Ordering::Relaxed);
+            print!("\r  {:3}% ({}/{}KiB)", pct, received / 1024, total / 1024);
+        }
+    }
+}
+static PROGRESS: Progress = Progress { 
+    total: AtomicUsize::new(0),
+    received: AtomicUsize::new(0),
+    last_pct: AtomicUsize::new(0),
+};
+
+/// The PXE Base Code Callback protocol. 
+/// Firmware calls 'callback' whenever a packet is sent or received. 
+#[repr(C)]
+struct PxeCallback { 
+    revision: u64,
+    callback: unsafe extern "efiapi" fn(
+        this: *mut PxeCallback,
+        function: u32,
+        received: u8,
+        packet_len: u32,
+        packet: *const u8,
+    ) -> u32,
+}
+impl PxeCallback { 
+    const GUID: Guid = guid!("245dca21-fb7b-11d3-8f01-00a0c969723b");
+    const REVISION: u64 = 0x0001_0000;
+
+    /// 'EFI_PXE_BASE_CODE_FUNCTION_MTFTP'
+    const FUNCTION_MTFTP: u32 = 3;
+    /// 'EFI_PXE_BASE_CODE_CALLBACK_STATUS_CONTINUE'
+    const STATUS_CONTINUE: u32 = 0;
+    /// TFTP opcode for a data packet
+    const TFTP_DATA: u16 = 3;
+}
+
+static PXE_CALLBACK: PxeCallback = PxeCallback { 
+    revision: PxeCallback::REVISION,
+    callback: pxe_callback,
+};
+
+/// Count the payload bytes in each received TFTP data packet. 
+unsafe extern "efiapi" fn pxe_callback(
+    _this: *mut PxeCallback,
+    function: u32,
+    received: u8,
+    packet_len: u32,
+    packet: *const u8,
+) -> u32 { 
+    if function != PxeCallback::FUNCTION_MTFTP || received == 0 || 
+        packet.is_null() || packet_len < 4 
+    { 
+        return PxeCallback::STATUS_CONTINUE;
+    }
+    // TFTP packets are big-endian
+    let opcode = u16::from_be_bytes([*packet, *packet.add(1)]);
+    if opcode == PxeCallback::TFTP_DATA { 
+        PROGRESS.update(packet_len as usize - 4);
+    }
+    PxeCallback::STATUS_CONTINUE
+}
+
+/// A kernel image (and its manifest) available on the PXE server. 
+pub struct KernelFile { 
+    /// Remote filename of the kernel ELF
+    pub name: &'static CStr8,
+    /// Remote filename of the kernel manifest
+    pub manifest: &'static CStr8,
+}
+
+/// Kernel images that can be selected from the boot menu. 
+/// The first entry is the default. 
+pub const KERNEL_FILES: [KernelFile; 2] = [
+    KernelFile { 
+        name: cstr8!("mrld-kernel-debug"),
+        manifest: cstr8!("mrld-kernel-debug.manifest"),
+    },
+    KernelFile { 
+        name: cstr8!("mrld-kernel"),
+        manifest: cstr8!("mrld-kernel.manifest"),
+    },
+];
+
+/// Helper for allocating/downloading/loading an 'mrld' kernel ELF. 
+pub struct KernelImage { 
+    /// Pointer to the kernel ELF
+    pub ptr: NonNull<u8>,
+    /// Size of the kernel ELF (in bytes)
+    pub size: usize,
+}
+impl KernelImage {
+    pub fn as_slice(&self) -> &[u8] { 
+        unsafe { 
+            NonNull::slice_from_raw_parts(self.ptr, self.size).as_ref()
+        }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] { 
+        unsafe { 
+            NonNull::slice_from_raw_parts(self.ptr, self.size).as_mut()
+        }
+    }
+}
+
+impl KernelImage {
+    /// Download the kernel image from the boot server.
     ///
-    pub unsafe fn load(&self) -> uefi::Result<mrld::MrldKernelEntrypoint> {
-        use elf::{
-            endian::LittleEndian,
-            abi::PT_LOAD,
-            ElfBytes,
+    /// Compressed images (see [`mrld::compress`]) are decompressed into a 
+    /// new buffer, and the compressed data is freed. 
+    pub fn download(net: &mut NetClient, file: &KernelFile) 
+        -> uefi::Result<Self> 
+    { 
+        let (ptr, size) = net.download(file.name, MemoryType::LOADER_DATA)?;
+        let data = unsafe { 
+            NonNull::slice_from_raw_parts(ptr, size).as_ref()
         };
-        println!("[*] Loading kernel ...");
-        let elf = {
-            let slice = unsafe { 
-                NonNull::slice_from_raw_parts(self.ptr, self.size).as_ref()
+        let header = match CompressedHeader::parse(data) { 
+            Ok(Some(header)) => header,
+            Ok(None) => return Ok(KernelImage { ptr, size }),
+            Err(e) => { 
+                println!("[!] Bad compressed kernel image: {}", e);
+                unsafe { free_pages(ptr, size.div_ceil(PAGE_SIZE).max(1))?; }
+                return Err(Status::COMPROMISED_DATA.into());
+            },
+        };
+
+        println!("[*] Decompressing kernel ({}, {}B -> {}B) ...", 
+            header.format, size, header.size
+        );
+        let out_pages = header.size.div_ceil(PAGE_SIZE);
+        let res = allocate_pages(
+            AllocateType::AnyPages, 
+            MemoryType::LOADER_DATA, 
+            out_pages
+        ).and_then(|out_ptr| { 
+            let out = unsafe { 
+                NonNull::slice_from_raw_parts(out_ptr, header.size).as_mut()
             };
-            ElfBytes::<LittleEndian>::minimal_parse(slice).unwrap()
+            match header.decompress(data, out) { 
+                Ok(()) => Ok(out_ptr),
+                Err(e) => { 
+                    println!("[!] Couldn't decompress kernel: {}", e);
+                    unsafe { free_pages(out_ptr, out_pages)?; }
+                    Err(Status::COMPROMISED_DATA.into())
+                },
+            }
+        });
+        unsafe { free_pages(ptr, size.div_ceil(PAGE_SIZE).max(1))?; }
+        Ok(KernelImage { ptr: res?, size: header.size })
+    }
+
+    /// Validate the kernel ELF, then load it into physical memory. 
+    ///
+    /// The kernel is validated with [`KernelElf::parse`] (see 
+    /// `mrld/src/image.rs` for the conventions), and loaded at a 
+    /// 2MiB-aligned physical address chosen with 'placement'. 
+    ///
+    /// The physical pages backing each segment are allocated from UEFI 
+    /// (with the type for [`MrldMemoryKind::KernelImage`]), so firmware 
+    /// will not hand them out to anyone else. 
+    ///
+    /// When the 'kaslr' feature is enabled, the image is also moved to a 
+    /// random virtual base address (see [`bup::choose_kernel_virt_base`]).
+    /// This only works if the kernel was linked with '--emit-relocs'. 
+    pub unsafe fn load(&self, placement: KernelPlacement) 
+        -> Result<LoadedKernel, LoadError> 
+    {
+        println!("[*] Loading kernel ...");
+        let slice = unsafe { 
+            NonNull::slice_from_raw_parts(self.ptr, self.size).as_ref()
         };
-        println!("  Kernel entrypoint: {:016x}", elf.ehdr.e_entry);
-        let entrypt = elf.ehdr.e_entry;
+        let img = KernelElf::parse(slice).map_err(LoadError::Image)?;
+        let elf = &img.elf;
+        let entrypt = img.entrypt;
+        println!("  Kernel entrypoint: {:016x}", entrypt);
 
+        // For now, physical addresses are just offsets from the base of 
+        // the image until we've decided where to put it. 
+        let mut res = LoadedKernel::new_empty();
+        for seg in img.segments() { 
+            println!("  Kernel segment: v={:016x} memsz={:08x}",
+                seg.vaddr, seg.memsz
+            );
+        }
+        res.num_segments = img.segments().len();
+        res.segments[..res.num_segments].copy_from_slice(img.segments());
+        let offsets = img.offsets();
 
-        for seg in elf.segments().unwrap() {
-            println!("  Kernel segment: p={:016x} v={:016x}",
-                seg.p_paddr, seg.p_vaddr
+        // Decide where the image lives in physical/virtual memory
+        res.size = img.size();
+        res.phys_base = bup::choose_kernel_phys_base(placement, res.size)
+            .ok_or(LoadError::NoPlacement(res.size))?;
+        res.virt_base = bup::choose_kernel_virt_base(res.size);
+        if res.virt_base != KERNEL_VIRT_BASE && !has_relocations(elf) { 
+            println!("[!] Kernel has no relocations, ignoring KASLR");
+            res.virt_base = KERNEL_VIRT_BASE;
+        }
+        let delta = res.virt_base.wrapping_sub(KERNEL_VIRT_BASE);
+        println!("  Kernel base: p={:016x} v={:016x} size={:08x}", 
+            res.phys_base, res.virt_base, res.size
+        );
+        for seg in &mut res.segments[..res.num_segments] { 
+            seg.paddr += res.phys_base;
+            seg.vaddr = seg.vaddr.wrapping_add(delta);
+        }
+
+        // Reserve the physical pages for each segment
+        for idx in 0..res.num_segments { 
+            let (lo, hi) = page_range(&res.segments[idx]);
+            let pages = ((hi - lo) as usize) / PAGE_SIZE;
+            let alloc = allocate_pages(
+                AllocateType::Address(lo),
+                MrldMemoryKind::KernelImage.as_uefi_type(),
+                pages,
             );
-            if seg.p_type != PT_LOAD { continue; }
-            if seg.p_paddr == 0 { continue; }
-            let tgt = seg.p_paddr as *mut u8;
+            if let Err(e) = alloc { 
+                res.free_segments(idx);
+                return Err(LoadError::Alloc { 
+                    idx, paddr: lo, pages, status: e.status()
+                });
+            }
+        }
 
-            if seg.p_memsz > seg.p_filesz { 
-                tgt.write_bytes(0, seg.p_memsz as _);
+        // Copy each segment into place and zero the remainder
+        for (seg, off) in res.segments().iter().zip(offsets.iter()) { 
+            let tgt = seg.paddr as *mut u8;
+            let src = self.ptr.add(*off as usize);
+            tgt.copy_from(src.as_ptr(), seg.filesz as usize);
+            if seg.memsz > seg.filesz { 
+                tgt.add(seg.filesz as usize)
+                    .write_bytes(0, (seg.memsz - seg.filesz) as usize);
             }
+        }
 
-            let src = self.ptr.offset(seg.p_offset as isize);
-            tgt.copy_from(src.as_ptr(), seg.p_filesz as usize);
+        // Fix up absolute addresses if we moved the virtual base
+        if delta != 0 { 
+            if let Err(e) = relocate(elf, &res) { 
+                res.free_segments(res.num_segments);
+                return Err(e);
+            }
         }
-        unsafe { 
-            Ok(core::mem::transmute(entrypt))
+
+        res.entrypt = entrypt.wrapping_add(delta);
+        Ok(res)
+    }
+
+    /// Copy the symbol table from the kernel ELF into memory that survives
+    /// after exiting boot services, so the kernel can symbolize backtraces.
+    ///
+    /// Symbol values are adjusted to match the virtual base of the loaded
+    /// kernel. Returns 'None' if the kernel has no symbol table. 
+    pub unsafe fn load_symbols(&self, kernel: &LoadedKernel) 
+        -> Option<MrldKernelSymbols> 
+    {
+        /// Size of an 'Elf64_Sym' entry
+        const SYM_SIZE: usize = 24;
+
+        let slice = unsafe { 
+            NonNull::slice_from_raw_parts(self.ptr, self.size).as_ref()
+        };
+        let elf = ElfBytes::<LittleEndian>::minimal_parse(slice).ok()?;
+        let shdrs = elf.section_headers()?;
+        let symtab_hdr = shdrs.iter().find(|s| s.sh_type == SHT_SYMTAB)?;
+        let strtab_hdr = shdrs.get(symtab_hdr.sh_link as usize).ok()?;
+        let (symtab, _) = elf.section_data(&symtab_hdr).ok()?;
+        let (strtab, _) = elf.section_data(&strtab_hdr).ok()?;
+
+        let size = symtab.len() + strtab.len();
+        let ptr: NonNull<u8> = allocate_pages(
+            AllocateType::AnyPages,
+            MrldMemoryKind::KernelSymbols.as_uefi_type(),
+            size.div_ceil(PAGE_SIZE),
+        ).ok()?;
+        let symtab_ptr = ptr.as_ptr();
+        let strtab_ptr = symtab_ptr.add(symtab.len());
+        symtab_ptr.copy_from_nonoverlapping(symtab.as_ptr(), symtab.len());
+        strtab_ptr.copy_from_nonoverlapping(strtab.as_ptr(), strtab.len());
+
+        // Move symbols along with the kernel (when using KASLR)
+        let delta = kernel.virt_base.wrapping_sub(KERNEL_VIRT_BASE);
+        if delta != 0 { 
+            for idx in 0..(symtab.len() / SYM_SIZE) { 
+                let ent = symtab_ptr.add(idx * SYM_SIZE);
+                let shndx = (ent.add(6) as *const u16).read_unaligned();
+                if shndx == SHN_UNDEF || shndx == SHN_ABS { 
+                    continue;
+                }
+                let val = ent.add(8) as *mut u64;
+                val.write_unaligned(val.read_unaligned().wrapping_add(delta));
+            }
         }
+
+        println!("  Kernel symbols: {} entries, {}B strtab", 
+            symtab.len() / SYM_SIZE, strtab.len()
+        );
+        Some(MrldKernelSymbols { 
+            symtab: symtab_ptr as u64,
+            symtab_size: symtab.len() as u64,
+            strtab: strtab_ptr as u64,
+            strtab_size: strtab.len() as u64,
+        })
     }
 }
 
+/// Returns 'true' if the ELF has relocations that apply to loaded sections.
+fn has_relocations(elf: &ElfBytes<LittleEndian>) -> bool { 
+    let Some(shdrs) = elf.section_headers() else { 
+        return false;
+    };
+    shdrs.iter().any(|shdr| { 
+        shdr.sh_type == SHT_RELA && 
+        shdrs.get(shdr.sh_info as usize)
+            .map_or(false, |tgt| (tgt.sh_flags & SHF_ALLOC as u64) != 0)
+    })
+}
+
+/// Apply relocations to a loaded image whose virtual base has moved. 
+///
+/// These come from linking with '--emit-relocs', so they are the original
+/// relocations from each object file (with 'r_offset' adjusted to a 
+/// virtual address in the linked image). We only need to care about 
+/// absolute references: PC-relative references don't change when the 
+/// whole image moves. 
+///
+/// NOTE: The linker doesn't emit relocations for GOT entries that it 
+/// synthesizes, so we also fix up everything in '.got' that points into 
+/// the image. 
+unsafe fn relocate(elf: &ElfBytes<LittleEndian>, img: &LoadedKernel) 
+    -> Result<(), LoadError> 
+{
+    let delta = img.virt_base.wrapping_sub(KERNEL_VIRT_BASE);
+    let link_range = KERNEL_VIRT_BASE..(KERNEL_VIRT_BASE + img.size);
+
+    // Translate a link-time virtual address into a physical address
+    let to_phys = |vaddr: u64, len: u64| -> Result<*mut u8, LoadError> { 
+        if !link_range.contains(&vaddr) || vaddr + len > link_range.end { 
+            return Err(LoadError::RelocBounds(vaddr));
+        }
+        Ok((img.phys_base + (vaddr - KERNEL_VIRT_BASE)) as *mut u8)
+    };
+
+    let shdrs = elf.section_headers().ok_or(LoadError::NoRelocations)?;
+    let (symtab, _) = elf.symbol_table()
+        .map_err(LoadError::Parse)?
+        .ok_or(LoadError::NoRelocations)?;
+
+    let mut cnt = 0;
+    for shdr in shdrs.iter().filter(|s| s.sh_type == SHT_RELA) { 
+        let tgt = shdrs.get(shdr.sh_info as usize)
+            .map_err(LoadError::Parse)?;
+        if (tgt.sh_flags & SHF_ALLOC as u64) == 0 { 
+            continue;
+        }
+        let relas = elf.section_data_as_relas(&shdr)
+            .map_err(LoadError::Parse)?;
+        for rela in relas { 
+            // References to absolute/undefined symbols don't move
+            let sym = symtab.get(rela.r_sym as usize)
+                .map_err(LoadError::Parse)?;
+            if rela.r_sym != 0 && 
+                (sym.st_shndx == SHN_ABS || sym.st_shndx == SHN_UNDEF) 
+            { 
+                continue;
+            }
+
+            match rela.r_type { 
+                R_X86_64_NONE |
+                R_X86_64_PC32 |
+                R_X86_64_PC64 |
+                R_X86_64_PLT32 |
+                R_X86_64_GOTPCREL |
+                R_X86_64_GOTPCRELX |
+                R_X86_64_REX_GOTPCRELX => {},
+
+                R_X86_64_64 => { 
+                    let ptr = to_phys(rela.r_offset, 8)? as *mut u64;
+                    let val = ptr.read_unaligned();
+                    ptr.write_unaligned(val.wrapping_add(delta));
+                    cnt += 1;
+                },
+                R_X86_64_32S => { 
+                    let ptr = to_phys(rela.r_offset, 4)? as *mut i32;
+                    let val = (ptr.read_unaligned() as i64)
+                        .wrapping_add(delta as i64);
+                    let Ok(val) = i32::try_from(val) else { 
+                        return Err(LoadError::Relocation { 
+                            offset: rela.r_offset, ty: rela.r_type 
+                        });
+                    };
+                    ptr.write_unaligned(val);
+                    cnt += 1;
+                },
+                ty => { 
+                    return Err(LoadError::Relocation { 
+                        offset: rela.r_offset, ty
+                    });
+                },
+            }
+        }
+    }
+
+    if let Ok(Some(got)) = elf.section_header_by_name(".got") { 
+        for idx in 0..(got.sh_size / 8) { 
+            let ptr = to_phys(got.sh_addr + idx * 8, 8)? as *mut u64;
+            let val = ptr.read_unaligned();
+            if link_range.contains(&val) { 
+                ptr.write_unaligned(val.wrapping_add(delta));
+                cnt += 1;
+            }
+        }
+    }
+    println!("  Applied {} relocations (delta {:016x})", cnt, delta);
+    Ok(())
+}
+
+/// A kernel image that has been loaded into physical memory.
+pub struct LoadedKernel { 
+    /// Virtual address of the kernel entrypoint
+    pub entrypt: u64,
+    /// Physical base address of the image
+    pub phys_base: u64,
+    /// Virtual base address of the image
+    pub virt_base: u64,
+    /// Size of the image (in bytes, rounded up to [`mrld::image::KERNEL_ALIGN`])
+    pub size: u64,
+    /// Segments loaded into physical memory
+    pub segments: [MrldKernelSegment; MAX_KERNEL_SEGMENTS],
+    /// Number of valid entries in 'segments'
+    pub num_segments: usize,
+}
+impl LoadedKernel { 
+    fn new_empty() -> Self { 
+        Self { 
+            entrypt: 0,
+            phys_base: 0,
+            virt_base: 0,
+            size: 0,
+            segments: [MrldKernelSegment::new_empty(); MAX_KERNEL_SEGMENTS],
+            num_segments: 0,
+        }
+    }
+
+    pub fn segments(&self) -> &[MrldKernelSegment] { 
+        &self.segments[..self.num_segments]
+    }
+
+    /// Return a function pointer to the kernel entrypoint. 
+    pub unsafe fn entrypoint(&self) -> mrld::MrldKernelEntrypoint { 
+        core::mem::transmute(self.entrypt)
+    }
+
+    /// Give the physical pages for the first 'cnt' segments back to UEFI.
+    unsafe fn free_segments(&self, cnt: usize) { 
+        for seg in &self.segments[..cnt] { 
+            let (lo, hi) = page_range(seg);
+            let ptr = NonNull::new(lo as *mut u8).unwrap();
+            let _ = free_pages(ptr, ((hi - lo) as usize) / PAGE_SIZE);
+        }
+    }
+}
+
+/// Errors that can occur while validating/loading the kernel ELF.
+#[derive(Debug)]
+pub enum LoadError { 
+    /// The image is not a valid kernel ELF
+    Image(ImageError),
+    /// The ELF sections could not be parsed
+    Parse(elf::ParseError),
+    /// There's no free physical memory for an image of this size
+    NoPlacement(u64),
+    /// UEFI could not allocate the physical pages for a segment
+    Alloc { idx: usize, paddr: u64, pages: usize, status: uefi::Status },
+    /// The image has no relocations/symbols 
+    NoRelocations,
+    /// A relocation refers to an address outside of the image
+    RelocBounds(u64),
+    /// A relocation has an unsupported type (or overflowed)
+    Relocation { offset: u64, ty: u32 },
+}
+impl core::fmt::Display for LoadError { 
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result { 
+        match self { 
+            Self::Image(e) => write!(f, "{}", e),
+            Self::Parse(e) => write!(f, "couldn't parse ELF: {}", e),
+            Self::NoPlacement(size) => { 
+                write!(f, "no free physical memory for {:x} bytes", size)
+            },
+            Self::Alloc { idx, paddr, pages, status } => { 
+                write!(f, "segment {}: couldn't allocate {} pages at {:016x} ({:?})",
+                    idx, pages, paddr, status)
+            },
+            Self::NoRelocations => write!(f, "no relocations"),
+            Self::RelocBounds(addr) => { 
+                write!(f, "relocation at {:016x} is out of bounds", addr)
+            },
+            Self::Relocation { offset, ty } => { 
+                write!(f, "couldn't apply relocation type {} at {:016x}", 
+                    ty, offset)
+            },
+        }
+    }
+}
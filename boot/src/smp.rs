@@ -1,24 +1,74 @@
+//! Running code on other processors with the UEFI MP Services protocol.
+//!
+//! This only works before `ExitBootServices()`. The firmware brings up the
+//! APs for us, and we can ask it to run a function on any of them, either
+//! blocking until the function returns, or non-blocking with an event that
+//! is signaled when the function returns (see `src/experiment.rs`).
 
+use alloc::vec::Vec;
 use core::ffi::c_void;
+use uefi::boot::ScopedProtocol;
+use uefi::proto::pi::mp::MpServices;
 
-/// Execute some function on the target AP (blocking). 
-///
-/// NOTE: It seems like MpServices only exposes a *blocking* interface 
-/// for running code on other APs. 
-pub fn smp_call(cpu_num: usize, func: fn()) -> uefi::Result<()> {
-    use uefi::proto::pi::mp::MpServices;
+/// Open the MP Services protocol.
+pub fn open() -> uefi::Result<ScopedProtocol<MpServices>> {
     use uefi::boot::{
         get_handle_for_protocol,
         open_protocol_exclusive,
     };
-
     let handle = get_handle_for_protocol::<MpServices>()?;
-    let mp_services = open_protocol_exclusive::<MpServices>(handle)?;
+    open_protocol_exclusive::<MpServices>(handle)
+}
+
+/// Describes a processor (from the MP Services protocol).
+#[derive(Clone, Copy, Debug)]
+pub struct Processor {
+    /// Processor number (used with the MP Services protocol)
+    pub number: usize,
+    /// APIC ID
+    pub apic_id: u64,
+    /// Physical package number
+    pub package: u32,
+    /// Core number (within the package)
+    pub core: u32,
+    /// Thread number (within the core)
+    pub thread: u32,
+    /// This is the bootstrap processor
+    pub is_bsp: bool,
+    /// This processor is enabled
+    pub is_enabled: bool,
+    /// This processor passed its self-test
+    pub is_healthy: bool,
+}
+
+/// Return information about all processors.
+pub fn topology(mp: &MpServices) -> uefi::Result<Vec<Processor>> {
+    let count = mp.get_number_of_processors()?;
+    let mut res = Vec::with_capacity(count.total);
+    for number in 0..count.total {
+        let info = mp.get_processor_info(number)?;
+        res.push(Processor {
+            number,
+            apic_id: info.processor_id,
+            package: info.location.package,
+            core: info.location.core,
+            thread: info.location.thread,
+            is_bsp: info.is_bsp(),
+            is_enabled: info.is_enabled(),
+            is_healthy: info.is_healthy(),
+        });
+    }
+    Ok(res)
+}
+
+/// Execute some function on the target AP (blocking).
+pub fn smp_call(cpu_num: usize, func: fn()) -> uefi::Result<()> {
+    let mp_services = open()?;
     mp_services.startup_this_ap(
-        cpu_num, 
-        _do_smp_call, 
-        func as *mut c_void, 
-        None, 
+        cpu_num,
+        _do_smp_call,
+        func as *mut c_void,
+        None,
         None
     )
 }
@@ -28,5 +78,3 @@ extern "efiapi" fn _do_smp_call(content: *mut core::ffi::c_void) {
     let func: fn() = unsafe { core::mem::transmute(content) };
     func();
 }
-
-
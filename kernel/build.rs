@@ -1,58 +1,47 @@
 
-
-use std::process::Command;
 use std::env;
-use std::path::{Path, PathBuf};
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Return an identifier for this build.
+///
+/// This is the current git revision (with `-dirty` when the tree has
+/// uncommitted changes), followed by the build time in seconds since the
+/// Unix epoch.
+fn build_id(root: &Path) -> String {
+    let rev = Command::new("git")
+        .args(["describe", "--always", "--dirty", "--abbrev=12"])
+        .current_dir(root)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|rev| rev.trim().to_string())
+        .unwrap_or("unknown".to_string());
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    format!("{}-{}", rev, time)
+}
 
 fn main() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap();
 
-    let mut root = Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap();
-    println!("{:?}", root);
-    let out_dir = root.join("target/mrld-kernel/");
-    let input = root.join("kernel/src/trampoline.S");
-    let ld = root.join("trampoline.ld");
-
-    let obj_out = root.join("target/mrld-kernel/trampoline.o");
-    let elf_out = root.join("target/mrld-kernel/trampoline.elf");
-    let bin_out = root.join("target/mrld-kernel/trampoline.bin");
-
-    let mut cmd = Command::new("as")
-        .arg(&input)
-        .arg("-o").arg(&obj_out)
-        .status().unwrap();
-    if let Some(code) = cmd.code() { 
-        if code != 0 { panic!("failed to assemble trampoline?"); }
-    }
-
-    let mut cmd = Command::new("ld")
-        .arg("-o").arg(&elf_out)
-        .arg("-T").arg(&ld)
-        //.arg("-b").arg("elf32-i386")
-        .arg(&obj_out)
-        .status().unwrap();
-    if let Some(code) = cmd.code() { 
-        if code != 0 { panic!("failed to link trampoline?"); }
-    }
-
-
-    let cmd = Command::new("objcopy")
-        .arg("-O").arg("binary")
-        .arg("-j").arg(".text")
-        .arg("-j").arg(".data")
-        .arg("--pad-to").arg("0x9000")
-        .arg(&elf_out)
-        .arg(&bin_out)
-        .status().unwrap();
-    if let Some(code) = cmd.code() { 
-        if code != 0 { panic!("failed to copy trampoline?"); }
+    // Keep relocations in the kernel ELF so the bootloader can apply them
+    // after moving the kernel to a random virtual address
+    if env::var("CARGO_FEATURE_KASLR").is_ok() {
+        println!("cargo:rustc-link-arg=--emit-relocs");
     }
 
-    // Force rebuild when linkerscripts change
-    println!("cargo:rerun-if-changed=mrld-kernel.ld");
-    println!("cargo:rerun-if-changed=trampoline.ld");
+    // Embed a build ID in the kernel (see `BUILD_ID` in `src/main.rs`)
+    println!("cargo:rustc-env=MRLD_BUILD_ID={}", build_id(root));
 
-    // Force rebuild if the trampoline changes
-    println!("cargo:rerun-if-changed=kernel/src/trampoline.S");
+    // Force rebuild when the linkerscript changes
+    println!("cargo:rerun-if-changed=../mrld-kernel.ld");
 
+    // Generate a new build ID when the sources or the git revision change
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=../mrld/src");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/index");
 
 }
@@ -111,6 +111,17 @@ impl MrldAcpiManager {
         self.maybe_guest
     }
 
+    /// Return the local APIC IDs of all enabled APs (from the MADT). 
+    pub fn application_processors(&self) -> Vec<u32> { 
+        let Some(info) = &self.platform.processor_info else { 
+            return Vec::new();
+        };
+        info.application_processors.iter()
+            .filter(|p| p.state != ProcessorState::Disabled)
+            .map(|p| p.local_apic_id)
+            .collect()
+    }
+
     unsafe fn get_fadt(&self) -> PhysicalMapping<MrldAcpiHandler, Fadt> { 
         self.platform.tables.find_table::<Fadt>().unwrap()
     }
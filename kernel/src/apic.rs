@@ -5,7 +5,34 @@ use mrld::x86::{
     cpuid,
 };
 use mrld::mmio::*;
+use core::sync::atomic::{ AtomicBool, Ordering };
 use crate::println;
+use crate::pit;
+
+/// Set when the bootstrap core is using x2APIC mode (APs follow it).
+static X2APIC: AtomicBool = AtomicBool::new(false);
+
+/// Local APIC registers (by offset into the xAPIC MMIO region).
+///
+/// In x2APIC mode, the same registers are MSRs starting at 
+/// [`Msr::X2APIC_BASE`] (one for every 16 bytes of MMIO space). 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum LapicReg { 
+    Id                  = 0x20,
+    Version             = 0x30,
+    TaskPriority        = 0x80,
+    EndOfInterrupt      = 0xb0,
+    SpuriousIntrVector  = 0xf0,
+}
+impl LapicReg { 
+    pub const fn mmio_offset(self) -> usize { 
+        self as usize
+    }
+    pub const fn msr(self) -> u32 { 
+        Msr::X2APIC_BASE + (self as u32 >> 4)
+    }
+}
 
 
 pub struct ApicMmio(pub MmioPtr<u32>);
@@ -51,15 +78,51 @@ impl ApicMmio {
     pub unsafe fn interrupt_command_hi(&self) -> MmioPtr<u32> { 
         self.0.offset_bytes(0x310)
     }
+    pub unsafe fn reg(&self, reg: LapicReg) -> MmioPtr<u32> { 
+        self.0.offset_bytes(reg.mmio_offset())
+    }
+}
 
+/// Access to the local APIC registers on this core, either with MMIO 
+/// (in xAPIC mode) or with MSRs (in x2APIC mode). 
+pub enum LapicRegs { 
+    Mmio(ApicMmio),
+    Msr,
+}
+impl LapicRegs { 
+    /// Use the interface for the current mode of this core. 
+    pub fn current() -> Self { 
+        let bar = ApicBar::from(Msr::rdmsr(Msr::APIC_BAR));
+        if bar.extd() { 
+            Self::Msr
+        } else { 
+            Self::Mmio(ApicMmio::new())
+        }
+    }
 
+    pub unsafe fn read(&self, reg: LapicReg) -> u32 { 
+        match self { 
+            Self::Mmio(mmio) => mmio.reg(reg).read(),
+            Self::Msr => Msr::rdmsr(reg.msr()) as u32,
+        }
+    }
 
+    pub unsafe fn write(&self, reg: LapicReg, val: u32) { 
+        match self { 
+            Self::Mmio(mmio) => mmio.reg(reg).write(val),
+            Self::Msr => Msr::wrmsr(reg.msr(), val as u64),
+        }
+    }
 }
 
 /// Helper for interactions with the local APIC. 
 pub struct Lapic; 
 impl Lapic { 
-    pub unsafe fn init() {
+    /// Initialize the local APIC on the bootstrap core. 
+    ///
+    /// If firmware didn't already enable x2APIC mode, it's only enabled when
+    /// 'want_x2apic' is set (and the processor supports it). 
+    pub unsafe fn init(want_x2apic: bool) {
         let bar = ApicBar::from(Msr::rdmsr(Msr::APIC_BAR));
         println!("[*] APIC BAR at {:016x}", bar.base_address());
         if bar.bsc() { 
@@ -71,7 +134,62 @@ impl Lapic {
             panic!("expected APIC to be enabled");
         }
         if bar.extd() {
-            println!("[*] X2APIC mode enabled");
+            println!("[*] X2APIC mode enabled (by firmware)");
+        } 
+        else if want_x2apic { 
+            if Self::enable_x2apic() { 
+                println!("[*] X2APIC mode enabled");
+            } else { 
+                println!("[!] X2APIC isn't supported, using xAPIC mode");
+            }
+        }
+        X2APIC.store(Self::is_x2apic(), Ordering::Release);
+        println!("[*] Local APIC ID {:#x}", Self::id());
+    }
+
+    /// Initialize the local APIC on an AP (using the same mode as the 
+    /// bootstrap core). 
+    pub unsafe fn init_ap() { 
+        if X2APIC.load(Ordering::Acquire) && !Self::is_x2apic() { 
+            if !Self::enable_x2apic() { 
+                panic!("AP doesn't support x2APIC mode?");
+            }
+        }
+    }
+
+    /// Returns 'true' if the processor supports x2APIC mode. 
+    pub fn x2apic_supported() -> bool { 
+        cpuid(1, 0).ecx & (1 << 21) != 0
+    }
+
+    /// Returns 'true' if this core is in x2APIC mode. 
+    pub fn is_x2apic() -> bool { 
+        ApicBar::from(Msr::rdmsr(Msr::APIC_BAR)).extd()
+    }
+
+    /// Switch this core from xAPIC mode into x2APIC mode. 
+    /// Returns `false` if the processor doesn't support x2APIC mode. 
+    ///
+    /// NOTE: The only way back into xAPIC mode is to disable the local APIC 
+    /// (or reset the core). 
+    pub unsafe fn enable_x2apic() -> bool { 
+        if !Self::x2apic_supported() { 
+            return false;
+        }
+        let bar = ApicBar::from(Msr::rdmsr(Msr::APIC_BAR))
+            .with_ae(true)
+            .with_extd(true);
+        Msr::wrmsr(Msr::APIC_BAR, u64::from(bar));
+        true
+    }
+
+    /// Return the local APIC ID of this core. 
+    pub unsafe fn id() -> u32 { 
+        let regs = LapicRegs::current();
+        let id = regs.read(LapicReg::Id);
+        match regs { 
+            LapicRegs::Mmio(_) => id >> 24,
+            LapicRegs::Msr => id,
         }
     }
 
@@ -83,57 +201,126 @@ impl Lapic {
         mmio
     }
 
-    // The 8-bit vector 
-    //
-    // When receiving SIPI, the entrypoint is a 20-bit physical address 
-    // where the high 8 bits are the vector. 
-    // 0x3_c000
-    pub unsafe fn send_sipi(dest: usize) {
-        const TRAMPOLINE_PHYS: usize = 0x0000_8000;
-
+    /// Send an INIT IPI to all other cores. 
+    ///
+    /// This puts them back into the wait-for-SIPI state, which is the only 
+    /// way to be sure that they aren't running anything. 
+    pub unsafe fn send_init_others() { 
         let init_cmd = mrld::x86::apic::IntrCommand::new()
             .with_l(true)
-            .with_tgm(true)
-            .with_des(dest as _)
+            .with_dsh(Destination::AllExcl as _)
             .with_mt(MessageType::Init as _);
+        Self::send_ipi(init_cmd);
+    }
 
-        let startup_cmd = mrld::x86::apic::IntrCommand::new()
-            .with_vec((TRAMPOLINE_PHYS >> 12) as _)
-            .with_mt(MessageType::Startup as _)
-            .with_des(dest as _)
-            .with_l(true);
-
-        let mut mmio = ApicMmio::new();
-        let startup_cmd = u64::from_le_bytes(startup_cmd.into_bytes());
-        let init_cmd = u64::from_le_bytes(init_cmd.into_bytes());
-
-        mmio.interrupt_command_hi().write(
-            ((init_cmd & 0xffff_ffff_0000_0000) >> 32) as _
-        );
-        mmio.interrupt_command_lo().write(
-            ((init_cmd & 0x0000_0000_ffff_ffff)) as _
-        );
-        mmio.interrupt_command_hi().write(
-            ((init_cmd & 0xffff_ffff_0000_0000) >> 32) as _
-        );
-        mmio.interrupt_command_lo().write(
-            ((init_cmd & 0x0000_0000_ffff_ffff)) as _
-        );
+    /// Write an interrupt command, and wait for the local APIC to deliver
+    /// it. Returns `false` if the delivery status never goes idle, or if 
+    /// the destination can't be used in xAPIC mode. 
+    pub unsafe fn send_ipi(cmd: IntrCommand) -> bool { 
+        let mmio = match LapicRegs::current() { 
+            LapicRegs::Mmio(mmio) => mmio,
 
+            // NOTE: In x2APIC mode, the ICR is a single MSR, and there's no 
+            // delivery status. Writes to it aren't serializing, so make sure
+            // that our stores are visible to the target first. 
+            LapicRegs::Msr => { 
+                core::arch::asm!("mfence", "lfence", options(nostack));
+                Msr::wrmsr(Msr::X2APIC_ICR, u64::from(cmd));
+                return true;
+            },
+        };
 
+        // In xAPIC mode, the destination is only 8 bits
+        if cmd.des() > 0xff { 
+            return false;
+        }
+        let cmd = u64::from(cmd);
 
+        // NOTE: Writing the low half sends the IPI
         mmio.interrupt_command_hi().write(
-            ((startup_cmd & 0xffff_ffff_0000_0000) >> 32) as _
+            (((cmd & 0xffff_ffff_0000_0000) >> 32) << 24) as _
         );
         mmio.interrupt_command_lo().write(
-            ((startup_cmd & 0x0000_0000_ffff_ffff)) as _
+            ((cmd & 0x0000_0000_ffff_ffff)) as _
         );
 
+        // NOTE: This doesn't use the PIT, since other cores may be sending
+        // IPIs at the same time (see `src/smpcall.rs`)
+        for _ in 0..Self::IPI_DELIVERY_POLLS { 
+            let lo = mmio.interrupt_command_lo().read() as u64;
+            if !IntrCommand::from(lo).ds() { 
+                return true;
+            }
+            mrld::x86::pause();
+        }
+        false
+    }
 
+    /// Number of times to poll the delivery status
+    const IPI_DELIVERY_POLLS: usize = 1_000_000;
 
+    /// Send a fixed interrupt with the given vector to the core with the 
+    /// given APIC ID. 
+    pub unsafe fn send_fixed(dest: u32, vector: u8) -> bool { 
+        let cmd = mrld::x86::apic::IntrCommand::new()
+            .with_vec(vector)
+            .with_mt(MessageType::Fixed as _)
+            .with_des(dest as _)
+            .with_l(true);
+        Self::send_ipi(cmd)
     }
 
+    /// Software-enable the local APIC on this core (so that it accepts 
+    /// fixed interrupts), using 'spurious_vector' for spurious interrupts. 
+    pub unsafe fn enable(spurious_vector: u8) { 
+        const SVR_APIC_ENABLE: u32 = 1 << 8;
+        LapicRegs::current().write(LapicReg::SpuriousIntrVector,
+            SVR_APIC_ENABLE | spurious_vector as u32
+        );
+    }
 
+    /// Signal the end of an interrupt to the local APIC. 
+    pub unsafe fn eoi() { 
+        LapicRegs::current().write(LapicReg::EndOfInterrupt, 0);
+    }
 
+    /// Send an INIT IPI to the core with the given APIC ID. 
+    pub unsafe fn send_init(dest: u32) -> bool { 
+        let init_cmd = mrld::x86::apic::IntrCommand::new()
+            .with_l(true)
+            .with_tgm(true)
+            .with_des(dest as _)
+            .with_mt(MessageType::Init as _);
+        Self::send_ipi(init_cmd)
+    }
 
+    /// Send a startup IPI to the core with the given APIC ID. 
+    ///
+    /// When receiving SIPI, the entrypoint is a 20-bit physical address 
+    /// where the high 8 bits are the vector. 
+    pub unsafe fn send_startup(dest: u32, entry: u64) -> bool { 
+        assert!(entry & 0xfff == 0 && entry < 0x10_0000);
+        let startup_cmd = mrld::x86::apic::IntrCommand::new()
+            .with_vec((entry >> 12) as _)
+            .with_mt(MessageType::Startup as _)
+            .with_des(dest as _)
+            .with_l(true);
+        Self::send_ipi(startup_cmd)
+    }
+
+    /// Start an AP with the INIT-SIPI-SIPI sequence. 
+    /// Returns `false` if any of the IPIs weren't delivered. 
+    pub unsafe fn start_ap(dest: u32, entry: u64) -> bool { 
+        if !Self::send_init(dest) { 
+            return false;
+        }
+        pit::udelay(10_000);
+        for _ in 0..2 { 
+            if !Self::send_startup(dest, entry) { 
+                return false;
+            }
+            pit::udelay(200);
+        }
+        true
+    }
 }
@@ -0,0 +1,255 @@
+//! Stack unwinding and symbolization.
+//!
+//! The kernel is built with frame pointers (see `mrld-kernel.json`), so
+//! every stack frame starts with the caller's frame pointer, followed by
+//! the return address:
+//!
+//! ```text
+//! [rbp + 8] : return address
+//! [rbp + 0] : caller's rbp
+//! ```
+//!
+//! `_start` clears RBP before calling into Rust code, which terminates the
+//! chain of frames.
+//!
+//! The bootloader copies the ELF symbol table (`.symtab` and `.strtab`)
+//! into memory and passes it along in [`MrldBootArgs`], which lets us print
+//! function names instead of running `addr2line` by hand after a crash.
+//!
+//! NOTE: This assumes the symbol table is identity-mapped.
+
+use mrld::{ MrldBootArgs, MrldKernelSymbols };
+use crate::println;
+use core::fmt;
+
+/// Maximum number of frames printed in a backtrace
+pub const MAX_FRAMES: usize = 32;
+
+/// The kernel symbol table.
+///
+/// NOTE: This is a [`spin::Once`] instead of a mutex because we need to read
+/// it from the panic handler, which should never have to wait on a lock.
+static SYMBOLS: spin::Once<SymbolTable> = spin::Once::new();
+
+/// An 'Elf64_Sym' entry.
+#[repr(C)]
+struct Elf64Sym {
+    st_name: u32,
+    st_info: u8,
+    st_other: u8,
+    st_shndx: u16,
+    st_value: u64,
+    st_size: u64,
+}
+impl Elf64Sym {
+    const STT_FUNC: u8 = 2;
+    fn is_func(&self) -> bool {
+        (self.st_info & 0xf) == Self::STT_FUNC
+    }
+}
+
+/// The kernel symbol table.
+pub struct SymbolTable {
+    syms: &'static [Elf64Sym],
+    strtab: &'static [u8],
+}
+impl SymbolTable {
+    /// Return the name of a symbol.
+    fn name(&self, sym: &Elf64Sym) -> &'static str {
+        let Some(bytes) = self.strtab.get(sym.st_name as usize..) else {
+            return "??";
+        };
+        let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        core::str::from_utf8(&bytes[..len]).unwrap_or("??")
+    }
+
+    /// Find the function containing 'addr'.
+    /// Returns the name of the function and the offset of 'addr' from the
+    /// start of the function.
+    pub fn lookup(&self, addr: u64) -> Option<(&'static str, u64)> {
+        let mut best: Option<&Elf64Sym> = None;
+        for sym in self.syms.iter().filter(|s| s.is_func() && s.st_value != 0) {
+            if addr < sym.st_value {
+                continue;
+            }
+            if sym.st_size != 0 && addr >= sym.st_value + sym.st_size {
+                continue;
+            }
+            if best.map_or(true, |b| sym.st_value > b.st_value) {
+                best = Some(sym);
+            }
+        }
+        best.map(|sym| (self.name(sym), addr - sym.st_value))
+    }
+}
+
+/// Use the symbol table passed from the bootloader (if we have one).
+pub unsafe fn init(args: &MrldBootArgs) {
+    let syms: &MrldKernelSymbols = &args.kernel_symbols;
+    if !syms.is_valid() {
+        println!("[!] No kernel symbols, backtraces won't be symbolized");
+        return;
+    }
+    SYMBOLS.call_once(|| {
+        let num_syms = syms.symtab_size as usize / size_of::<Elf64Sym>();
+        unsafe {
+            SymbolTable {
+                syms: core::slice::from_raw_parts(
+                    syms.symtab as *const Elf64Sym, num_syms
+                ),
+                strtab: core::slice::from_raw_parts(
+                    syms.strtab as *const u8, syms.strtab_size as usize
+                ),
+            }
+        }
+    });
+}
+
+/// Formats a code address as 'function+offset'.
+pub struct Symbolize {
+    addr: u64,
+    /// Set when 'addr' is a return address
+    is_return: bool,
+}
+impl Symbolize {
+    pub fn new(addr: u64) -> Self {
+        Self { addr, is_return: false }
+    }
+
+    /// Symbolize a return address.
+    ///
+    /// The return address might be the first instruction in some other
+    /// function when the call is the last instruction in the caller (ie.
+    /// when calling something that never returns), so we look up the
+    /// address immediately before it instead.
+    pub fn ret(addr: u64) -> Self {
+        Self { addr, is_return: true }
+    }
+}
+impl fmt::Display for Symbolize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let adj = if self.is_return { 1 } else { 0 };
+        let res = SYMBOLS.get()
+            .and_then(|tbl| tbl.lookup(self.addr.wrapping_sub(adj)));
+        match res {
+            Some((name, off)) => write!(f, "{}+{:#x}", Demangle(name), off + adj),
+            None => write!(f, "??"),
+        }
+    }
+}
+
+/// Formats a symbol name, demangling it if it uses the legacy Rust scheme.
+///
+/// Legacy names look like `_ZN` followed by a list of length-prefixed
+/// identifiers (the last of which is a hash), and then `E`, ie.
+/// `_ZN4core9panicking5panic17h0123456789abcdefE`.
+pub struct Demangle<'a>(pub &'a str);
+impl Demangle<'_> {
+    /// Returns 'true' if this identifier is the hash at the end of a path
+    fn is_hash(ident: &str) -> bool {
+        ident.len() == 17 && ident.starts_with('h') &&
+            ident[1..].bytes().all(|b| b.is_ascii_hexdigit())
+    }
+
+    /// Write an identifier, replacing any escape sequences.
+    fn write_ident(f: &mut fmt::Formatter, ident: &str) -> fmt::Result {
+        let mut rest = ident;
+        if rest.starts_with("_$") {
+            rest = &rest[1..];
+        }
+        while !rest.is_empty() {
+            if let Some(r) = rest.strip_prefix("..") {
+                f.write_str("::")?;
+                rest = r;
+                continue;
+            }
+            if rest.starts_with('$') {
+                if let Some(end) = rest[1..].find('$') {
+                    let s = match &rest[1..=end] {
+                        "SP" => "@", "BP" => "*", "RF" => "&",
+                        "LT" => "<", "GT" => ">", "LP" => "(", "RP" => ")",
+                        "C" => ",", "u20" => " ", "u22" => "\"", "u27" => "'",
+                        "u2b" => "+", "u3b" => ";", "u5b" => "[", "u5d" => "]",
+                        "u7b" => "{", "u7d" => "}", "u7e" => "~",
+                        _ => &rest[..=end + 1],
+                    };
+                    f.write_str(s)?;
+                    rest = &rest[end + 2..];
+                    continue;
+                }
+            }
+            let c = rest.chars().next().unwrap();
+            write!(f, "{}", c)?;
+            rest = &rest[c.len_utf8()..];
+        }
+        Ok(())
+    }
+}
+impl fmt::Display for Demangle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Some(mut rest) = self.0.strip_prefix("_ZN") else {
+            return f.write_str(self.0);
+        };
+
+        let mut first = true;
+        while !rest.starts_with('E') {
+            // Parse the length of the next identifier
+            let digits = rest.bytes().take_while(|b| b.is_ascii_digit()).count();
+            let len: Option<usize> = rest[..digits].parse().ok();
+            let ident = len.and_then(|len| rest.get(digits..digits + len));
+            let Some(ident) = ident else {
+                // This is malformed, so just print whatever is left
+                return f.write_str(rest);
+            };
+            rest = &rest[digits + ident.len()..];
+
+            if rest == "E" && Self::is_hash(ident) {
+                break;
+            }
+            if !first {
+                f.write_str("::")?;
+            }
+            Self::write_ident(f, ident)?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+/// Return the current frame pointer.
+#[inline(always)]
+pub fn read_rbp() -> u64 {
+    let rbp: u64;
+    unsafe {
+        core::arch::asm!("mov {}, rbp", out(reg) rbp);
+    }
+    rbp
+}
+
+/// Walk the chain of frame pointers starting at 'rbp' and print each
+/// return address.
+///
+/// NOTE: We give up as soon as a frame pointer looks bogus: all of our
+/// stacks live in the upper half of the address space, and the stack
+/// grows down, so the chain should always be increasing.
+pub unsafe fn print_backtrace(rbp: u64) {
+    println!("[!] Backtrace:");
+    let mut rbp = rbp;
+    for idx in 0..MAX_FRAMES {
+        if rbp == 0 || (rbp & 0x7) != 0 || rbp < 0xffff_8000_0000_0000 {
+            break;
+        }
+        let frame = rbp as *const u64;
+        let ret = frame.add(1).read();
+        if ret == 0 {
+            break;
+        }
+        println!("  #{:02} {:016x} {}", idx, ret, Symbolize::ret(ret));
+
+        let next = frame.read();
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
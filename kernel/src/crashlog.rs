@@ -0,0 +1,42 @@
+//! Persistent crash log.
+//!
+//! Everything printed with [`println!`] is also copied into the crash log
+//! region reserved by the bootloader (see [`mrld::crashlog`]). If we panic
+//! or never reach a clean shutdown, the bootloader uploads the log on the
+//! next boot.
+//!
+//! NOTE: This assumes that the region is identity-mapped.
+//!
+//! [`println!`]: crate::println
+
+use mrld::MrldBootArgs;
+use mrld::crashlog::{ CrashLog, CrashLogState, CRASHLOG_PHYS_BASE };
+use spin::Mutex;
+
+/// The crash log (if the bootloader reserved one).
+pub static CRASHLOG: Mutex<Option<CrashLog>> = Mutex::new(None);
+
+/// Start a new crash log (if the bootloader reserved the region).
+pub fn init(args: &MrldBootArgs) {
+    if args.crashlog != CRASHLOG_PHYS_BASE {
+        return;
+    }
+    let mut log = unsafe { CrashLog::new() };
+    log.init(crate::BUILD_ID);
+    *CRASHLOG.lock() = Some(log);
+}
+
+/// Append to the crash log.
+pub fn write_fmt(args: core::fmt::Arguments<'_>) {
+    use core::fmt::Write;
+    if let Some(log) = CRASHLOG.lock().as_mut() {
+        let _ = log.write_fmt(args);
+    }
+}
+
+/// Record the state of the kernel in the crash log.
+pub fn set_state(state: CrashLogState) {
+    if let Some(log) = CRASHLOG.lock().as_mut() {
+        log.set_state(state);
+    }
+}
@@ -0,0 +1,247 @@
+//! UEFI runtime services.
+//!
+//! The bootloader passes the physical address of the UEFI runtime services
+//! table (along with the UEFI memory map). Before we can use any runtime
+//! services after `ExitBootServices()`, we need to:
+//!
+//! - Map every region with the `EFI_MEMORY_RUNTIME` attribute
+//! - Call `SetVirtualAddressMap()` so that firmware can relocate itself
+//!   into our mappings
+//!
+//! Each runtime region is mapped at [`UEFI_RUNTIME_BASE`] plus its physical
+//! address. This keeps all of the regions in the same order and at the same
+//! distance from each other (some firmware quietly depends on this).
+//!
+//! NOTE: `SetVirtualAddressMap()` can only be called once. Runtime services
+//! aren't reentrant, so all calls are serialized with [`RUNTIME`].
+//!
+//! NOTE: This assumes that the UEFI memory map is identity-mapped.
+
+use mrld::MrldBootArgs;
+use mrld::paging::*;
+use spin::Mutex;
+use uefi_raw::{ guid, Guid, Status };
+use uefi_raw::time::Time;
+use uefi_raw::table::boot::{ MemoryAttribute, MemoryDescriptor };
+use uefi_raw::table::runtime::{
+    RuntimeServices, ResetType, VariableAttributes
+};
+use core::mem::MaybeUninit;
+
+use crate::println;
+use crate::mm::UEFI_RUNTIME_BASE;
+use crate::paging::PAGE_TABLE;
+
+/// Vendor GUID for variables owned by mrld.
+pub const MRLD_VENDOR_GUID: Guid = guid!("6d726c64-0000-4000-8000-6b65726e656c");
+
+/// Maximum length of a variable name (in UCS-2 characters, without the NUL)
+pub const MAX_VARIABLE_NAME_LEN: usize = 64;
+
+/// UEFI runtime services.
+pub static RUNTIME: Mutex<UefiRuntime> = {
+    Mutex::new(UefiRuntime::new_empty())
+};
+
+/// Wrapper around the UEFI runtime services table.
+pub struct UefiRuntime {
+    /// Virtual address of the runtime services table
+    /// (only valid after calling `SetVirtualAddressMap()`)
+    rt: *const RuntimeServices,
+}
+unsafe impl Send for UefiRuntime {}
+impl UefiRuntime {
+    pub const fn new_empty() -> Self {
+        Self { rt: core::ptr::null() }
+    }
+
+    /// Returns 'true' if runtime services are available.
+    pub fn is_available(&self) -> bool {
+        !self.rt.is_null()
+    }
+
+    fn rt(&self) -> Result<&RuntimeServices, Status> {
+        unsafe { self.rt.as_ref().ok_or(Status::UNSUPPORTED) }
+    }
+
+    /// Map all runtime regions and switch firmware over to our mappings.
+    pub unsafe fn init(&mut self, args: &MrldBootArgs) {
+        if args.uefi_runtime == 0 {
+            println!("[!] No UEFI runtime services?");
+            return;
+        }
+
+        let map_ptr = args.uefi_map as *mut u8;
+        let desc_sz = args.uefi_map_desc_size;
+        let num_entries = args.uefi_map_size / desc_sz;
+
+        // Map each runtime region, and record the new virtual address in
+        // the memory map that we're going to give back to firmware.
+        {
+            let mut pt = PAGE_TABLE.lock();
+            let pml4 = PageTable::<PML4>::from_cr3_mut();
+            for idx in 0..num_entries {
+                let ptr = map_ptr.add(idx * desc_sz);
+                let desc = &mut *(ptr as *mut MemoryDescriptor);
+                if !desc.att.contains(MemoryAttribute::RUNTIME) {
+                    continue;
+                }
+
+                // Anything that isn't write-back cacheable is probably MMIO
+                let flags = if desc.att.contains(MemoryAttribute::WRITE_BACK) {
+                    PTFlag::empty()
+                } else {
+                    PTFlag::PCD | PTFlag::PWT
+                };
+
+                desc.virt_start = UEFI_RUNTIME_BASE + desc.phys_start;
+                for pg in 0..desc.page_count {
+                    let off = pg * u64::from(PageSize::Size4KiB);
+                    pt.map_page_flags(pml4,
+                        desc.virt_start + off,
+                        desc.phys_start + off,
+                        PageSize::Size4KiB,
+                        flags
+                    );
+                }
+                println!("  UEFI runtime {:016x}:{:016x} => {:016x} ({:?})",
+                    desc.phys_start,
+                    desc.phys_start + (desc.page_count * 0x1000),
+                    desc.virt_start, desc.ty,
+                );
+            }
+
+            // Flush the TLB
+            mrld::x86::CR3::write(mrld::x86::CR3::read());
+        }
+
+        // The table itself lives in runtime memory, so we can still use the
+        // physical address before switching.
+        let rt_phys = args.uefi_runtime as *const RuntimeServices;
+        let status = ((*rt_phys).set_virtual_address_map)(
+            args.uefi_map_size,
+            desc_sz,
+            args.uefi_map_desc_version,
+            map_ptr as *mut MemoryDescriptor,
+        );
+        if status != Status::SUCCESS {
+            println!("[!] SetVirtualAddressMap() failed: {:?}", status);
+            return;
+        }
+        self.rt = (UEFI_RUNTIME_BASE + args.uefi_runtime) as *const _;
+        println!("[*] UEFI runtime services at {:016x}", self.rt as u64);
+    }
+
+    /// Read the current time from the platform's real-time clock.
+    pub fn get_time(&mut self) -> Result<Time, Status> {
+        let rt = self.rt()?;
+        let mut time = MaybeUninit::<Time>::uninit();
+        let status = unsafe {
+            (rt.get_time)(time.as_mut_ptr(), core::ptr::null_mut())
+        };
+        if status != Status::SUCCESS {
+            return Err(status);
+        }
+        Ok(unsafe { time.assume_init() })
+    }
+
+    /// Read a variable into 'buf'.
+    /// Returns the size of the variable and its attributes.
+    pub fn get_variable(&mut self, name: &str, guid: &Guid, buf: &mut [u8])
+        -> Result<(usize, VariableAttributes), Status>
+    {
+        let rt = self.rt()?;
+        let name = Ucs2Name::new(name).ok_or(Status::INVALID_PARAMETER)?;
+        let mut attrs = VariableAttributes::empty();
+        let mut size = buf.len();
+        let status = unsafe {
+            (rt.get_variable)(
+                name.as_ptr(), guid, &mut attrs, &mut size, buf.as_mut_ptr()
+            )
+        };
+        if status != Status::SUCCESS {
+            return Err(status);
+        }
+        Ok((size, attrs))
+    }
+
+    /// Create or update a variable.
+    /// Writing an empty variable deletes it.
+    pub fn set_variable(&mut self, name: &str, guid: &Guid,
+        attrs: VariableAttributes, data: &[u8]) -> Result<(), Status>
+    {
+        let rt = self.rt()?;
+        let name = Ucs2Name::new(name).ok_or(Status::INVALID_PARAMETER)?;
+        let status = unsafe {
+            (rt.set_variable)(
+                name.as_ptr(), guid, attrs, data.len(), data.as_ptr()
+            )
+        };
+        if status != Status::SUCCESS {
+            return Err(status);
+        }
+        Ok(())
+    }
+
+    /// Reset (or shut down) the platform.
+    /// Returns an error only if runtime services are unavailable.
+    pub fn reset(&mut self, ty: ResetType) -> Status {
+        match self.rt() {
+            Ok(rt) => unsafe {
+                (rt.reset_system)(ty, Status::SUCCESS, 0, core::ptr::null())
+            },
+            Err(e) => e,
+        }
+    }
+}
+
+/// A NUL-terminated UCS-2 variable name.
+struct Ucs2Name {
+    buf: [u16; MAX_VARIABLE_NAME_LEN + 1],
+}
+impl Ucs2Name {
+    /// Convert from a string. Returns [`None`] if the name is too long or
+    /// can't be represented with UCS-2.
+    fn new(name: &str) -> Option<Self> {
+        let mut buf = [0u16; MAX_VARIABLE_NAME_LEN + 1];
+        for (idx, c) in name.chars().enumerate() {
+            if idx >= MAX_VARIABLE_NAME_LEN || c == '\0' {
+                return None;
+            }
+            buf[idx] = u16::try_from(u32::from(c)).ok()?;
+        }
+        Some(Self { buf })
+    }
+    fn as_ptr(&self) -> *const u16 {
+        self.buf.as_ptr()
+    }
+}
+
+/// Returns 'true' if runtime services are available.
+pub fn is_available() -> bool {
+    RUNTIME.lock().is_available()
+}
+
+/// Read the current time from the platform's real-time clock.
+pub fn get_time() -> Result<Time, Status> {
+    RUNTIME.lock().get_time()
+}
+
+/// Read a variable into 'buf'.
+pub fn get_variable(name: &str, guid: &Guid, buf: &mut [u8])
+    -> Result<(usize, VariableAttributes), Status>
+{
+    RUNTIME.lock().get_variable(name, guid, buf)
+}
+
+/// Create or update a variable.
+pub fn set_variable(name: &str, guid: &Guid, attrs: VariableAttributes,
+    data: &[u8]) -> Result<(), Status>
+{
+    RUNTIME.lock().set_variable(name, guid, attrs, data)
+}
+
+/// Reset (or shut down) the platform.
+pub fn reset(ty: ResetType) -> Status {
+    RUNTIME.lock().reset(ty)
+}
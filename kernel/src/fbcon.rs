@@ -0,0 +1,189 @@
+//! Text console drawn into a linear framebuffer.
+//!
+//! The bootloader passes a [`MrldFramebuffer`] describing the video mode
+//! that UEFI left us in. We render glyphs from the built-in font (see
+//! `src/font.rs`) directly into the framebuffer, and scroll the contents
+//! up by one line of text when we run out of space.
+//!
+//! This is used as a second sink for [`crate::println`] (in addition to the
+//! serial port), which is useful on machines without a serial header.
+//!
+//! NOTE: This assumes that the framebuffer is identity-mapped.
+
+use mrld::{ MrldFramebuffer, MrldPixelFormat };
+use crate::font::{ self, GLYPH_WIDTH, GLYPH_HEIGHT };
+use spin;
+
+/// The framebuffer console.
+pub static FBCON: spin::Mutex<FramebufferConsole> = {
+    spin::Mutex::new(FramebufferConsole::new_empty())
+};
+
+/// State for a text console drawn into a framebuffer.
+pub struct FramebufferConsole {
+    /// The framebuffer we're drawing into
+    fb: MrldFramebuffer,
+    /// Foreground color (encoded for the pixel format)
+    fg: u32,
+    /// Background color (encoded for the pixel format)
+    bg: u32,
+    /// Number of text columns
+    cols: usize,
+    /// Number of text rows
+    rows: usize,
+    /// Current column
+    col: usize,
+    /// Current row
+    row: usize,
+}
+impl FramebufferConsole {
+    pub const fn new_empty() -> Self {
+        Self {
+            fb: MrldFramebuffer::new_empty(),
+            fg: 0,
+            bg: 0,
+            cols: 0,
+            rows: 0,
+            col: 0,
+            row: 0,
+        }
+    }
+
+    /// Returns 'true' if this console has been initialized.
+    pub fn enabled(&self) -> bool {
+        self.fb.is_valid()
+    }
+
+    /// Initialize the console with a framebuffer passed from the bootloader.
+    pub unsafe fn init(&mut self, fb: &MrldFramebuffer) {
+        if !fb.is_valid() {
+            return;
+        }
+        self.fb = *fb;
+        self.cols = fb.width / GLYPH_WIDTH;
+        self.rows = fb.height / GLYPH_HEIGHT;
+        self.col = 0;
+        self.row = 0;
+        self.fg = self.encode(0xc0, 0xc0, 0xc0);
+        self.bg = self.encode(0x00, 0x00, 0x00);
+        self.clear();
+    }
+
+    /// Encode a color for the pixel format used by this framebuffer.
+    fn encode(&self, r: u8, g: u8, b: u8) -> u32 {
+        match self.fb.format {
+            MrldPixelFormat::Rgb => {
+                (r as u32) | (g as u32) << 8 | (b as u32) << 16
+            },
+            MrldPixelFormat::Bgr => {
+                (b as u32) | (g as u32) << 8 | (r as u32) << 16
+            },
+            MrldPixelFormat::Bitmask => {
+                Self::scale(r, self.fb.red_mask) |
+                Self::scale(g, self.fb.green_mask) |
+                Self::scale(b, self.fb.blue_mask)
+            },
+            MrldPixelFormat::None => 0,
+        }
+    }
+
+    /// Scale an 8-bit channel value into the bits selected by 'mask'.
+    fn scale(val: u8, mask: u32) -> u32 {
+        if mask == 0 {
+            return 0;
+        }
+        let shift = mask.trailing_zeros();
+        let bits = (mask >> shift).count_ones();
+        let val = if bits >= 8 {
+            (val as u32) << (bits - 8)
+        } else {
+            (val as u32) >> (8 - bits)
+        };
+        (val << shift) & mask
+    }
+
+    fn ptr(&self) -> *mut u32 {
+        self.fb.base as *mut u32
+    }
+
+    /// Fill a number of scanlines (starting at 'y') with the background color.
+    unsafe fn fill_lines(&mut self, y: usize, cnt: usize) {
+        for line in y..(y + cnt) {
+            let ptr = self.ptr().add(line * self.fb.stride);
+            for x in 0..self.fb.width {
+                ptr.add(x).write_volatile(self.bg);
+            }
+        }
+    }
+
+    /// Clear the screen and move the cursor to the top-left corner.
+    pub unsafe fn clear(&mut self) {
+        self.fill_lines(0, self.fb.height);
+        self.col = 0;
+        self.row = 0;
+    }
+
+    /// Move the contents of the screen up by one row of text.
+    unsafe fn scroll(&mut self) {
+        let line_pixels = self.fb.stride * GLYPH_HEIGHT;
+        let total_pixels = self.fb.stride * GLYPH_HEIGHT * self.rows;
+        let src = self.ptr().add(line_pixels);
+        self.ptr().copy_from(src, total_pixels - line_pixels);
+        self.fill_lines((self.rows - 1) * GLYPH_HEIGHT, GLYPH_HEIGHT);
+    }
+
+    /// Draw a single glyph at the given text column and row.
+    unsafe fn draw_glyph(&mut self, c: u8, col: usize, row: usize) {
+        let bitmap = font::glyph(c);
+        let x0 = col * GLYPH_WIDTH;
+        let y0 = row * GLYPH_HEIGHT;
+        for (dy, bits) in bitmap.iter().enumerate() {
+            let ptr = self.ptr().add((y0 + dy) * self.fb.stride + x0);
+            for dx in 0..GLYPH_WIDTH {
+                let px = if bits & (0x80 >> dx) != 0 { self.fg } else { self.bg };
+                ptr.add(dx).write_volatile(px);
+            }
+        }
+    }
+
+    fn newline(&mut self) {
+        self.col = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            unsafe { self.scroll(); }
+        }
+    }
+
+    /// Write a byte to the console.
+    pub fn write_byte(&mut self, byte: u8) {
+        if !self.enabled() {
+            return;
+        }
+        match byte {
+            b'\r' => self.col = 0,
+            b'\n' => self.newline(),
+            b'\t' => {
+                for _ in 0..(4 - (self.col % 4)) {
+                    self.write_byte(b' ');
+                }
+            },
+            _ => {
+                if self.col >= self.cols {
+                    self.newline();
+                }
+                unsafe { self.draw_glyph(byte, self.col, self.row); }
+                self.col += 1;
+            },
+        }
+    }
+}
+
+impl core::fmt::Write for FramebufferConsole {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
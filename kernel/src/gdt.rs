@@ -0,0 +1,109 @@
+//! Per-core GDT and TSS.
+//!
+//! `_start` (see `src/start.rs`) loads a static GDT with only code and data
+//! descriptors, and APs start on a provisional GDT from the trampoline.
+//! Once the heap is available, each core builds its own GDT with a
+//! descriptor for its own TSS, which holds the interrupt stack table (IST).
+//!
+//! Exceptions that can happen while the current stack is unusable (#DF, NMI,
+//! and #MC) switch to a stack from the IST (see `src/interrupt.rs`).
+
+use alloc::alloc::alloc;
+use core::alloc::Layout;
+use mrld::x86::dtr::{ DescriptorTableRegister, GDTR };
+use mrld::x86::gdt::{ GlobalDescriptorTable, KERNEL_CODE_SEL, KERNEL_DATA_SEL };
+use mrld::x86::segment::{ SegmentSelector, PrivilegeLevel };
+use mrld::x86::tss::TaskStateSegment;
+
+use crate::start::{ KERNEL_TEXT_DESC, KERNEL_DATA_DESC };
+
+/// Selector for the TSS descriptor in each per-core GDT
+pub const KERNEL_TSS_SEL: SegmentSelector =
+    SegmentSelector::new(3, false, PrivilegeLevel::Ring0);
+
+/// IST index for double faults
+pub const IST_DOUBLE_FAULT: u8 = 1;
+/// IST index for non-maskable interrupts
+pub const IST_NMI: u8 = 2;
+/// IST index for machine checks
+pub const IST_MACHINE_CHECK: u8 = 3;
+
+/// Number of IST stacks for each core
+const NUM_IST_STACKS: usize = 3;
+
+/// Size of each IST stack
+const IST_STACK_SIZE: usize = 0x4000;
+
+/// Descriptor tables for a single core.
+///
+/// The GDT has a null descriptor, kernel code and data descriptors (like
+/// the static GDT in `src/start.rs`), and a 128-bit TSS descriptor.
+#[repr(C, align(64))]
+pub struct CoreTables {
+    gdt: GlobalDescriptorTable<5>,
+    gdtr: DescriptorTableRegister,
+    tss: TaskStateSegment,
+}
+
+impl CoreTables {
+    /// Build and load a GDT and TSS for this core.
+    ///
+    /// This never returns the memory (it's used until the core is reset).
+    pub unsafe fn init() -> &'static Self {
+        let ptr = alloc(Layout::new::<Self>()) as *mut Self;
+        ptr.write(Self {
+            gdt: GlobalDescriptorTable::new_zeroed(),
+            gdtr: DescriptorTableRegister::new(0, core::ptr::null()),
+            tss: TaskStateSegment::new(),
+        });
+        let tables = &mut *ptr;
+
+        for idx in 1..=NUM_IST_STACKS {
+            let stack_lo = alloc(
+                Layout::from_size_align(IST_STACK_SIZE, 0x1000).unwrap()
+            );
+            stack_lo.write_bytes(0, IST_STACK_SIZE);
+            tables.tss.set_ist(idx, stack_lo.add(IST_STACK_SIZE) as u64);
+        }
+
+        tables.gdt = GlobalDescriptorTable::new_zeroed()
+            .push_null_desc()
+            .push_user_desc(KERNEL_TEXT_DESC)
+            .push_user_desc(KERNEL_DATA_DESC)
+            .push_sys_desc(tables.tss.descriptor().as_u64());
+        tables.gdtr = DescriptorTableRegister::new(
+            tables.gdt.limit(), tables.gdt.as_ptr()
+        );
+
+        GDTR::write(&tables.gdtr);
+        Self::reload_segments();
+        core::arch::asm!("ltr {0:x}", in(reg) KERNEL_TSS_SEL.as_u16(),
+            options(nostack, preserves_flags)
+        );
+        tables
+    }
+
+    /// Reload the segment registers after loading a new GDT.
+    ///
+    /// NOTE: This leaves GS alone, since loading it would also clobber
+    /// `GS_BASE` (which points to local storage, see `src/tls.rs`).
+    unsafe fn reload_segments() {
+        core::arch::asm!(r#"
+            mov ds, {data:x}
+            mov es, {data:x}
+            mov fs, {data:x}
+            mov ss, {data:x}
+
+            // Switch out code segment (using the far return `retfq`)
+            push {code}
+            lea {tmp}, [2f]
+            push {tmp}
+            retfq
+        2:
+        "#,
+        data = in(reg) KERNEL_DATA_SEL.as_u16(),
+        code = in(reg) KERNEL_CODE_SEL.as_u16() as u64,
+        tmp = out(reg) _,
+        );
+    }
+}
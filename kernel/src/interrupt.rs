@@ -3,7 +3,9 @@ use mrld::x86::idt::*;
 use mrld::x86::dtr::*;
 use mrld::x86::cr::*;
 use crate::util;
+use crate::gdt;
 use crate::println;
+use crate::backtrace::Symbolize;
 
 use spin;
 
@@ -16,7 +18,7 @@ pub struct IdtManager;
 impl IdtManager { 
     /// Initialize the IDT and update the IDTR
     pub unsafe fn init() {
-        let idt_ptr = { 
+        { 
             let mut idt = IDT.lock();
 
             idt.de = IdtEntry::new_interrupt(de_handler as _);
@@ -43,11 +45,37 @@ impl IdtManager {
             idt.hv = IdtEntry::new_interrupt(hv_handler as _);
             idt.vc = IdtEntry::new_interrupt(vc_handler as _);
             idt.sx = IdtEntry::new_interrupt(sx_handler as _);
-            idt.as_ptr()
-        };
+        }
+        Self::load();
+    }
 
-        IDTR::write(&DescriptorTableRegister::new(512, idt_ptr as _));
+    /// Load the IDT on this core. 
+    ///
+    /// The IDT is shared by all cores. APs load it after they've loaded 
+    /// their own GDT and TSS (see `src/smp.rs`). 
+    pub unsafe fn load() { 
+        let idt_ptr = IDT.lock().as_ptr();
+        let limit = (core::mem::size_of::<Idt>() - 1) as u16;
+        IDTR::write(&DescriptorTableRegister::new(limit, idt_ptr as _));
+    }
+
+    /// Install a handler for one of the vectors after the first 32. 
+    pub unsafe fn set_handler(vector: u8, func: InterruptHandlerFn) { 
+        assert!(vector >= 32, "Vector {} is reserved for exceptions", vector);
+        let mut idt = IDT.lock();
+        idt.usr[vector as usize - 32] = IdtEntry::new_interrupt(func);
+    }
 
+    /// Use separate stacks (from the IST) for double faults, NMIs, and 
+    /// machine checks. 
+    ///
+    /// This must only be called after the bootstrap core has loaded its TSS,
+    /// and any other core must load a TSS before loading the IDT.
+    pub unsafe fn enable_ist() { 
+        let mut idt = IDT.lock();
+        idt.df = idt.df.with_ist(gdt::IST_DOUBLE_FAULT);
+        idt.nmi = idt.nmi.with_ist(gdt::IST_NMI);
+        idt.mc = idt.mc.with_ist(gdt::IST_MACHINE_CHECK);
     }
 }
 
@@ -56,7 +84,10 @@ fn generic_handler_panic(s: &'static str, f: &InterruptStackFrame, err: Option<u
     println!("err={:016x?}", err);
     println!("CR2={:016x}", unsafe { CR2::read() });
     println!("{:x?}", f);
-    panic!("panic for #{} at {:016x}!", s, f.rip);
+    let apic_id = mrld::x86::cpuid(0xb, 0).edx;
+    panic!("panic for #{} on core {} at {:016x} ({})!", 
+        s, apic_id, f.rip, Symbolize::new(f.rip)
+    );
 }
 
 macro_rules! decl_generic_handler {
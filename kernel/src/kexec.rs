@@ -0,0 +1,339 @@
+//! Loading a new kernel without rebooting.
+//!
+//! Rebooting through firmware and PXE for every change is slow. Instead, a
+//! running kernel can receive a new kernel ELF (see [`receive_serial`]),
+//! load it into physical memory (see [`load`]), and jump into it (see
+//! [`exec`]) with a freshly-built [`MrldBootArgs`].
+//!
+//! Handoff
+//! =======
+//!
+//! The new kernel expects to be entered in the same state that the
+//! bootloader leaves us in, so we build:
+//!
+//! - A "handoff" region (two 2MiB pages, [`MrldMemoryKind::BootArgs`])
+//!   containing the new boot arguments, a new memory map, and page tables
+//!   with the usual identity and kernel mappings
+//!
+//! - A copy of the kernel symbol table ([`MrldMemoryKind::KernelSymbols`])
+//!
+//! The new memory map is written in the UEFI format, but it's generated from
+//! our own [`MEMORY_MAP`] (which started out as the original UEFI memory
+//! map). Everything that belonged to the old kernel (its image, heap, page
+//! tables, symbols, and the old boot arguments) becomes available memory.
+//! The RSDP, framebuffer, command line, boot modules, and crash log are
+//! passed along unchanged.
+//!
+//! Right before jumping into the new kernel, we send INIT to all other
+//! cores, switch to the handoff page tables, and jump. The last step runs
+//! from the identity mapping, since the handoff page tables map the new
+//! kernel image at the old virtual addresses.
+//!
+//! NOTE: `SetVirtualAddressMap()` can only be called once, so UEFI runtime
+//! services aren't available to the new kernel.
+//!
+//! NOTE: The new kernel is always loaded at [`KERNEL_VIRT_BASE`] (we don't
+//! apply relocations here).
+//!
+//! Serial Protocol
+//! ===============
+//!
+//! With the `kexec` command line option, the kernel waits for a new image
+//! on COM2 (see `cargo xtask kexec`) instead of shutting down. The request
+//! format is described in `mrld/src/kexec.rs`.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use elf::abi::SHT_SYMTAB;
+use mrld::{
+    MrldBootArgs, MrldBuildId, MrldKernelSegment, MrldKernelSymbols,
+    MAX_KERNEL_SEGMENTS,
+};
+use mrld::image::{ KernelElf, ImageError, KERNEL_VIRT_BASE };
+use mrld::kexec::{ KEXEC_MAGIC, fnv1a32 };
+use mrld::paging::PageSize;
+use mrld::physmem::*;
+use uefi_raw::table::boot::{ MemoryAttribute, MemoryDescriptor, MemoryType };
+
+use crate::apic::Lapic;
+use crate::paging::MrldPageTable;
+use crate::physmem::MEMORY_MAP;
+use crate::serial::COM2;
+use crate::println;
+
+/// Largest kernel ELF we're willing to receive (in bytes)
+pub const MAX_IMAGE_SIZE: usize = 256 << 20;
+
+/// Offset of the new memory map in the handoff region
+const HANDOFF_MAP_OFFSET: u64 = 0x0001_0000;
+/// Offset of the page tables in the handoff region. 
+/// NOTE: The rest of the region (3MiB) is enough for a 512GiB identity 
+/// mapping with 2MiB pages (see [`MrldPageTable::build_handoff`]).
+const HANDOFF_PT_OFFSET: u64 = 0x0010_0000;
+
+/// Errors that can occur while receiving or loading a new kernel.
+#[derive(Debug)]
+pub enum KexecError {
+    /// The image is larger than [`MAX_IMAGE_SIZE`]
+    TooLarge(u64),
+    /// The image doesn't match the checksum
+    Checksum { expected: u32, actual: u32 },
+    /// The image is not a valid kernel ELF
+    Image(ImageError),
+    /// We couldn't allocate physical memory for something
+    NoMemory(&'static str),
+}
+impl core::fmt::Display for KexecError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::TooLarge(size) => {
+                write!(f, "image is too large ({} bytes)", size)
+            },
+            Self::Checksum { expected, actual } => {
+                write!(f, "bad checksum {:08x} (expected {:08x})",
+                    actual, expected)
+            },
+            Self::Image(e) => write!(f, "{}", e),
+            Self::NoMemory(what) => {
+                write!(f, "couldn't allocate physical memory for {}", what)
+            },
+        }
+    }
+}
+
+/// Wait for a kernel image on COM2.
+///
+/// Anything received before [`KEXEC_MAGIC`] is ignored.
+pub fn receive_serial() -> Result<Vec<u8>, KexecError> {
+    let mut com2 = COM2.lock();
+
+    // Look for the magic bytes
+    let mut window = [0u8; 8];
+    while window != KEXEC_MAGIC {
+        window.copy_within(1.., 0);
+        window[7] = unsafe { com2.recv_byte() };
+    }
+
+    let mut hdr = [0u8; 12];
+    for b in hdr.iter_mut() {
+        *b = unsafe { com2.recv_byte() };
+    }
+    let size = u64::from_le_bytes(hdr[0..8].try_into().unwrap());
+    let expected = u32::from_le_bytes(hdr[8..12].try_into().unwrap());
+    if size > MAX_IMAGE_SIZE as u64 {
+        return Err(KexecError::TooLarge(size));
+    }
+
+    let mut data = vec![0u8; size as usize];
+    for b in data.iter_mut() {
+        *b = unsafe { com2.recv_byte() };
+    }
+    drop(com2);
+
+    let actual = fnv1a32(&data);
+    if actual != expected {
+        return Err(KexecError::Checksum { expected, actual });
+    }
+    Ok(data)
+}
+
+/// A new kernel that has been loaded into physical memory.
+pub struct KexecImage {
+    /// Virtual address of the new kernel entrypoint
+    pub entrypt: u64,
+    /// Physical address of the new boot arguments
+    pub args: u64,
+    /// Physical address of the handoff PML4 table
+    pub pml4: u64,
+}
+
+/// Validate a kernel ELF, load it into physical memory, and prepare the
+/// handoff region.
+///
+/// The image is validated with [`KernelElf::parse`], like the bootloader
+/// does (see `mrld/src/image.rs`).
+pub unsafe fn load(args: &MrldBootArgs, image: &[u8])
+    -> Result<KexecImage, KexecError>
+{
+    let img = KernelElf::parse(image).map_err(KexecError::Image)?;
+    let elf = &img.elf;
+    let entrypt = img.entrypt;
+    let offsets = img.offsets();
+    let kernel_size = img.size();
+    let pg_2m = u64::from(PageSize::Size2MiB);
+
+    // Physical addresses are offsets from the base of the image for now
+    let mut segments = [MrldKernelSegment::new_empty(); MAX_KERNEL_SEGMENTS];
+    let segments = &mut segments[..offsets.len()];
+    segments.copy_from_slice(img.segments());
+
+    // Reserve physical memory for the new kernel image, the symbol table,
+    // and the handoff region.
+    let symbols = elf.section_headers().and_then(|shdrs| {
+        let symtab_hdr = shdrs.iter().find(|s| s.sh_type == SHT_SYMTAB)?;
+        let strtab_hdr = shdrs.get(symtab_hdr.sh_link as usize).ok()?;
+        let (symtab, _) = elf.section_data(&symtab_hdr).ok()?;
+        let (strtab, _) = elf.section_data(&strtab_hdr).ok()?;
+        Some((symtab, strtab))
+    });
+    let (kernel_desc, syms_desc, handoff_desc) = {
+        let mut mmap = MEMORY_MAP.lock();
+        let kernel_desc = mmap.allocate(PageSize::Size2MiB,
+            (kernel_size / pg_2m) as usize, MrldMemoryKind::KernelImage
+        ).ok_or(KexecError::NoMemory("kernel image"))?;
+        let syms_desc = match symbols {
+            Some((symtab, strtab)) => Some(mmap.allocate(PageSize::Size4KiB,
+                (symtab.len() + strtab.len()).div_ceil(0x1000).max(1),
+                MrldMemoryKind::KernelSymbols
+            ).ok_or(KexecError::NoMemory("kernel symbols"))?),
+            None => None,
+        };
+        let handoff_desc = mmap.allocate(PageSize::Size2MiB, 2,
+            MrldMemoryKind::BootArgs
+        ).ok_or(KexecError::NoMemory("handoff region"))?;
+        (kernel_desc, syms_desc, handoff_desc)
+    };
+
+    // Copy each segment into place and zero the remainder
+    for (seg, off) in segments.iter_mut().zip(offsets.iter()) {
+        seg.paddr += kernel_desc.start();
+        let tgt = seg.paddr as *mut u8;
+        tgt.copy_from(image.as_ptr().add(*off as usize), seg.filesz as usize);
+        if seg.memsz > seg.filesz {
+            tgt.add(seg.filesz as usize)
+                .write_bytes(0, (seg.memsz - seg.filesz) as usize);
+        }
+    }
+
+    // Build the new boot arguments
+    let new_args_ptr = handoff_desc.start() as *mut MrldBootArgs;
+    assert!(size_of::<MrldBootArgs>() as u64 <= HANDOFF_MAP_OFFSET);
+    new_args_ptr.write(MrldBootArgs::new_empty());
+    let new_args = &mut *new_args_ptr;
+    new_args.rsdp_addr = args.rsdp_addr;
+    new_args.framebuffer = args.framebuffer;
+    new_args.kernel_phys_base = kernel_desc.start();
+    new_args.kernel_virt_base = KERNEL_VIRT_BASE;
+    new_args.kernel_size = kernel_size;
+    new_args.kernel_segments[..segments.len()].copy_from_slice(segments);
+    new_args.num_kernel_segments = segments.len();
+    // NOTE: The new kernel has its own build ID, and there's no manifest
+    new_args.kernel_build_id = MrldBuildId::new_empty();
+    new_args.cmdline = args.cmdline;
+    new_args.firmware = args.firmware;
+    new_args.modules = args.modules;
+    new_args.num_modules = args.num_modules;
+    new_args.crashlog = args.crashlog;
+    new_args.timeline.tsc_freq = args.timeline.tsc_freq;
+    new_args.timeline.record("kexec load");
+
+    if let (Some((symtab, strtab)), Some(desc)) = (symbols, syms_desc) {
+        let symtab_ptr = desc.start() as *mut u8;
+        let strtab_ptr = symtab_ptr.add(symtab.len());
+        symtab_ptr.copy_from_nonoverlapping(symtab.as_ptr(), symtab.len());
+        strtab_ptr.copy_from_nonoverlapping(strtab.as_ptr(), strtab.len());
+        new_args.kernel_symbols = MrldKernelSymbols {
+            symtab: symtab_ptr as u64,
+            symtab_size: symtab.len() as u64,
+            strtab: strtab_ptr as u64,
+            strtab_size: strtab.len() as u64,
+        };
+    }
+
+    // Build the new memory map
+    let map_ptr = (handoff_desc.start() + HANDOFF_MAP_OFFSET) as *mut u8;
+    let desc_size = size_of::<MemoryDescriptor>();
+    let keep = [Some(kernel_desc), syms_desc, Some(handoff_desc)];
+    let mut num_entries = 0;
+    {
+        let mmap = MEMORY_MAP.lock();
+        let mut entries: Vec<MrldMemoryDesc> = mmap.iter_valid().copied()
+            .collect();
+        entries.sort_by_key(|d| d.start());
+        for entry in entries {
+            let kind = match entry.kind {
+                MrldMemoryKind::KernelImage |
+                MrldMemoryKind::KernelHeap |
+                MrldMemoryKind::KernelPaging |
+                MrldMemoryKind::KernelSymbols |
+                MrldMemoryKind::Trampoline |
+                MrldMemoryKind::BootArgs
+                    if !keep.contains(&Some(entry)) => MrldMemoryKind::Available,
+                kind => kind,
+            };
+            let off = num_entries * desc_size;
+            assert!(HANDOFF_MAP_OFFSET + ((off + desc_size) as u64)
+                <= HANDOFF_PT_OFFSET);
+            (map_ptr.add(off) as *mut MemoryDescriptor).write(MemoryDescriptor {
+                ty: kind.as_uefi_type(),
+                phys_start: entry.start(),
+                virt_start: 0,
+                page_count: entry.size() / 0x1000,
+                att: MemoryAttribute::empty(),
+            });
+            num_entries += 1;
+        }
+    }
+    new_args.uefi_map = map_ptr as u64;
+    new_args.uefi_map_size = num_entries * desc_size;
+    new_args.uefi_map_desc_size = desc_size;
+    new_args.uefi_map_desc_version = MemoryDescriptor::VERSION;
+
+    // Build the handoff page tables
+    let pt_desc = MrldMemoryDesc::new(
+        PhysRange::new(handoff_desc.start() + HANDOFF_PT_OFFSET,
+            handoff_desc.end()),
+        MrldMemoryKind::BootArgs,
+    );
+    let pml4 = MrldPageTable::new_empty().build_handoff(pt_desc,
+        kernel_desc.start(), KERNEL_VIRT_BASE, kernel_size
+    );
+
+    println!("[*] kexec: loaded kernel at p={:016x} ({:x} bytes), entry={:016x}",
+        kernel_desc.start(), kernel_size, entrypt
+    );
+    Ok(KexecImage { entrypt, args: new_args_ptr as u64, pml4 })
+}
+
+/// Switch to the handoff page tables and jump into the new kernel.
+///
+/// NOTE: This must run from the identity mapping.
+#[unsafe(naked)]
+unsafe extern "sysv64" fn handoff(args: u64, pml4: u64, entrypt: u64) -> ! {
+    core::arch::naked_asm!(r#"
+        mov cr3, rsi
+        jmp rdx
+    "#);
+}
+
+/// Stop all other cores and jump into a new kernel.
+pub unsafe fn exec(args: &MrldBootArgs, img: KexecImage) -> ! {
+    println!("[*] kexec: jumping into the new kernel ...");
+    core::arch::asm!("cli");
+    Lapic::send_init_others();
+    mrld::x86::wbinvd();
+
+    // Jump to the identity-mapped copy of 'handoff()' in the old image
+    let handoff_phys = (handoff as *const () as u64)
+        .wrapping_sub(args.kernel_virt_base)
+        .wrapping_add(args.kernel_phys_base);
+    let handoff_fn: unsafe extern "sysv64" fn(u64, u64, u64) -> ! =
+        core::mem::transmute(handoff_phys);
+    handoff_fn(img.args, img.pml4, img.entrypt)
+}
+
+/// Wait for new kernel images on COM2 until one of them can be loaded,
+/// then jump into it.
+pub unsafe fn serve_serial(args: &MrldBootArgs) -> ! {
+    loop {
+        println!("[*] kexec: waiting for a kernel image on COM2 ...");
+        let res = receive_serial().and_then(|image| {
+            println!("[*] kexec: received {} bytes", image.len());
+            load(args, &image)
+        });
+        match res {
+            Ok(img) => exec(args, img),
+            Err(e) => { println!("[!] kexec: {}", e); },
+        }
+    }
+}
@@ -3,6 +3,12 @@
 pub fn _print(args: core::fmt::Arguments<'_>) { 
     use core::fmt::Write;
     crate::serial::COM2.lock().write_fmt(args).unwrap();
+    crate::crashlog::write_fmt(args);
+
+    let mut fbcon = crate::fbcon::FBCON.lock();
+    if fbcon.enabled() { 
+        fbcon.write_fmt(args).unwrap();
+    }
 }
 
 #[macro_export]
@@ -10,31 +10,55 @@
 
 mod macros;
 mod serial;
+mod font;
+mod fbcon;
 mod util;
 mod mm; 
 mod physmem;
 mod paging;
 mod start;
+mod gdt;
+mod pvh;
 mod panic;
+mod backtrace;
+mod modules;
+mod crashlog;
+mod timeline;
+mod kexec;
+mod efi;
+mod smbios;
 mod interrupt;
 mod tls;
 mod acpi;
 mod apic; 
+mod pit;
 mod smp;
+mod smpcall;
 mod trampoline; 
 
 extern crate alloc;
 
 use mrld::x86::*;
 use mrld::{
-    MrldBootArgs
+    MrldBootArgs, MrldBuildId
 };
+use mrld::crashlog::CrashLogState;
+use uefi_raw::table::runtime::{ ResetType, VariableAttributes };
+
+/// Identifies this build of the kernel (generated by `build.rs`). 
+///
+/// `cargo xtask build` reads this from the ELF and copies it into the 
+/// manifest, so the bootloader verifies the kernel against the same ID. 
+#[used]
+#[unsafe(link_section = ".mrld_build_id")]
+pub static BUILD_ID: MrldBuildId = MrldBuildId::new(env!("MRLD_BUILD_ID"));
 
 /// Kernel entrypoint [in Rust].
 /// This function is entered from `_start()` in `src/start.rs`. 
 #[unsafe(link_section = ".text")]
 #[unsafe(no_mangle)]
 pub extern "sysv64" fn kernel_main(args: *const MrldBootArgs) -> ! { 
+    let entry_tsc = rdtsc();
     let args = unsafe { args.as_ref().unwrap() };
 
     // I guess we can use the APIC ID as a core ID for now
@@ -43,12 +67,42 @@ pub extern "sysv64" fn kernel_main(args: *const MrldBootArgs) -> ! {
     unsafe {
         // Initialize serial port as soon as possible
         serial::COM2.lock().init();
+        timeline::init(&args, entry_tsc);
+        timeline::record("serial");
+
+        // Keep a copy of our log output for the next boot
+        crashlog::init(&args);
+
+        // Use the framebuffer as a second console (if we have one)
+        fbcon::FBCON.lock().init(&args.framebuffer);
+
         println!("[*] HELO from the mrld kernel, on core {} :^)", apic_id);
+        println!("[*] Kernel build: {}", BUILD_ID.as_str());
+        let expected = args.kernel_build_id.as_str();
+        if !expected.is_empty() && expected != BUILD_ID.as_str() { 
+            println!("[!] The manifest is for a different build ({})", expected);
+        }
+        println!("[*] Command line: '{}'", args.cmdline.as_str());
+
+        // Use the symbol table from the bootloader for backtraces
+        backtrace::init(&args);
+
+        // Record which machine and firmware we're running on
+        let fw = &args.firmware;
+        println!("[*] Firmware: '{}' rev {:08x}, UEFI {}.{}", 
+            fw.vendor(), fw.revision, 
+            fw.uefi_revision >> 16, fw.uefi_revision & 0xffff
+        );
+        match smbios::Smbios::new(fw) {
+            Some(smbios) => smbios.dump(),
+            None => { println!("[!] No SMBIOS tables?"); },
+        }
 
         // Write and switch into a new IDT
         interrupt::IdtManager::init();
+        timeline::record("idt");
 
-        apic::Lapic::init();
+        apic::Lapic::init(args.cmdline.has("x2apic"));
     }
 
     // Initialize our memory map with data passed from UEFI. 
@@ -74,17 +128,57 @@ pub extern "sysv64" fn kernel_main(args: *const MrldBootArgs) -> ! {
         };
         (pt_desc, heap_desc)
     };
+    timeline::record("memory map");
 
     unsafe { 
         // Initialize page tables
         let mut pt = paging::PAGE_TABLE.lock();
-        pt.init(pt_desc, heap_desc);
+        pt.init(&args, pt_desc, heap_desc);
+        timeline::record("paging");
 
         // Initialize the global allocator and kernel heap
         mm::HEAP.init();
+        timeline::record("heap");
 
         // Initialize thread-local storage
         tls::Tls::init(apic_id as _);
+
+        // Switch to our own GDT and TSS, and use the IST for exceptions
+        // that need a known-good stack
+        gdt::CoreTables::init();
+        interrupt::IdtManager::enable_ist();
+    }
+
+    // Switch UEFI runtime services over to our page tables
+    unsafe { 
+        efi::RUNTIME.lock().init(&args);
+    }
+
+    if let Ok(t) = efi::get_time() { 
+        println!("[*] RTC time: {:04}-{:02}-{:02} {:02}:{:02}:{:02}", 
+            t.year, t.month, t.day, t.hour, t.minute, t.second
+        );
+    }
+
+    // Keep a persistent count of boots on this machine in NVRAM. 
+    // This writes to flash on every boot, so it's only enabled on request.
+    if args.cmdline.has("bootcount") && efi::is_available() { 
+        let mut buf = [0u8; 8];
+        let count = match efi::get_variable(
+            "MrldBootCount", &efi::MRLD_VENDOR_GUID, &mut buf
+        ) { 
+            Ok((8, _)) => u64::from_le_bytes(buf) + 1,
+            _ => 1,
+        };
+        let attrs = VariableAttributes::NON_VOLATILE 
+            | VariableAttributes::BOOTSERVICE_ACCESS
+            | VariableAttributes::RUNTIME_ACCESS;
+        match efi::set_variable("MrldBootCount", &efi::MRLD_VENDOR_GUID, 
+            attrs, &count.to_le_bytes()
+        ) { 
+            Ok(()) => { println!("[*] Boot count: {}", count); },
+            Err(e) => { println!("[!] Couldn't update boot count: {:?}", e); },
+        }
     }
 
     // Initialize ACPI
@@ -96,9 +190,26 @@ pub extern "sysv64" fn kernel_main(args: *const MrldBootArgs) -> ! {
         }
         mgr
     };
+    timeline::record("acpi");
 
 
 
+    println!("[*] Kernel image: p={:016x} v={:016x} size={:08x}",
+        args.kernel_phys_base, args.kernel_virt_base, args.kernel_size
+    );
+    println!("[*] Kernel segments:");
+    for seg in args.kernel_segments() { 
+        println!("  p={:016x} v={:016x} memsz={:08x} flags={:x}", 
+            seg.paddr, seg.vaddr, seg.memsz, seg.flags
+        );
+    }
+
+    { 
+        let mut modules = modules::MODULES.lock();
+        modules.init(&args);
+        modules.dump();
+    }
+
     println!("[*] Memory map:");
     { 
         let map = physmem::MEMORY_MAP.lock();
@@ -115,14 +226,34 @@ pub extern "sysv64" fn kernel_main(args: *const MrldBootArgs) -> ! {
 
     let x = tls::Tls::as_ref().state();
 
+    // Wait for a new kernel over serial instead of shutting down
+    if args.cmdline.has("kexec") { 
+        unsafe { kexec::serve_serial(&args) }
+    }
+
     unsafe { 
-        smp::Smp::init();
+        smpcall::init();
+        smp::Smp::init(&acpi);
     }
+    timeline::record("smp");
+
+    // Make sure that every core can run cross-core calls
+    if let Err(e) = smpcall::call_blocking(smpcall::CallTarget::All, || { 
+        println!("[*] smpcall on core {}", mrld::x86::cpuid(0xb, 0).edx);
+    }) { 
+        println!("[!] smpcall failed: {}", e);
+    }
+    timeline::dump();
 
 
 
     unsafe { 
         println!("[!] Going for shutdown (hopefully) ...");
+        crashlog::set_state(CrashLogState::Clean);
+
+        // Prefer UEFI runtime services, and fall back to ACPI
+        let status = efi::reset(ResetType::SHUTDOWN);
+        println!("[!] ResetSystem() unavailable ({:?}), trying ACPI", status);
         acpi.enter_s5_state(5);
     }
 
@@ -17,13 +17,15 @@ use uefi_raw::table::boot::{
     MemoryType, MemoryAttribute, MemoryDescriptor
 };
 
-/// The base of kernel image mapping
-pub const KERNEL_TEXT_BASE: u64 = 0xffff_ffff_8000_0000;
 /// The base of the kernel heap mapping
 pub const KERNEL_HEAP_BASE: u64 = 0xffff_ffd0_0000_0000;
 /// The size of the kernel heap mapping
 pub const KERNEL_HEAP_SIZE: usize = PageSize::Size1GiB.as_usize();
 
+/// The base of the UEFI runtime services mapping. 
+/// Each runtime region is mapped at this address plus its physical address.
+pub const UEFI_RUNTIME_BASE: u64 = 0xffff_ff00_0000_0000;
+
 /// The global allocator.
 #[global_allocator]
 pub static HEAP: MrldHeap = {
@@ -33,7 +35,7 @@ pub static HEAP: MrldHeap = {
     }
 };
 
-/// Trivial bump allocator. 
+/// Trivial bump allocator (safe to use from multiple cores). 
 pub struct MrldHeap { 
     next: AtomicPtr<u8>,
     end:  AtomicPtr<u8>,
@@ -57,24 +59,29 @@ impl MrldHeap {
 
 unsafe impl GlobalAlloc for MrldHeap { 
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 { 
-        let this_ptr = self.next.load(Ordering::SeqCst);
+        // NOTE: Other cores may be allocating at the same time
         let max_ptr = self.end.load(Ordering::SeqCst);
+        let mut this_ptr = self.next.load(Ordering::SeqCst);
+        loop { 
+            let this_algn = this_ptr.align_offset(layout.align());
+            let res_ptr = this_ptr.add(this_algn);
+            let next_ptr = res_ptr.add(layout.size());
 
-        let this_algn = this_ptr.align_offset(layout.align());
-        let next_ptr = this_ptr.offset(
-            this_algn as isize + layout.size() as isize
-        );
+            if next_ptr >= max_ptr { 
+                panic!("uhhhhh");
+            }
 
-        if next_ptr >= max_ptr { 
-            panic!("uhhhhh");
-        }
+            //println!("[*] alloc {:x?} this={:016x?} next={:016x?}", 
+            //    layout, this_ptr, next_ptr
+            //);
 
-        //println!("[*] alloc {:x?} this={:016x?} next={:016x?}", 
-        //    layout, this_ptr, next_ptr
-        //);
-
-        self.next.store(next_ptr, Ordering::SeqCst);
-        this_ptr
+            match self.next.compare_exchange_weak(this_ptr, next_ptr, 
+                Ordering::SeqCst, Ordering::SeqCst
+            ) { 
+                Ok(_) => return res_ptr,
+                Err(cur) => this_ptr = cur,
+            }
+        }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
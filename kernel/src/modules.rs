@@ -0,0 +1,66 @@
+//! Boot modules.
+//!
+//! The bootloader can load extra files (experiment payloads, test inputs,
+//! configuration, etc) alongside the kernel. These are described in
+//! [`MrldBootArgs`] by name, physical address, and size.
+//!
+//! NOTE: This assumes that modules are identity-mapped.
+
+use mrld::{ MrldBootArgs, MrldBootModule, MAX_BOOT_MODULES };
+use crate::println;
+use spin::Mutex;
+
+/// The list of boot modules.
+pub static MODULES: Mutex<Modules> = {
+    Mutex::new(Modules::new_empty())
+};
+
+/// The set of modules passed from the bootloader.
+pub struct Modules {
+    entries: [MrldBootModule; MAX_BOOT_MODULES],
+    num_entries: usize,
+}
+impl Modules {
+    pub const fn new_empty() -> Self {
+        Self {
+            entries: [const { MrldBootModule::new_empty() }; MAX_BOOT_MODULES],
+            num_entries: 0,
+        }
+    }
+
+    /// Copy the list of modules from the boot arguments.
+    pub fn init(&mut self, args: &MrldBootArgs) {
+        let modules = args.modules();
+        self.entries[..modules.len()].copy_from_slice(modules);
+        self.num_entries = modules.len();
+    }
+
+    /// Return an iterator over all modules.
+    pub fn iter(&self) -> impl Iterator<Item = &MrldBootModule> {
+        self.entries[..self.num_entries].iter()
+    }
+
+    /// Return the contents of the module with the given name (if it exists).
+    pub fn find(&self, name: &str) -> Option<&'static [u8]> {
+        let module = self.iter().find(|m| m.name() == name)?;
+        unsafe {
+            Some(core::slice::from_raw_parts(
+                module.addr as *const u8, module.size as usize
+            ))
+        }
+    }
+
+    pub fn dump(&self) {
+        println!("[*] Boot modules:");
+        for module in self.iter() {
+            println!("  {:016x} {:08x} '{}'",
+                module.addr, module.size, module.name()
+            );
+        }
+    }
+}
+
+/// Return the contents of the module with the given name (if it exists).
+pub fn find(name: &str) -> Option<&'static [u8]> {
+    MODULES.lock().find(name)
+}
@@ -7,8 +7,8 @@ use crate::println;
 use crate::physmem::*;
 use crate::mm::{ 
     KERNEL_HEAP_BASE,
-    KERNEL_TEXT_BASE
 };
+use mrld::MrldBootArgs;
 
 pub static PAGE_TABLE: Mutex<MrldPageTable> = { 
     Mutex::new(MrldPageTable::new_empty())
@@ -53,6 +53,7 @@ impl MrldPageTable {
     /// kernel image. 
     ///
     pub unsafe fn init(&mut self, 
+        args: &MrldBootArgs,
         pt_desc: MrldMemoryDesc,
         heap_desc: MrldMemoryDesc,
     ) { 
@@ -66,23 +67,24 @@ impl MrldPageTable {
         let pml4_ptr = self.allocate();
         let mut pml4 = PageTable::<PML4>::mut_ref_from_ptr(pml4_ptr as _);
 
-        self.map_pages(&mut pml4, 
+        let huge = mrld::x86::has_1gib_pages();
+        self.map_gib_range(&mut pml4, 
             0x0000_0000_0000_0000,
             0x0000_0000_0000_0000,
-            PageSize::Size1GiB,
-            512
+            Self::identity_map_size(huge),
+            huge
         );
         self.map_pages(&mut pml4, 
-            KERNEL_TEXT_BASE,
-            KERNEL_PHYS_BASE,
+            args.kernel_virt_base,
+            args.kernel_phys_base,
             PageSize::Size2MiB,
-            32
+            (args.kernel_size / u64::from(PageSize::Size2MiB)) as usize
         );
-        self.map_pages(&mut pml4, 
+        self.map_gib_range(&mut pml4, 
             KERNEL_HEAP_BASE,
             heap_desc.start(),
-            PageSize::Size1GiB,
-            1
+            u64::from(PageSize::Size1GiB),
+            huge
         );
 
         // Self::dump(&pml4);
@@ -90,6 +92,88 @@ impl MrldPageTable {
 
     }
 
+    /// Build a minimal set of page tables for handing off to another 
+    /// kernel (see `src/kexec.rs`), backed by 'desc'. 
+    ///
+    /// - Identity mapping (see [`MrldPageTable::identity_map_size`])
+    /// - Kernel image (2MiB pages)
+    ///
+    /// Unlike [`MrldPageTable::init`], this doesn't touch CR3. 
+    /// Returns the physical address of the new PML4 table. 
+    pub unsafe fn build_handoff(&mut self, 
+        desc: MrldMemoryDesc,
+        kernel_phys_base: u64,
+        kernel_virt_base: u64,
+        kernel_size: u64,
+    ) -> u64 { 
+        self.desc = desc;
+        self.next_page = desc.start();
+
+        let mut ptr = desc.start() as *mut u8;
+        ptr.write_bytes(0, desc.size() as _);
+
+        let pml4_ptr = self.allocate();
+        let mut pml4 = PageTable::<PML4>::mut_ref_from_ptr(pml4_ptr as _);
+        let huge = mrld::x86::has_1gib_pages();
+        self.map_gib_range(&mut pml4, 
+            0x0000_0000_0000_0000,
+            0x0000_0000_0000_0000,
+            Self::identity_map_size(huge),
+            huge
+        );
+        self.map_pages(&mut pml4, 
+            kernel_virt_base,
+            kernel_phys_base,
+            PageSize::Size2MiB,
+            (kernel_size / u64::from(PageSize::Size2MiB)) as usize
+        );
+        pml4.as_ptr() as u64
+    }
+
+    /// Size of the identity mapping (in bytes). 
+    ///
+    /// With 1GiB pages, this covers the low 512GiB. Otherwise (without 
+    /// PDPE1GB), we use 2MiB pages and only cover the top of physical memory 
+    /// (and at least the low 4GiB), like the bootloader does. 
+    fn identity_map_size(huge: bool) -> u64 { 
+        const IDENTITY_MAP_SIZE: u64 = 512 << 30;
+        if huge { 
+            return IDENTITY_MAP_SIZE;
+        }
+        MEMORY_MAP.lock().iter_valid()
+            .map(|d| d.end())
+            .max().unwrap_or(0)
+            .max(4 << 30)
+            .next_multiple_of(u64::from(PageSize::Size1GiB))
+            .min(IDENTITY_MAP_SIZE)
+    }
+
+    /// Map 'size' bytes of physical memory (a multiple of 1GiB), using 
+    /// 1GiB pages if 'huge' is set, and 2MiB pages otherwise. 
+    unsafe fn map_gib_range(
+        &mut self, 
+        pml4: &mut PageTable<PML4>,
+        base_vaddr: u64,
+        base_paddr: u64,
+        size: u64,
+        huge: bool,
+    )
+    {
+        let gib = u64::from(PageSize::Size1GiB);
+        assert!(size & (gib - 1) == 0);
+        if huge { 
+            self.map_pages(pml4, base_vaddr, base_paddr, 
+                PageSize::Size1GiB, (size / gib) as usize
+            );
+            return;
+        }
+        for off in (0..size).step_by(gib as usize) { 
+            self.map_pages(pml4, base_vaddr + off, base_paddr + off, 
+                PageSize::Size2MiB, 512
+            );
+        }
+    }
+
     /// Map a single page of physical memory. 
     pub unsafe fn map_page(
         &mut self, 
@@ -98,6 +182,22 @@ impl MrldPageTable {
         base_paddr: u64,
         pagesz: PageSize,
     )
+    {
+        self.map_page_flags(pml4, base_vaddr, base_paddr, pagesz, 
+            PTFlag::empty()
+        );
+    }
+
+    /// Map a single page of physical memory with some extra flags 
+    /// (ie. [`PTFlag::PCD`] for uncacheable memory). 
+    pub unsafe fn map_page_flags(
+        &mut self, 
+        pml4: &mut PageTable<PML4>,
+        base_vaddr: u64,
+        base_paddr: u64,
+        pagesz: PageSize,
+        flags: PTFlag,
+    )
     {
         assert!(base_vaddr & (u64::from(pagesz) - 1) == 0);
         assert!(base_paddr & (u64::from(pagesz) - 1) == 0);
@@ -119,7 +219,7 @@ impl MrldPageTable {
         if pagesz == PageSize::Size1GiB { 
             let entry = PageTableEntry::new(
                 base_paddr, 
-                PTFlag::P | PTFlag::RW | PTFlag::PS
+                PTFlag::P | PTFlag::RW | PTFlag::PS | flags
             );
             pdp.set_entry(pdp_idx, entry);
             return;
@@ -139,13 +239,28 @@ impl MrldPageTable {
         if pagesz == PageSize::Size2MiB { 
             let entry = PageTableEntry::new(
                 base_paddr, 
-                PTFlag::P | PTFlag::RW | PTFlag::PS
+                PTFlag::P | PTFlag::RW | PTFlag::PS | flags
             );
             pd.set_entry(pd_idx, entry);
             return;
         } 
-            
-        unimplemented!("4KiB mapping unimplemented");
+
+        // Get a mutable reference to the page table (or allocate a new one)
+        let pde = pd.get_mut(pd_idx);
+        let pt = if let Some(pt) = pde.as_mut_table() { 
+            pt
+        } else { 
+            let mut pt = PageTable::<PT>::mut_ref_from_ptr(self.allocate());
+            let entry = PageTableEntry::<PD>::new_table_ptr(pt.as_ptr());
+            pd.set_entry(pd_idx, entry);
+            pt
+        };
+
+        let entry = PageTableEntry::new(
+            base_paddr, 
+            PTFlag::P | PTFlag::RW | flags
+        );
+        pt.set_entry(pt_idx, entry);
     }
 
     /// Map one or more pages of physical memory. 
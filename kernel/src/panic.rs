@@ -1,6 +1,7 @@
 
 use crate::println;
 use core::panic::PanicInfo;
+use mrld::crashlog::CrashLogState;
 
 /// Global panic handler
 #[panic_handler]
@@ -8,6 +9,9 @@ fn panic(info: &core::panic::PanicInfo) -> ! { unsafe {
     // Disable interrupts
     core::arch::asm!("cli");
 
+    // Keep the crash log around for the bootloader
+    crate::crashlog::set_state(CrashLogState::Panic);
+
     if let Some(loc) = info.location() { 
         println!("[!] PANIC! in '{}', line {}, col {}",
             loc.file(), loc.line(), loc.column()
@@ -17,6 +21,10 @@ fn panic(info: &core::panic::PanicInfo) -> ! { unsafe {
         println!("[!] PANIC!: (no location)"); 
         println!("{}", info.message());
     }
+    crate::backtrace::print_backtrace(crate::backtrace::read_rbp());
+
+    // Make sure the crash log reaches memory before someone resets us
+    mrld::x86::wbinvd();
     loop {}
 } }
 
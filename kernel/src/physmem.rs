@@ -17,30 +17,39 @@
 //! ===========
 //!
 //! The kernel boots with provisional page tables defined in the bootloader
-//! (see `boot/src/bup.rs`), which are roughly: 
+//! (see `BootPageTables` in `boot/src/bup.rs`), which are roughly: 
 //!
-//! - 512GiB identity mapping
-//!     - Physical : 0x0000_0000_0000_0000 - 0x0000_0080_0000_0000 
-//!     - Virtual  : 0x0000_0000_0000_0000 - 0x0000_0080_0000_0000
+//! - Identity mapping
+//!     - 512GiB with 1GiB pages, or up to the top of the UEFI memory map
+//!       with 2MiB pages (when the CPU doesn't support 1GiB pages)
+//!     - The boot arguments, framebuffer, symbol table, and UEFI memory map
+//!
+//! - Kernel image (4KiB pages, only the ELF segments)
+//!     - Physical : 'kernel_phys_base' - 'kernel_phys_base + kernel_size'
+//!     - Virtual  : 'kernel_virt_base' - 'kernel_virt_base + kernel_size'
+//!     - Segments are only writable with `PF_W`, and are no-execute 
+//!       without `PF_X` (if the CPU supports NX)
 //!
-//! - Kernel image (32 2MiB pages)
-//!     - Physical : 0x0000_0000_0400_0000 - 0x0000_0000_0800_0000 
-//!     - Virtual  : 0xffff_ffff_8000_0000 - 0xffff_ffff_8400_0000 
+//! The bootloader chooses where the kernel image lives, and passes the 
+//! base addresses and size in [`MrldBootArgs`]. Unless the bootloader was 
+//! built with KASLR, the virtual base is always `0xffff_ffff_8000_0000`.
 //!
 //! After we've defined a physical region for paging, a new set of tables 
 //! is created with the following mappings: 
 //!
-//! - 512GiB identity mapping
+//! - 512GiB identity mapping (1GiB pages)
 //!     - Physical : 0x0000_0000_0000_0000 - 0x0000_0080_0000_0000 
 //!     - Virtual  : 0x0000_0000_0000_0000 - 0x0000_0080_0000_0000
+//!     - Without 1GiB pages, this uses 2MiB pages and only covers the top 
+//!       of physical memory (and at least the low 4GiB)
 //!
-//! - Kernel heap (one 1GiB page)
+//! - Kernel heap (one 1GiB page, or 512 2MiB pages)
 //!     - Physical : The [`MrldMemoryKind::KernelHeap`] region
 //!     - Virtual  : 0xffff_ffd0_0000_0000 - 0xffff_ffd0_4000_0000 
 //!
-//! - Kernel image (32 2MiB pages)
-//!     - Physical : 0x0000_0000_0400_0000 - 0x0000_0000_0800_0000 
-//!     - Virtual  : 0xffff_ffff_8000_0000 - 0xffff_ffff_8400_0000 
+//! - Kernel image (2MiB pages)
+//!     - Physical : 'kernel_phys_base' - 'kernel_phys_base + kernel_size'
+//!     - Virtual  : 'kernel_virt_base' - 'kernel_virt_base + kernel_size'
 //!
 
 use mrld::physmem::*;
@@ -52,9 +61,6 @@ use uefi_raw::table::boot::{
     MemoryType, MemoryAttribute, MemoryDescriptor
 };
 
-/// Base of physical memory allocated (by the bootloader) for the kernel.
-pub const KERNEL_PHYS_BASE: u64     = 0x0000_0000_0400_0000;
-
 /// The physical memory map.
 pub static MEMORY_MAP: Mutex<MrldMemoryMap> = {
     Mutex::new(MrldMemoryMap::new_empty())
@@ -167,18 +173,12 @@ impl MrldMemoryMap {
             }
         }
 
-        // Reserve a region for the kernel image
-        let desc = self.allocate_at(
-            KERNEL_PHYS_BASE,
-            mrld::paging::PageSize::Size2MiB, 
-            32, 
-            mrld::physmem::MrldMemoryKind::KernelImage,
-        );
-        if desc.is_none() { 
-            panic!("Couldn't reserve physical region for kernel image?");
+        // NOTE: The bootloader allocates the kernel image with a custom UEFI
+        // memory type, so it already appears in the map as 
+        // [`MrldMemoryKind::KernelImage`]. 
+        if self.find_with(|d| d.kind == MrldMemoryKind::KernelImage).is_none() { 
+            panic!("No physical region for the kernel image?");
         }
-
-
     }
 
     /// Create a new region with the given base address, page size, and page 
@@ -271,5 +271,22 @@ impl MrldMemoryMap {
             None
         }
     }
+
+    /// Like [`MrldMemoryMap::allocate`], but the new region must end at or 
+    /// below 'max_addr' (ie. for memory that must be addressable in real 
+    /// mode). The first page of physical memory is never used. 
+    pub unsafe fn allocate_below(&mut self, max_addr: u64, pagesz: PageSize, 
+        cnt: usize, kind: MrldMemoryKind) -> Option<MrldMemoryDesc>
+    { 
+        let requested_range = self.iter_valid()
+            .filter(|desc| desc.kind == MrldMemoryKind::Available)
+            .filter(|desc| desc.end() > 0x1000)
+            .filter_map(|desc| { 
+                PhysRange::new(desc.start().max(0x1000), desc.end())
+                    .try_get_pages(pagesz, cnt)
+            })
+            .find(|range| range.end() <= max_addr)?;
+        self.allocate_at(requested_range.start(), pagesz, cnt, kind)
+    }
 }
 
@@ -0,0 +1,57 @@
+//! Busy-wait delays with the legacy programmable interval timer (PIT).
+//!
+//! We don't know the TSC frequency (the bootloader may not have measured it,
+//! see [`mrld::MrldBootTimeline`]) and we don't have any timer interrupts
+//! yet. The PIT runs at a fixed frequency, so we use channel 2 in one-shot
+//! mode and poll the output bit in port `0x61` until the count expires.
+//!
+//! This is only meant for short delays during initialization (ie. for the
+//! INIT-SIPI-SIPI sequence in `src/smp.rs`).
+
+use mrld::x86::io::IoPort;
+
+/// PIT input clock frequency (in Hz)
+const PIT_FREQ: u64 = 1_193_182;
+
+/// Longest delay for a single countdown (the counter is 16-bit)
+const MAX_CHUNK_US: u64 = 50_000;
+
+/// Channel 2 data port
+const PIT_CH2: IoPort = IoPort::new(0x42);
+/// Mode/command register
+const PIT_CMD: IoPort = IoPort::new(0x43);
+/// NMI status and control (channel 2 gate and output)
+const PIT_CTRL: IoPort = IoPort::new(0x61);
+
+/// Channel 2, lo/hi byte access, mode 0 (interrupt on terminal count)
+const PIT_CMD_CH2_ONESHOT: u8 = 0b1011_0000;
+
+/// Channel 2 gate (bit 0), speaker enable (bit 1), channel 2 output (bit 5)
+const CTRL_GATE: u8 = 1 << 0;
+const CTRL_SPEAKER: u8 = 1 << 1;
+const CTRL_OUT: u8 = 1 << 5;
+
+/// Count down 'ticks' of the PIT clock and wait for the output to go high.
+unsafe fn countdown(ticks: u16) {
+    // Enable the channel 2 gate (with the speaker disabled)
+    let ctrl = PIT_CTRL.in8();
+    PIT_CTRL.out8((ctrl & !CTRL_SPEAKER) | CTRL_GATE);
+
+    PIT_CMD.out8(PIT_CMD_CH2_ONESHOT);
+    PIT_CH2.out8(ticks as u8);
+    PIT_CH2.out8((ticks >> 8) as u8);
+    while PIT_CTRL.in8() & CTRL_OUT == 0 {
+        mrld::x86::pause();
+    }
+}
+
+/// Wait for (at least) 'us' microseconds.
+pub fn udelay(us: u64) {
+    let mut remaining = us;
+    while remaining != 0 {
+        let chunk = remaining.min(MAX_CHUNK_US);
+        let ticks = (chunk * PIT_FREQ).div_ceil(1_000_000);
+        unsafe { countdown(ticks as u16); }
+        remaining -= chunk;
+    }
+}
@@ -0,0 +1,494 @@
+//! PVH entrypoint (for booting directly with `qemu -kernel`).
+//!
+//! Booting in QEMU normally goes through OVMF, PXE, and the bootloader
+//! before the kernel ever runs. The kernel ELF also contains a Xen PVH entry
+//! note (`XEN_ELFNOTE_PHYS32_ENTRY`), so QEMU can load the ELF itself (at
+//! the physical addresses in the program headers, see `mrld-kernel.ld`) and
+//! jump into [`pvh_start32`] without any firmware in the way:
+//!
+//! ```text
+//! $ qemu-system-x86_64 -kernel target/mrld-kernel/debug/mrld-kernel \
+//!     -append "<command line>" ...
+//! ```
+//!
+//! Entry State
+//! ===========
+//!
+//! PVH starts us in 32-bit protected mode with paging disabled, flat
+//! segments, no stack, and the physical address of the start info
+//! ([`HvmStartInfo`]) in `ebx`. The 32-bit stub:
+//!
+//! - Builds minimal page tables (a 512GiB identity mapping with 1GiB pages,
+//!   or only the low 4GiB with 2MiB pages when the CPU doesn't support 1GiB
+//!   pages, and the kernel image at [`KERNEL_VIRT_BASE`] with 2MiB pages)
+//! - Enables long mode (and NX, if supported), and switches into a 64-bit
+//!   code segment
+//! - Calls [`pvh_main`] on the kernel stack
+//!
+//! [`pvh_main`] translates the start info (memory map, RSDP, command line,
+//! and modules) into [`MrldBootArgs`], then enters the kernel through
+//! [`_start`] like the bootloader does.
+//!
+//! Implementation Notes
+//! ====================
+//!
+//! The stub runs at physical addresses, but the kernel is linked in the top
+//! 2GiB. The linker script defines the physical addresses that we need as
+//! absolute symbols (`_pvh_start32_phys` and `_pvh_tables_phys`), so that
+//! the bootloader doesn't try to relocate them with KASLR.
+//!
+//! There is no UEFI here: runtime services, the framebuffer, the crash log,
+//! and kernel symbols are unavailable. We scan the BIOS area for the SMBIOS
+//! entry points (and the RSDP, if the start info doesn't have one).
+//!
+//! [`_start`]: crate::start::_start
+
+use core::ffi::{ c_char, CStr };
+use core::mem::MaybeUninit;
+use mrld::{
+    MrldBootArgs, MrldBootModule, MrldBuildId, MrldCmdline, MrldFirmwareInfo,
+    MAX_BOOT_MODULES,
+};
+use mrld::image::KERNEL_VIRT_BASE;
+use mrld::physmem::MrldMemoryKind;
+use mrld::x86::gdt::{ KERNEL_CODE_SEL, KERNEL_DATA_SEL };
+use spin::Mutex;
+use uefi_raw::table::boot::{ MemoryAttribute, MemoryDescriptor, MemoryType };
+
+use crate::start::{ KERNEL_TEXT_DESC, KERNEL_DATA_DESC };
+use crate::serial;
+use crate::println;
+
+/// Magic value in [`HvmStartInfo::magic`]
+const HVM_START_MAGIC: u32 = 0x336e_c578;
+
+/// Xen ELF note type for the 32-bit PVH entrypoint
+const XEN_ELFNOTE_PHYS32_ENTRY: u32 = 18;
+
+/// Maximum number of entries in the memory map we build
+const MAX_MAP_ENTRIES: usize = 256;
+
+/// E820 memory types (from the PVH memory map)
+const E820_RAM: u32 = 1;
+const E820_ACPI: u32 = 3;
+const E820_NVS: u32 = 4;
+const E820_UNUSABLE: u32 = 5;
+
+/// The PVH start info (`struct hvm_start_info`).
+#[repr(C)]
+struct HvmStartInfo {
+    magic: u32,
+    version: u32,
+    flags: u32,
+    nr_modules: u32,
+    modlist_paddr: u64,
+    cmdline_paddr: u64,
+    rsdp_paddr: u64,
+    /// Only valid when 'version' is at least 1
+    memmap_paddr: u64,
+    /// Only valid when 'version' is at least 1
+    memmap_entries: u32,
+    reserved: u32,
+}
+
+/// An entry in the PVH module list (`struct hvm_modlist_entry`).
+#[repr(C)]
+struct HvmModlistEntry {
+    paddr: u64,
+    size: u64,
+    cmdline_paddr: u64,
+    reserved: u64,
+}
+
+/// An entry in the PVH memory map (`struct hvm_memmap_table_entry`).
+#[repr(C)]
+struct HvmMemmapEntry {
+    addr: u64,
+    size: u64,
+    ty: u32,
+    reserved: u32,
+}
+
+/// Storage for the boot arguments and memory map that we build.
+struct PvhStorage {
+    args: MaybeUninit<MrldBootArgs>,
+    map: [MaybeUninit<MemoryDescriptor>; MAX_MAP_ENTRIES],
+}
+
+/// NOTE: This is locked forever once we've used it (see [`pvh_main`]).
+static STORAGE: Mutex<PvhStorage> = Mutex::new(PvhStorage {
+    args: MaybeUninit::uninit(),
+    map: [const { MaybeUninit::uninit() }; MAX_MAP_ENTRIES],
+});
+
+unsafe extern "C" {
+    /// End of the kernel image (from the linker script)
+    static _kernel_end: u8;
+}
+
+// The PVH entry note (read by QEMU from the PT_NOTE segment).
+core::arch::global_asm!(r#"
+.section .note.Xen, "a", @note
+.balign 4
+    .long 4
+    .long 4
+    .long {note_type}
+    .asciz "Xen"
+.balign 4
+    .long _pvh_start32_phys
+.balign 4
+"#,
+note_type = const XEN_ELFNOTE_PHYS32_ENTRY,
+);
+
+// Page tables (and a small stack) for the 32-bit stub.
+core::arch::global_asm!(r#"
+.section .bss.pvh, "aw", @nobits
+.balign 4096
+.global pvh_tables
+pvh_tables:
+    // PML4, PDPT (identity), PDPT (kernel), PD (kernel), four PDs (for the
+    // identity mapping without 1GiB pages), and a stack
+    .skip 0x9000
+"#);
+
+// The 32-bit stub.
+core::arch::global_asm!(r#"
+.section .text.pvh, "ax", @progbits
+.code32
+.global pvh_start32
+pvh_start32:
+    cli
+    cld
+
+    // Keep the start info pointer somewhere that CPUID won't clobber
+    mov ebp, ebx
+    mov esp, offset _pvh_tables_phys + 0x9000
+
+    // Clear the page tables
+    mov edi, offset _pvh_tables_phys
+    xor eax, eax
+    mov ecx, 0x2000
+    rep stosd
+
+    // PML4[0] points to the identity PDPT, PML4[511] to the kernel PDPT
+    mov edi, offset _pvh_tables_phys
+    lea eax, [edi + 0x1003]
+    mov dword ptr [edi], eax
+    lea eax, [edi + 0x2003]
+    mov dword ptr [edi + 511 * 8], eax
+
+    // Identity map the first 512GiB with 1GiB pages, if we have them
+    // (CPUID Fn8000_0001 EDX[26])
+    lea esi, [edi + 0x1000]
+    mov eax, 0x80000001
+    cpuid
+    test edx, 1 << 26
+    jz 5f
+    xor ecx, ecx
+2:
+    mov eax, ecx
+    shl eax, 30
+    or eax, 0x83
+    mov edx, ecx
+    shr edx, 2
+    mov dword ptr [esi + ecx * 8], eax
+    mov dword ptr [esi + ecx * 8 + 4], edx
+    inc ecx
+    cmp ecx, 512
+    jb 2b
+    jmp 6f
+
+    // Otherwise, identity map the first 4GiB with 2MiB pages
+5:
+    lea eax, [edi + 0x4003]
+    xor ecx, ecx
+7:
+    mov dword ptr [esi + ecx * 8], eax
+    add eax, 0x1000
+    inc ecx
+    cmp ecx, 4
+    jb 7b
+    lea esi, [edi + 0x4000]
+    xor ecx, ecx
+8:
+    mov eax, ecx
+    shl eax, 21
+    or eax, 0x83
+    mov dword ptr [esi + ecx * 8], eax
+    inc ecx
+    cmp ecx, 2048
+    jb 8b
+6:
+
+    // Map 1GiB of the kernel image at the top of the address space
+    lea eax, [edi + 0x3003]
+    mov dword ptr [edi + 0x2000 + 510 * 8], eax
+    lea esi, [edi + 0x3000]
+    mov eax, offset _kernel_phys_base
+    or eax, 0x83
+    xor ecx, ecx
+3:
+    mov dword ptr [esi + ecx * 8], eax
+    add eax, 0x200000
+    inc ecx
+    cmp ecx, 512
+    jb 3b
+
+    // Enable PAE and load the page tables
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+    mov cr3, edi
+
+    // Enable long mode (and NX, if we have it)
+    mov eax, 0x80000001
+    cpuid
+    mov esi, 1 << 8
+    test edx, 1 << 20
+    jz 4f
+    or esi, 1 << 11
+4:
+    mov ecx, 0xc0000080
+    rdmsr
+    or eax, esi
+    wrmsr
+
+    // Enable paging (and write protection)
+    mov eax, cr0
+    or eax, (1 << 31) | (1 << 16) | 1
+    mov cr0, eax
+
+    // Switch into a 64-bit code segment
+    mov ebx, offset _pvh_start32_phys
+    lgdt [ebx + PVH_GDTR_OFF]
+    push {code_sel}
+    lea eax, [ebx + PVH_START64_OFF]
+    push eax
+    retf
+
+.code64
+pvh_start64:
+    mov ax, {data_sel}
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov gs, ax
+    mov ss, ax
+
+    // Move to the kernel stack, and continue in Rust
+    movabs rsp, offset _kernel_stack_hi
+    mov edi, ebp
+    mov esi, offset _kernel_phys_base
+    xor ebp, ebp
+    movabs rax, offset {main}
+    call rax
+5:
+    ud2
+    jmp 5b
+
+.balign 8
+pvh_gdt:
+    .quad 0x0000000000000000
+    .quad {text}
+    .quad {data}
+pvh_gdtr:
+    .word (pvh_gdtr - pvh_gdt - 1)
+    .long _pvh_start32_phys + (pvh_gdt - pvh_start32)
+
+// Offsets from the start of the stub (we're running at a physical address)
+.set PVH_GDTR_OFF, pvh_gdtr - pvh_start32
+.set PVH_START64_OFF, pvh_start64 - pvh_start32
+"#,
+code_sel = const KERNEL_CODE_SEL.as_u16(),
+data_sel = const KERNEL_DATA_SEL.as_u16(),
+text = const KERNEL_TEXT_DESC.as_u64(),
+data = const KERNEL_DATA_DESC.as_u64(),
+main = sym pvh_main,
+);
+
+/// Return the physical address of something in the kernel image.
+fn image_phys(vaddr: u64, phys_base: u64) -> u64 {
+    vaddr - KERNEL_VIRT_BASE + phys_base
+}
+
+/// Read a NUL-terminated string at a physical address.
+unsafe fn read_cstr(paddr: u64) -> &'static str {
+    if paddr == 0 {
+        return "";
+    }
+    unsafe { CStr::from_ptr(paddr as *const c_char) }.to_str().unwrap_or("")
+}
+
+/// Scan the BIOS area for a structure with the given anchor string
+/// (on a 16-byte boundary).
+unsafe fn scan_bios(lo: u64, hi: u64, anchor: &[u8]) -> u64 {
+    for addr in (lo..hi).step_by(16) {
+        let ptr = addr as *const u8;
+        if unsafe { core::slice::from_raw_parts(ptr, anchor.len()) } == anchor {
+            return addr;
+        }
+    }
+    0
+}
+
+/// Convert an E820 memory type into a UEFI memory type.
+fn e820_to_uefi(ty: u32) -> MemoryType {
+    match ty {
+        E820_RAM => MemoryType::CONVENTIONAL,
+        E820_ACPI => MemoryType::ACPI_RECLAIM,
+        E820_NVS => MemoryType::ACPI_NON_VOLATILE,
+        E820_UNUSABLE => MemoryType::UNUSABLE,
+        _ => MemoryType::RESERVED,
+    }
+}
+
+/// Builds a UEFI-format memory map.
+struct MapBuilder<'a> {
+    map: &'a mut [MaybeUninit<MemoryDescriptor>],
+    len: usize,
+}
+impl MapBuilder<'_> {
+    fn push(&mut self, start: u64, end: u64, ty: MemoryType) {
+        if end <= start {
+            return;
+        }
+        if self.len == self.map.len() {
+            panic!("PVH memory map has too many entries");
+        }
+        self.map[self.len].write(MemoryDescriptor {
+            ty,
+            phys_start: start,
+            virt_start: 0,
+            page_count: (end - start) / 0x1000,
+            att: MemoryAttribute::empty(),
+        });
+        self.len += 1;
+    }
+
+    /// Add a RAM region, with any overlapping carve-outs (sorted by address)
+    /// split out into their own entries.
+    fn push_ram(&mut self, start: u64, end: u64, carve: &[(u64, u64, MemoryType)]) {
+        let mut cur = start;
+        for &(lo, hi, ty) in carve.iter() {
+            if hi <= cur || lo >= end {
+                continue;
+            }
+            self.push(cur, lo.max(cur), MemoryType::CONVENTIONAL);
+            self.push(lo.max(cur), hi.min(end), ty);
+            cur = hi.min(end);
+        }
+        self.push(cur, end, MemoryType::CONVENTIONAL);
+    }
+}
+
+/// Translate the PVH start info into [`MrldBootArgs`], then enter the
+/// kernel.
+///
+/// This is called from [`pvh_start32`] (in 64-bit mode, on the kernel
+/// stack) with the physical addresses of the start info and the kernel
+/// image.
+extern "sysv64" fn pvh_main(start_info: u64, phys_base: u64) -> ! {
+    let entry_tsc = mrld::x86::rdtsc();
+    unsafe { serial::COM2.lock().init(); }
+
+    let info = unsafe { &*(start_info as *const HvmStartInfo) };
+    if info.magic != HVM_START_MAGIC {
+        panic!("Bad PVH start info magic {:08x}", info.magic);
+    }
+    if info.version < 1 || info.memmap_entries == 0 {
+        panic!("PVH start info has no memory map (version {})", info.version);
+    }
+    println!("[*] PVH boot: start info v{}, {} memory map entries, {} modules",
+        info.version, info.memmap_entries, info.nr_modules
+    );
+
+    let storage = spin::MutexGuard::leak(STORAGE.lock());
+    let args = storage.args.write(MrldBootArgs::new_empty());
+
+    // Describe the kernel image
+    let end = unsafe { &raw const _kernel_end } as u64;
+    args.kernel_phys_base = phys_base;
+    args.kernel_virt_base = KERNEL_VIRT_BASE;
+    args.kernel_size = (end - KERNEL_VIRT_BASE).next_multiple_of(0x20_0000);
+    // NOTE: There's no manifest (the kernel has its own build ID)
+    args.kernel_build_id = MrldBuildId::new_empty();
+    args.timeline.record_at("pvh entry", entry_tsc);
+
+    args.cmdline = MrldCmdline::new(unsafe { read_cstr(info.cmdline_paddr) });
+
+    // Firmware tables
+    args.rsdp_addr = match info.rsdp_paddr {
+        0 => unsafe { scan_bios(0xe_0000, 0x10_0000, b"RSD PTR ") },
+        addr => addr,
+    };
+    args.firmware = MrldFirmwareInfo::new_empty();
+    args.firmware.vendor[..3].copy_from_slice(b"PVH");
+    args.firmware.smbios_addr = unsafe {
+        scan_bios(0xf_0000, 0x10_0000, b"_SM_")
+    };
+    args.firmware.smbios3_addr = unsafe {
+        scan_bios(0xf_0000, 0x10_0000, b"_SM3_")
+    };
+
+    // Boot modules (ie. from '-initrd')
+    let mut carve = [(0u64, 0u64, MemoryType::RESERVED); MAX_BOOT_MODULES + 1];
+    carve[0] = (
+        phys_base,
+        phys_base + args.kernel_size,
+        MrldMemoryKind::KernelImage.as_uefi_type(),
+    );
+    let mut num_carve = 1;
+    let modules = unsafe {
+        core::slice::from_raw_parts(
+            info.modlist_paddr as *const HvmModlistEntry,
+            info.nr_modules as usize,
+        )
+    };
+    for module in modules.iter().take(MAX_BOOT_MODULES) {
+        let name = match unsafe { read_cstr(module.cmdline_paddr) } {
+            "" => "initrd",
+            name => name,
+        };
+        args.modules[args.num_modules] = MrldBootModule::new(
+            name, module.paddr, module.size
+        );
+        args.num_modules += 1;
+        carve[num_carve] = (
+            module.paddr & !0xfff,
+            (module.paddr + module.size).next_multiple_of(0x1000),
+            MrldMemoryKind::BootModule.as_uefi_type(),
+        );
+        num_carve += 1;
+    }
+    let carve = &mut carve[..num_carve];
+    carve.sort_unstable_by_key(|c| c.0);
+
+    // Build a UEFI-format memory map from the E820 map.
+    // NOTE: This assumes that the E820 map is sorted.
+    let e820 = unsafe {
+        core::slice::from_raw_parts(
+            info.memmap_paddr as *const HvmMemmapEntry,
+            info.memmap_entries as usize,
+        )
+    };
+    let mut map = MapBuilder { map: &mut storage.map, len: 0 };
+    for entry in e820 {
+        if entry.ty == E820_RAM {
+            let start = entry.addr.next_multiple_of(0x1000);
+            let end = (entry.addr + entry.size) & !0xfff;
+            map.push_ram(start, end, carve);
+        } else {
+            let start = entry.addr & !0xfff;
+            let end = (entry.addr + entry.size).next_multiple_of(0x1000);
+            map.push(start, end, e820_to_uefi(entry.ty));
+        }
+    }
+    let num_entries = map.len;
+    args.uefi_map = image_phys(storage.map.as_ptr() as u64, phys_base);
+    args.uefi_map_size = num_entries * size_of::<MemoryDescriptor>();
+    args.uefi_map_desc_size = size_of::<MemoryDescriptor>();
+    args.uefi_map_desc_version = MemoryDescriptor::VERSION;
+
+    crate::start::_start(args)
+}
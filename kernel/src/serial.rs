@@ -73,6 +73,24 @@ impl <const PORT: u16> SerialPort<PORT> {
         }
     }
 
+    /// Returns the next byte from this port (if one has been received)
+    pub unsafe fn try_recv_byte(&mut self) -> Option<u8> {
+        if (Self::LINE_STS.in8() & 0b0000_0001) != 0 {
+            Some(Self::DATA.in8())
+        } else {
+            None
+        }
+    }
+
+    /// Wait for the next byte from this port
+    pub unsafe fn recv_byte(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_recv_byte() {
+                return byte;
+            }
+        }
+    }
+
     /// Initialize this serial port. 
     pub unsafe fn init(&mut self) { 
         self.disable_interrupts();
@@ -0,0 +1,321 @@
+//! SMBIOS (System Management BIOS) tables.
+//!
+//! The bootloader passes the physical addresses of the SMBIOS 2.x and 3.x
+//! entry points (from the UEFI configuration table). The entry point tells
+//! us where to find the structure table, which is a list of variable-length
+//! structures:
+//!
+//! - A formatted area, starting with a 4-byte header (type, length, handle)
+//! - An unformatted area with a set of NUL-terminated strings, ending with
+//!   an extra NUL byte. Fields in the formatted area refer to these strings
+//!   with a 1-based index (and zero means "no string").
+//!
+//! We only care about a few kinds of structures (BIOS, system, baseboard,
+//! and memory devices), which are enough to tell exactly which machine and
+//! firmware produced some experimental result.
+//!
+//! NOTE: This assumes that the SMBIOS tables are identity-mapped.
+//!
+//! See the DMTF "System Management BIOS (SMBIOS) Reference Specification".
+
+use mrld::MrldFirmwareInfo;
+use crate::println;
+
+/// Helper for reading SMBIOS structures.
+pub struct Smbios {
+    /// SMBIOS version (major, minor)
+    version: (u8, u8),
+    /// The structure table
+    table: &'static [u8],
+}
+impl Smbios {
+    /// Structure type for BIOS information
+    pub const TYPE_BIOS: u8 = 0;
+    /// Structure type for system information
+    pub const TYPE_SYSTEM: u8 = 1;
+    /// Structure type for baseboard information
+    pub const TYPE_BOARD: u8 = 2;
+    /// Structure type for a memory device
+    pub const TYPE_MEMORY_DEVICE: u8 = 17;
+    /// Structure type marking the end of the table
+    pub const TYPE_END: u8 = 127;
+
+    /// Find the structure table, preferring the SMBIOS 3.x entry point.
+    pub unsafe fn new(fw: &MrldFirmwareInfo) -> Option<Self> {
+        if fw.smbios3_addr != 0 {
+            if let Some(res) = Self::from_entry3(fw.smbios3_addr) {
+                return Some(res);
+            }
+        }
+        if fw.smbios_addr != 0 {
+            if let Some(res) = Self::from_entry2(fw.smbios_addr) {
+                return Some(res);
+            }
+        }
+        None
+    }
+
+    /// Returns 'true' if the bytes in an entry point sum to zero.
+    fn checksum_ok(data: &[u8]) -> bool {
+        data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)) == 0
+    }
+
+    /// Parse a 64-bit ('_SM3_') entry point.
+    unsafe fn from_entry3(addr: u64) -> Option<Self> {
+        let ptr = addr as *const u8;
+        let hdr = core::slice::from_raw_parts(ptr, 0x18);
+        if &hdr[0..5] != b"_SM3_" {
+            return None;
+        }
+        let len = hdr[0x06] as usize;
+        if len < 0x18 || !Self::checksum_ok(core::slice::from_raw_parts(ptr, len)) {
+            return None;
+        }
+        let max_size = u32::from_le_bytes(hdr[0x0c..0x10].try_into().unwrap());
+        let table = u64::from_le_bytes(hdr[0x10..0x18].try_into().unwrap());
+        Some(Self {
+            version: (hdr[0x07], hdr[0x08]),
+            table: core::slice::from_raw_parts(
+                table as *const u8, max_size as usize
+            ),
+        })
+    }
+
+    /// Parse a 32-bit ('_SM_') entry point.
+    unsafe fn from_entry2(addr: u64) -> Option<Self> {
+        let ptr = addr as *const u8;
+        let hdr = core::slice::from_raw_parts(ptr, 0x1f);
+        if &hdr[0..4] != b"_SM_" || &hdr[0x10..0x15] != b"_DMI_" {
+            return None;
+        }
+        let len = hdr[0x05] as usize;
+        if len < 0x1f || !Self::checksum_ok(core::slice::from_raw_parts(ptr, len)) {
+            return None;
+        }
+        let size = u16::from_le_bytes(hdr[0x16..0x18].try_into().unwrap());
+        let table = u32::from_le_bytes(hdr[0x18..0x1c].try_into().unwrap());
+        Some(Self {
+            version: (hdr[0x06], hdr[0x07]),
+            table: core::slice::from_raw_parts(
+                table as *const u8, size as usize
+            ),
+        })
+    }
+
+    /// Return the SMBIOS version (major, minor).
+    pub fn version(&self) -> (u8, u8) {
+        self.version
+    }
+
+    /// Return an iterator over all structures in the table.
+    pub fn structures(&self) -> StructureIter<'static> {
+        StructureIter { data: self.table }
+    }
+
+    /// Return the first structure with the given type.
+    pub fn find(&self, ty: u8) -> Option<Structure<'static>> {
+        self.structures().find(|s| s.ty == ty)
+    }
+
+    pub fn bios(&self) -> Option<BiosInfo> {
+        let s = self.find(Self::TYPE_BIOS)?;
+        Some(BiosInfo {
+            vendor: s.string(0x04),
+            version: s.string(0x05),
+            release_date: s.string(0x08),
+        })
+    }
+
+    pub fn system(&self) -> Option<SystemInfo> {
+        let s = self.find(Self::TYPE_SYSTEM)?;
+        let uuid = s.bytes(0x08, 16).map(|b| b.try_into().unwrap());
+        Some(SystemInfo {
+            manufacturer: s.string(0x04),
+            product: s.string(0x05),
+            version: s.string(0x06),
+            serial: s.string(0x07),
+            uuid,
+        })
+    }
+
+    pub fn board(&self) -> Option<BoardInfo> {
+        let s = self.find(Self::TYPE_BOARD)?;
+        Some(BoardInfo {
+            manufacturer: s.string(0x04),
+            product: s.string(0x05),
+            version: s.string(0x06),
+            serial: s.string(0x07),
+        })
+    }
+
+    /// Return an iterator over all memory devices.
+    pub fn memory_devices(&self) -> impl Iterator<Item = MemoryDevice> {
+        self.structures()
+            .filter(|s| s.ty == Self::TYPE_MEMORY_DEVICE)
+            .map(|s| MemoryDevice::from_structure(&s))
+    }
+
+    /// Print a summary of the platform.
+    pub fn dump(&self) {
+        println!("[*] SMBIOS {}.{}:", self.version.0, self.version.1);
+        if let Some(bios) = self.bios() {
+            println!("  BIOS:   {} {} ({})",
+                bios.vendor, bios.version, bios.release_date
+            );
+        }
+        if let Some(sys) = self.system() {
+            println!("  System: {} {} {} (serial '{}')",
+                sys.manufacturer, sys.product, sys.version, sys.serial
+            );
+            if let Some(uuid) = sys.uuid {
+                println!("  UUID:   {:02x?}", uuid);
+            }
+        }
+        if let Some(board) = self.board() {
+            println!("  Board:  {} {} {} (serial '{}')",
+                board.manufacturer, board.product, board.version, board.serial
+            );
+        }
+        for dev in self.memory_devices() {
+            // Skip empty slots
+            if dev.size_mib == Some(0) {
+                continue;
+            }
+            println!("  Memory: {} {} {:?}MiB {:?}MT/s {} {}",
+                dev.locator, dev.bank, dev.size_mib, dev.speed,
+                dev.manufacturer, dev.part_number
+            );
+        }
+    }
+}
+
+/// Iterator over structures in the SMBIOS structure table.
+pub struct StructureIter<'a> {
+    data: &'a [u8],
+}
+impl<'a> Iterator for StructureIter<'a> {
+    type Item = Structure<'a>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.len() < 4 {
+            return None;
+        }
+        let ty = self.data[0];
+        let len = self.data[1] as usize;
+        let handle = u16::from_le_bytes([self.data[2], self.data[3]]);
+        if len < 4 || len > self.data.len() || ty == Smbios::TYPE_END {
+            return None;
+        }
+
+        // The string-set ends with two NUL bytes
+        let rest = &self.data[len..];
+        let strings_len = rest.windows(2).position(|w| w == [0, 0])?;
+        let res = Structure {
+            ty,
+            handle,
+            data: &self.data[..len],
+            strings: &rest[..strings_len],
+        };
+        self.data = &rest[strings_len + 2..];
+        Some(res)
+    }
+}
+
+/// A single SMBIOS structure.
+pub struct Structure<'a> {
+    /// Structure type
+    pub ty: u8,
+    /// Structure handle
+    pub handle: u16,
+    /// The formatted area (including the header)
+    data: &'a [u8],
+    /// The string-set (without the trailing NUL bytes)
+    strings: &'a [u8],
+}
+impl<'a> Structure<'a> {
+    pub fn byte(&self, off: usize) -> Option<u8> {
+        self.data.get(off).copied()
+    }
+    pub fn word(&self, off: usize) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(off, 2)?.try_into().unwrap()))
+    }
+    pub fn dword(&self, off: usize) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(off, 4)?.try_into().unwrap()))
+    }
+    pub fn bytes(&self, off: usize, len: usize) -> Option<&'a [u8]> {
+        self.data.get(off..off + len)
+    }
+
+    /// Return the string referred to by the index at offset 'off'.
+    /// Returns an empty string if there is no string.
+    pub fn string(&self, off: usize) -> &'a str {
+        let idx = match self.byte(off) {
+            Some(idx) if idx != 0 => idx as usize,
+            _ => return "",
+        };
+        self.strings.split(|b| *b == 0)
+            .nth(idx - 1)
+            .and_then(|s| core::str::from_utf8(s).ok())
+            .unwrap_or("")
+    }
+}
+
+/// BIOS information (type 0).
+pub struct BiosInfo {
+    pub vendor: &'static str,
+    pub version: &'static str,
+    pub release_date: &'static str,
+}
+
+/// System information (type 1).
+pub struct SystemInfo {
+    pub manufacturer: &'static str,
+    pub product: &'static str,
+    pub version: &'static str,
+    pub serial: &'static str,
+    pub uuid: Option<[u8; 16]>,
+}
+
+/// Baseboard information (type 2).
+pub struct BoardInfo {
+    pub manufacturer: &'static str,
+    pub product: &'static str,
+    pub version: &'static str,
+    pub serial: &'static str,
+}
+
+/// Memory device (type 17).
+pub struct MemoryDevice {
+    /// Name of the slot (ie. "DIMM 0")
+    pub locator: &'static str,
+    /// Name of the bank (ie. "BANK 0")
+    pub bank: &'static str,
+    /// Size of the device (in MiB). Zero means the slot is empty.
+    pub size_mib: Option<u64>,
+    /// Memory type (ie. 0x1a for DDR4)
+    pub mem_type: Option<u8>,
+    /// Maximum speed (in MT/s)
+    pub speed: Option<u16>,
+    pub manufacturer: &'static str,
+    pub part_number: &'static str,
+}
+impl MemoryDevice {
+    fn from_structure(s: &Structure<'static>) -> Self {
+        let size_mib = match s.word(0x0c) {
+            None | Some(0xffff) => None,
+            // The real size is in the 'extended size' field
+            Some(0x7fff) => s.dword(0x1c).map(|x| (x & 0x7fff_ffff) as u64),
+            // Bit 15 is set when the size is in KiB
+            Some(x) if (x & 0x8000) != 0 => Some(((x & 0x7fff) as u64) / 1024),
+            Some(x) => Some(x as u64),
+        };
+        Self {
+            locator: s.string(0x10),
+            bank: s.string(0x11),
+            size_mib,
+            mem_type: s.byte(0x12),
+            speed: s.word(0x15).filter(|x| *x != 0 && *x != 0xffff),
+            manufacturer: s.string(0x17),
+            part_number: s.string(0x1a),
+        }
+    }
+}
@@ -1,66 +1,153 @@
+//! Bringing up application processors (APs).
+//!
+//! The bootstrap core starts each enabled AP in the MADT, one at a time:
+//!
+//! - Allocate a stack and local storage ([`Tls`]) for the AP
+//! - Write the trampoline parameters (see `src/trampoline.rs`) with the
+//!   stack, and a pointer to local storage as the argument to [`ap_entry`]
+//! - Send the INIT-SIPI-SIPI sequence
+//! - Wait for the AP to mark its local storage as initialized (after it has
+//!   loaded its own GDT, TSS, and the IDT)
+//!
+//! APs that don't check in before [`AP_TIMEOUT_US`] are sent another INIT,
+//! so that they can't wake up later and use the trampoline while we're
+//! starting a different AP.
 
-use mrld::x86::apic::*;
-use mrld::physmem::*;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use mrld::x86::msr::Msr;
+use spin::Mutex;
+use crate::acpi::MrldAcpiManager;
 use crate::apic;
+use crate::gdt;
+use crate::interrupt;
+use crate::pit;
 use crate::println;
-use crate::trampoline;
+use crate::smpcall;
+use crate::tls::{ Tls, ThreadState };
+use crate::trampoline::{ Trampoline, TrampolineParams };
 
-unsafe extern "C" { 
-    #[link_name = "_trampoline_start_vaddr"]
-    pub static _TRAMPOLINE_START16: u64;
-    #[link_name = "_trampoline_end_vaddr"]
-    pub static _TRAMPOLINE_END: u64;
-}
+/// How long to wait for each AP to check in (in microseconds)
+pub const AP_TIMEOUT_US: u64 = 100_000;
 
-pub struct Smp;
-impl Smp { 
+/// How often to check whether an AP has checked in (in microseconds)
+const AP_POLL_US: u64 = 100;
+
+/// Size of the stack for each AP
+const AP_STACK_SIZE: usize = 0x1_0000;
+
+/// APIC IDs of all cores that are online (including the bootstrap core)
+pub static ONLINE_CORES: Mutex<Vec<u32>> = Mutex::new(Vec::new());
 
-    // FIXME: Actually do this in a sane way
-    pub unsafe fn init() { 
-        use core::alloc::*;
+pub struct Smp;
+impl Smp {
+    /// Start all enabled APs, and wait for them to check in.
+    pub unsafe fn init(acpi: &MrldAcpiManager) {
         use alloc::alloc::alloc;
 
-        let stac_lo = alloc(
-            core::alloc::Layout::new::<[u8; 0x1000]>()
-            .align_to(0x1000).unwrap()
-        );
-        stac_lo.write_bytes(0, 0x1000);
-        let stac_hi = stac_lo.offset(0x1000);
+        let bsp_id = mrld::x86::cpuid(0xb, 0).edx;
+        ONLINE_CORES.lock().push(bsp_id);
 
-        trampoline::Trampoline::write(
-            ap_entry as _,
-            mrld::x86::CR3::read(),
-            stac_hi as u64 - 16,
+        let cr3 = mrld::x86::CR3::read();
+        let efer = Msr::rdmsr(Msr::EFER);
+        let ap_ids = acpi.application_processors();
+        let mut failed = Vec::new();
+
+        let tramp = Trampoline::new();
+        println!("[*] Starting {} APs (trampoline at {:#x}) ...",
+            ap_ids.len(), tramp.base()
         );
+        for apic_id in ap_ids.iter().copied() {
+            // We can only target 8-bit APIC IDs without x2APIC
+            if apic_id > 0xff && !apic::Lapic::is_x2apic() {
+                println!("[!] Can't start AP {} (needs x2APIC)", apic_id);
+                failed.push(apic_id);
+                continue;
+            }
+
+            let stack_lo = alloc(
+                Layout::from_size_align(AP_STACK_SIZE, 0x1000).unwrap()
+            );
+            stack_lo.write_bytes(0, AP_STACK_SIZE);
+            let stack_hi = stack_lo.add(AP_STACK_SIZE);
+            let tls = Tls::new(apic_id as _);
 
-        apic::Lapic::send_sipi(1);
+            // NOTE: The trampoline jumps into 'ap_entry' (instead of calling
+            // it), so offset the stack like there's a return address.
+            tramp.set_params(TrampolineParams {
+                temp_pml4: 0,
+                cr3,
+                efer,
+                entry: ap_entry as *const () as u64,
+                stack: stack_hi as u64 - 8,
+                arg: tls as u64,
+            });
 
-        // FIXME: Wait around for APs to check back in
-        unsafe { 
-            loop { mrld::x86::pause(); }
+            if !apic::Lapic::start_ap(apic_id, tramp.base()) {
+                println!("[!] Couldn't deliver INIT/SIPI to AP {}", apic_id);
+                failed.push(apic_id);
+                continue;
+            }
+
+            if Self::wait_for_checkin(&*tls) {
+                ONLINE_CORES.lock().push(apic_id);
+            } else {
+                println!("[!] AP {} didn't check in after {}us",
+                    apic_id, AP_TIMEOUT_US
+                );
+                apic::Lapic::send_init(apic_id);
+                failed.push(apic_id);
+            }
         }
 
+        let online = ONLINE_CORES.lock();
+        println!("[*] {}/{} cores online: {:?}",
+            online.len(), ap_ids.len() + 1, &*online
+        );
+        if !failed.is_empty() {
+            println!("[!] APs that failed to start: {:?}", failed);
+        }
+    }
 
+    /// Wait for an AP to mark its local storage as initialized.
+    fn wait_for_checkin(tls: &Tls) -> bool {
+        for _ in 0..(AP_TIMEOUT_US / AP_POLL_US) {
+            if tls.state() == ThreadState::Init {
+                return true;
+            }
+            pit::udelay(AP_POLL_US);
+        }
+        tls.state() == ThreadState::Init
     }
 }
 
 
-// NOTE: APs enter this from the trampoline in 64-bit mode with paging. 
+// NOTE: APs enter this from the trampoline in 64-bit mode with paging,
+// with a pointer to local storage (allocated by the bootstrap core).
 //
 // FIXME:
-// - There's no IDT
-// - There's some provisional GDT
 // - We're using PML4 from the bootstrap core
 // - Actually do something
 //
-pub unsafe fn ap_entry() -> ! { 
-    use crate::tls::*;
+pub extern "sysv64" fn ap_entry(tls: *mut Tls) -> ! {
     let apic_id = mrld::x86::cpuid(0xb, 0).edx;
-    println!("HELO from AP {}", apic_id);
+    unsafe {
+        Tls::install(tls);
+
+        // Replace the trampoline GDT with our own GDT and TSS, then use the
+        // same IDT as the bootstrap core
+        gdt::CoreTables::init();
+        interrupt::IdtManager::load();
+        apic::Lapic::init_ap();
+        smpcall::enable_core();
+    }
+    println!("[*] HELO from AP {}", apic_id);
 
-    Tls::init(apic_id as _);
+    // Let the bootstrap core know that we're alive
+    Tls::as_ref().set_state(ThreadState::Init);
 
-    unsafe { 
-        loop { mrld::x86::pause(); }
+    // Wait for cross-core calls (see `src/smpcall.rs`)
+    loop {
+        unsafe { core::arch::asm!("sti", "hlt"); }
     }
 }
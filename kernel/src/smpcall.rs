@@ -0,0 +1,272 @@
+//! Cross-core function calls.
+//!
+//! Run a closure on one core, a set of cores, or all online cores (see
+//! [`crate::smp::ONLINE_CORES`]):
+//!
+//! ```ignore
+//! // Wait for every core to finish
+//! smpcall::call_blocking(CallTarget::All, || { ... })?;
+//!
+//! // Or, keep going and check for completion later
+//! let handle = smpcall::call(CallTarget::Core(1), || { ... })?;
+//! ...
+//! handle.wait();
+//! ```
+//!
+//! Each core has a queue of pending calls (claimed in [`enable_core`]). The
+//! caller pushes a request onto the queue for each target, then sends a fixed
+//! IPI ([`CALL_VECTOR`]) with the local APIC. The interrupt handler on the
+//! target drains its queue. If the caller is also a target, it runs the
+//! closure itself after sending the IPIs to everyone else.
+//!
+//! Targets only run calls when they have interrupts enabled. APs idle with
+//! interrupts enabled (see `src/smp.rs`), but the bootstrap core doesn't.
+//! Calls run in interrupt context, so they shouldn't wait on anything held
+//! by code that they might have interrupted.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{ AtomicU32, AtomicUsize, Ordering };
+use mrld::x86::idt::InterruptStackFrame;
+use spin::Mutex;
+
+use crate::apic;
+use crate::interrupt::IdtManager;
+use crate::smp::ONLINE_CORES;
+
+/// Vector for cross-core call IPIs
+pub const CALL_VECTOR: u8 = 0xf0;
+
+/// Vector for spurious interrupts from the local APIC
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Maximum number of cores
+const MAX_CORES: usize = 256;
+
+/// Marks a queue that doesn't belong to any core
+const NO_CORE: u32 = u32::MAX;
+
+/// A pending call (shared by all of the targets).
+struct CallRequest {
+    func: Box<dyn Fn() + Send + Sync>,
+    /// Number of targets that haven't finished running 'func'
+    remaining: AtomicUsize,
+}
+impl CallRequest {
+    fn run(&self) {
+        (self.func)();
+        self.remaining.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Queues of pending calls for each core
+static QUEUES: [Mutex<VecDeque<Arc<CallRequest>>>; MAX_CORES] =
+    [const { Mutex::new(VecDeque::new()) }; MAX_CORES];
+
+/// APIC ID of the core that owns each queue in [`QUEUES`].
+///
+/// NOTE: With x2APIC, APIC IDs are 32-bit (and not necessarily contiguous),
+/// so they can't be used to index the queues directly.
+static QUEUE_OWNERS: [AtomicU32; MAX_CORES] =
+    [const { AtomicU32::new(NO_CORE) }; MAX_CORES];
+
+/// Return the queue for the core with the given APIC ID (if it has one).
+fn queue(apic_id: u32) -> Option<&'static Mutex<VecDeque<Arc<CallRequest>>>> {
+    QUEUE_OWNERS.iter()
+        .position(|owner| owner.load(Ordering::Acquire) == apic_id)
+        .map(|idx| &QUEUES[idx])
+}
+
+/// The cores that should run a call.
+#[derive(Clone, Copy, Debug)]
+pub enum CallTarget<'a> {
+    /// A single core (by APIC ID)
+    Core(u32),
+    /// A set of cores (by APIC ID)
+    Cores(&'a [u32]),
+    /// All online cores (including this one)
+    All,
+    /// All online cores (except this one)
+    Others,
+}
+
+#[derive(Debug)]
+pub enum CallError {
+    /// The target core isn't online (nothing was queued)
+    Offline(u32),
+    /// The IPIs to some of the targets weren't delivered.
+    ///
+    /// The call was withdrawn from those cores, but the other targets still
+    /// run it (use the handle to wait for them).
+    Delivery { failed: Vec<u32>, handle: CallHandle },
+}
+impl core::fmt::Display for CallError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Offline(id) => write!(f, "core {} isn't online", id),
+            Self::Delivery { failed, .. } => {
+                write!(f, "couldn't send an IPI to core(s) {:?}", failed)
+            },
+        }
+    }
+}
+
+/// Handle for checking whether a call has completed on every target.
+pub struct CallHandle(Arc<CallRequest>);
+impl core::fmt::Debug for CallHandle {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("CallHandle").field("pending", &self.pending()).finish()
+    }
+}
+impl CallHandle {
+    /// Return the number of targets that haven't finished the call.
+    pub fn pending(&self) -> usize {
+        self.0.remaining.load(Ordering::Acquire)
+    }
+
+    /// Return true if every target has finished the call.
+    pub fn is_done(&self) -> bool {
+        self.pending() == 0
+    }
+
+    /// Wait for every target to finish the call.
+    pub fn wait(&self) {
+        while !self.is_done() {
+            mrld::x86::pause();
+        }
+    }
+}
+
+/// Install the IPI handlers, and enable the local APIC on the bootstrap
+/// core. This must be called before starting APs.
+pub unsafe fn init() {
+    IdtManager::set_handler(CALL_VECTOR, call_handler);
+    IdtManager::set_handler(SPURIOUS_VECTOR, spurious_handler);
+    enable_core();
+}
+
+/// Claim a queue for this core, and enable the local APIC (so that it
+/// accepts call IPIs).
+pub unsafe fn enable_core() {
+    let id = mrld::x86::cpuid(0xb, 0).edx;
+    if queue(id).is_none() {
+        let claimed = QUEUE_OWNERS.iter().any(|owner| {
+            owner.compare_exchange(NO_CORE, id,
+                Ordering::AcqRel, Ordering::Acquire
+            ).is_ok()
+        });
+        if !claimed {
+            panic!("No call queue for core {} (more than {} cores?)",
+                id, MAX_CORES
+            );
+        }
+    }
+    apic::Lapic::enable(SPURIOUS_VECTOR);
+}
+
+/// Run 'func' on the target cores without waiting for it to finish.
+///
+/// If some of the IPIs can't be delivered, this returns
+/// [`CallError::Delivery`] with a handle for the cores that do run the call.
+pub fn call<F>(target: CallTarget, func: F) -> Result<CallHandle, CallError>
+    where F: Fn() + Send + Sync + 'static
+{
+    let self_id = mrld::x86::cpuid(0xb, 0).edx;
+    let online = ONLINE_CORES.lock().clone();
+    let mut targets: Vec<u32> = match target {
+        CallTarget::Core(id) => alloc::vec![id],
+        CallTarget::Cores(ids) => ids.to_vec(),
+        CallTarget::All => online.clone(),
+        CallTarget::Others => online.iter().copied()
+            .filter(|id| *id != self_id)
+            .collect(),
+    };
+    targets.sort_unstable();
+    targets.dedup();
+    if let Some(id) = targets.iter().find(|id| !online.contains(id)) {
+        return Err(CallError::Offline(*id));
+    }
+
+    // Find the queues before queueing anything, so that we don't leave
+    // the call behind on some of the targets
+    let run_here = targets.contains(&self_id);
+    let mut queues = Vec::with_capacity(targets.len());
+    for id in targets.iter().copied().filter(|id| *id != self_id) {
+        let Some(queue) = queue(id) else {
+            return Err(CallError::Offline(id));
+        };
+        queues.push((id, queue));
+    }
+
+    let req = Arc::new(CallRequest {
+        func: Box::new(func),
+        remaining: AtomicUsize::new(targets.len()),
+    });
+    for (_, queue) in queues.iter() {
+        queue.lock().push_back(req.clone());
+    }
+
+    // Signal every target. If an IPI isn't delivered, take the call back
+    // out of that queue (unless another IPI already drained it, in which
+    // case the target runs it anyway).
+    let mut failed = Vec::new();
+    for (id, queue) in queues.iter() {
+        if unsafe { apic::Lapic::send_fixed(*id, CALL_VECTOR) } {
+            continue;
+        }
+        let mut queue = queue.lock();
+        if let Some(idx) = queue.iter().position(|r| Arc::ptr_eq(r, &req)) {
+            queue.remove(idx);
+            req.remaining.fetch_sub(1, Ordering::Release);
+            failed.push(*id);
+        }
+    }
+
+    if run_here {
+        req.run();
+    }
+    if !failed.is_empty() {
+        return Err(CallError::Delivery { failed, handle: CallHandle(req) });
+    }
+    Ok(CallHandle(req))
+}
+
+/// Run 'func' on the target cores, and wait for all of them to finish.
+///
+/// When some of the IPIs can't be delivered, this still waits for the other
+/// targets before returning the error.
+pub fn call_blocking<F>(target: CallTarget, func: F) -> Result<(), CallError>
+    where F: Fn() + Send + Sync + 'static
+{
+    match call(target, func) {
+        Ok(handle) => {
+            handle.wait();
+            Ok(())
+        },
+        Err(CallError::Delivery { failed, handle }) => {
+            handle.wait();
+            Err(CallError::Delivery { failed, handle })
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Run all of the pending calls for this core.
+unsafe extern "x86-interrupt" fn call_handler(_f: InterruptStackFrame) {
+    let id = mrld::x86::cpuid(0xb, 0).edx;
+    if let Some(queue) = queue(id) {
+        loop {
+            let Some(req) = queue.lock().pop_front() else {
+                break;
+            };
+            req.run();
+        }
+    }
+    apic::Lapic::eoi();
+}
+
+/// Spurious interrupts don't need an EOI.
+unsafe extern "x86-interrupt" fn spurious_handler(_f: InterruptStackFrame) {
+}
@@ -28,7 +28,8 @@ use mrld::x86::segment::{SegmentSelector, PrivilegeLevel};
 
 /// Kernel entrypoint. 
 ///
-/// The bootloader jumps here. 
+/// The bootloader jumps here. When booting directly with PVH, we enter
+/// from `pvh_main()` in `src/pvh.rs` instead.
 ///
 /// Note that page tables should have already been configured by the time
 /// we've entered this function. 
@@ -63,6 +64,9 @@ pub extern "sysv64" fn _start(args: *const MrldBootArgs) -> ! { unsafe {
         movabs rsp, offset _kernel_stack_hi
         sub rsp, 4096
 
+        // Clear the frame pointer (this terminates backtraces)
+        xor ebp, ebp
+
         // Jump into the kernel - execution continues in 'src/main.rs'
         call {main}
 
@@ -79,10 +83,12 @@ pub extern "sysv64" fn _start(args: *const MrldBootArgs) -> ! { unsafe {
 } }
 
 
-const KERNEL_TEXT_DESC: Descriptor = Descriptor::new(
+/// Kernel code descriptor (also used by the PVH entrypoint)
+pub const KERNEL_TEXT_DESC: Descriptor = Descriptor::new(
     0x0000_0000, PrivilegeLevel::Ring0, 0xffff, DFlags::CODE
 );
-const KERNEL_DATA_DESC: Descriptor = Descriptor::new(
+/// Kernel data descriptor (also used by the PVH entrypoint)
+pub const KERNEL_DATA_DESC: Descriptor = Descriptor::new(
     0x0000_0000, PrivilegeLevel::Ring0, 0xffff, DFlags::DATA
 );
 
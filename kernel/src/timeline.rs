@@ -0,0 +1,55 @@
+//! Boot timeline.
+//!
+//! The bootloader records a TSC timestamp at the end of each of its stages
+//! (see [`mrld::MrldBootTimeline`]). We keep a copy of the timeline, append
+//! timestamps for each stage of kernel initialization, and print the whole
+//! thing once we're done booting.
+
+use mrld::{ MrldBootArgs, MrldBootTimeline };
+use spin::Mutex;
+use crate::println;
+
+/// The boot timeline (from the bootloader, plus our own stages).
+pub static TIMELINE: Mutex<MrldBootTimeline> =
+    Mutex::new(MrldBootTimeline::new_empty());
+
+/// Copy the timeline from the bootloader, and record the kernel entry
+/// (at 'entry_tsc').
+pub fn init(args: &MrldBootArgs, entry_tsc: u64) {
+    let mut timeline = TIMELINE.lock();
+    *timeline = args.timeline;
+    timeline.record_at("kernel_main", entry_tsc);
+}
+
+/// Record the end of a kernel initialization stage.
+pub fn record(name: &str) {
+    TIMELINE.lock().record(name);
+}
+
+/// Print the boot timeline.
+///
+/// Each line has the time since the bootloader was entered, and the time
+/// since the previous stage (in microseconds, or in TSC ticks if the
+/// frequency is unknown).
+pub fn dump() {
+    let timeline = TIMELINE.lock();
+    let stages = timeline.stages();
+    let Some(first) = stages.first() else {
+        return;
+    };
+    let unit = if timeline.tsc_freq != 0 { "us" } else { "ticks" };
+    println!("[*] Boot timeline (TSC at {}MHz):",
+        timeline.tsc_freq / 1_000_000
+    );
+    let mut prev = first.tsc;
+    for stage in stages {
+        let total = stage.tsc.wrapping_sub(first.tsc);
+        let delta = stage.tsc.wrapping_sub(prev);
+        prev = stage.tsc;
+        println!("  {:>12}{} (+{:>10}{}) {}",
+            timeline.ticks_to_us(total).unwrap_or(total), unit,
+            timeline.ticks_to_us(delta).unwrap_or(delta), unit,
+            stage.name()
+        );
+    }
+}
@@ -2,6 +2,7 @@
 use mrld::x86::msr::*;
 use mrld::x86::segment::GS;
 use core::alloc::*;
+use core::sync::atomic::{ AtomicUsize, Ordering };
 use crate::mm;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -16,19 +17,32 @@ pub enum ThreadState {
 pub struct Tls { 
     _p: *const Self,
     core_id: usize,
-    state: ThreadState,
+    /// A [`ThreadState`] (this is read by other cores, see `src/smp.rs`)
+    state: AtomicUsize,
 }
 impl Tls { 
     /// Initialize local storage for this hardware thread. 
     pub unsafe fn init(core_id: usize) { 
+        let ptr = Self::new(core_id);
+        Self::install(ptr);
+        (*ptr).set_state(ThreadState::Init);
+    }
+
+    /// Allocate local storage for a hardware thread (in the uninitialized
+    /// state). The bootstrap core uses this to prepare storage for APs. 
+    pub unsafe fn new(core_id: usize) -> *mut Self { 
         // Allocate some backing memory
         let ptr: *mut Self = mm::HEAP.alloc(Layout::new::<Self>()) as _;
+        ptr.write(Self { 
+            _p: ptr,
+            core_id,
+            state: AtomicUsize::new(ThreadState::Uninit as usize),
+        });
+        ptr
+    }
 
-        let mut tls = ptr.as_mut_unchecked();
-        tls._p = ptr;
-        tls.state = ThreadState::Init;
-        tls.core_id = core_id;
-
+    /// Use local storage at 'ptr' for this hardware thread. 
+    pub unsafe fn install(ptr: *mut Self) { 
         // Let the GS segment point to local storage
         Msr::wrmsr(Msr::GS_BASE, ptr as _);
     }
@@ -38,8 +52,17 @@ impl Tls {
     pub fn is_bsp(&self) -> bool { 
         self.core_id == 0
     }
+    pub fn core_id(&self) -> usize { 
+        self.core_id
+    }
     pub fn state(&self) -> ThreadState { 
-        self.state
+        match self.state.load(Ordering::Acquire) { 
+            x if x == ThreadState::Init as usize => ThreadState::Init,
+            _ => ThreadState::Uninit,
+        }
+    }
+    pub fn set_state(&self, state: ThreadState) { 
+        self.state.store(state as usize, Ordering::Release);
     }
 }
 
@@ -1,90 +1,256 @@
 //! Trampoline used to boot APs starting in real mode.
 //!
-//! The trampoline code is assembled and linked seperately. 
-//! See `kernel/src/trampoline.S`, `trampoline.ld`, and `kernel/build.rs` for 
-//! important details about all of this. 
-//!
 //! Notes
 //! =====
 //!
-//! An AP begins fetching instructions at '_trampoline_start' after receiving 
-//! a startup IPI (SIPI) from the bootstrap core. The 8-bit vector associated 
-//! with the SIPI becomes the index of a 4KiB page in the low 1MiB of physical 
-//! memory (from `0x0_0000` to `0xf_f000`). I *think* this immediately changes
-//! the CS segment, and the instruction pointer is set to zero. 
+//! An AP begins fetching instructions at '_trampoline_start' after receiving
+//! a startup IPI (SIPI) from the bootstrap core. The 8-bit vector associated
+//! with the SIPI becomes the index of a 4KiB page in the low 1MiB of physical
+//! memory (from `0x0_0000` to `0xf_f000`). The CS segment base is set to the
+//! address of the page, and the instruction pointer is set to zero.
+//!
+//! The trampoline code is part of the kernel image (see the `global_asm!`
+//! below), and it's copied into a page allocated below 1MiB at runtime.
+//! It only uses addresses relative to the start of that page, so it doesn't
+//! matter where the page is.
 //!
-//! In our case, we assume this code will always be located at `0x8000`.
-//! This is baked into the linkerscript. 
-//! The length of the resulting binary is padded to 4096 bytes with `objdump`. 
+//! Before the SIPI, the bootstrap core writes [`TrampolineParams`] for each
+//! AP into the trampoline page. The AP:
+//!
+//! - Switches into protected mode with a provisional GDT
+//! - Enables long mode with a temporary PML4 (in the pages right after the
+//!   trampoline, since CR3 is only 32-bit here) that identity-maps the low
+//!   2MiB of physical memory
+//! - Switches into a 64-bit code segment, and then switches to the real CR3
+//!   (which also needs to identity-map the trampoline)
+//! - Jumps to the entrypoint with the stack pointer and argument from
+//!   [`TrampolineParams`]
 //!
 //! Debugging
 //! =========
 //!
-//! tl;dr GOOD LUCK, it's basically impossible to use QEMU's GDB stub for 
+//! tl;dr GOOD LUCK, it's basically impossible to use QEMU's GDB stub for
 //! looking at code across different x86 operating modes. Instead of debugging,
 //! consider immediately writing the bugfree code and not making any mistakes.
 //!
-//! After having trouble with GDB, I had a little bit of success gleaning 
-//! information from KVM and ftrace, ie. 
+//! After having trouble with GDB, I had a little bit of success gleaning
+//! information from KVM and ftrace, ie.
 //!
 //! ```shell
 //! $ echo 1 > /sys/kernel/tracing/events/kvm/enable
 //! $ cat /sys/kernel/tracing/trace_pipe
 //! ```
 
-/// 32-byte metadata used by the trampoline 
+use core::mem::offset_of;
+use mrld::paging::PageSize;
+use mrld::physmem::{ MrldMemoryDesc, MrldMemoryKind };
+use crate::physmem::MEMORY_MAP;
+
+/// Parameters for a single AP (written by the bootstrap core).
 #[repr(C)]
-pub struct TrampolineHeader { 
-    magic: u32,
-    pml4: u32,
-    entry: u64,
-    stack_base: u64,
+#[derive(Clone, Copy, Debug)]
+pub struct TrampolineParams {
+    /// Physical address of the temporary PML4 (filled in by [`Trampoline`])
+    pub temp_pml4: u64,
+    /// Value of CR3 to use in 64-bit mode
+    pub cr3: u64,
+    /// Value of the EFER MSR (this must enable long mode, LMA is ignored)
+    pub efer: u64,
+    /// Address of the entrypoint
+    pub entry: u64,
+    /// Initial stack pointer
+    pub stack: u64,
+    /// Passed to the entrypoint as the first argument (in `rdi`)
+    pub arg: u64,
 }
-impl TrampolineHeader { 
-    pub const MAGIC: u32 = 0xb007c0de;
-    pub const fn new(entry: u64, pml4: u32, stack_base: u64) -> Self { 
-        Self { 
-            magic: 0xb007c0de,
-            pml4,
-            entry,
-            stack_base,
-        }
-    }
+
+/// The trampoline (and temporary page tables) in low memory.
+pub struct Trampoline {
+    desc: MrldMemoryDesc,
 }
+impl Trampoline {
+    /// Offset of [`TrampolineParams`] in the trampoline
+    const PARAMS_OFF: u64 = 0x10;
 
-#[repr(C)]
-pub struct Trampoline;
-impl Trampoline { 
-    /// Fixed physical address of the trampoline
-    pub const PHYS_BASE: u64 = 0x8000;
+    /// Number of pages: the trampoline, and the temporary PML4/PDPT/PD
+    const NUM_PAGES: usize = 4;
 
-    /// Fixed offset to [`TrampolineHeader`] 
-    pub const HDR_OFF: isize = 0x200;
+    /// Trampoline pages must be addressable in real mode
+    const MAX_ADDR: u64 = 0x10_0000;
 
-    pub const DATA: &'static [u8; 0x1000] = 
-        include_bytes!("../../target/mrld-kernel/trampoline.bin");
+    /// Allocate pages for the trampoline, then write the trampoline and
+    /// the temporary page tables.
+    pub unsafe fn new() -> Self {
+        let Some(desc) = MEMORY_MAP.lock().allocate_below(
+            Self::MAX_ADDR,
+            PageSize::Size4KiB,
+            Self::NUM_PAGES,
+            MrldMemoryKind::Trampoline,
+        ) else {
+            panic!("Couldn't reserve physical memory for the AP trampoline?");
+        };
 
-    pub fn as_ptr() -> *const u8 { 
-        Self::DATA.as_ptr()
+        let base = desc.start();
+        let tgt = base as *mut u8;
+        tgt.write_bytes(0, Self::NUM_PAGES * 0x1000);
+
+        let src = &raw const _trampoline_start;
+        let len = (&raw const _trampoline_end).offset_from(src) as usize;
+        assert!(len <= 0x1000, "Trampoline is larger than a page?");
+        tgt.copy_from_nonoverlapping(src, len);
+
+        // Identity-map the low 2MiB with a single large page
+        let pml4 = (base + 0x1000) as *mut u64;
+        let pdpt = (base + 0x2000) as *mut u64;
+        let pd   = (base + 0x3000) as *mut u64;
+        pml4.write_volatile((pdpt as u64) | 0b11);
+        pdpt.write_volatile((pd as u64) | 0b11);
+        pd.write_volatile(0x0000_0000 | 0b1000_0011);
+
+        Self { desc }
     }
 
-    /// Write the trampoline binary into physical memory.
-    pub unsafe fn write(entry: u64, pml4: u64, stack_base: u64) { 
-        let tgt = (Self::PHYS_BASE as *mut u8);
-        let src = Self::DATA.as_ptr();
-        tgt.copy_from_nonoverlapping(src, Self::DATA.len());
-
-        // FIXME: For now, I think we need the physical address of PML4
-        // to be a 32-bit address... 
-        assert!(pml4 & 0xffff_ffff_0000_0000 == 0);
-
-        let hdr = TrampolineHeader::new(
-            entry,
-            pml4 as _,
-            stack_base,
-        );
-        let tgt_hdr = (tgt.offset(Self::HDR_OFF) as *mut TrampolineHeader);
-        tgt_hdr.write_volatile(hdr);
+    /// Physical address of the trampoline (used as the SIPI vector).
+    pub fn base(&self) -> u64 {
+        self.desc.start()
     }
+
+    /// Write the parameters for the next AP.
+    pub unsafe fn set_params(&self, params: TrampolineParams) {
+        let ptr = (self.base() + Self::PARAMS_OFF) as *mut TrampolineParams;
+        ptr.write_volatile(TrampolineParams {
+            temp_pml4: self.base() + 0x1000,
+            ..params
+        });
+    }
+}
+
+unsafe extern "C" {
+    static _trampoline_start: u8;
+    static _trampoline_end: u8;
 }
 
+// NOTE: Everything here is addressed relative to '_trampoline_start', so
+// the assembler resolves it without any relocations.
+core::arch::global_asm!(r#"
+.section .text.trampoline, "ax", @progbits
+.code16
+.global _trampoline_start
+_trampoline_start:
+    cli
+    jmp _trampoline_16
+
+// NOTE: This fails to assemble if the code above grows past the params
+.org {params_off}
+_trampoline_params:
+    .skip {params_size}
+
+.balign 16
+_trampoline_gdt:
+    .quad 0x0000000000000000
+    .quad 0x00cf9a000000ffff
+    .quad 0x00cf92000000ffff
+    .quad 0x00af9a000000ffff
+_trampoline_gdtr:
+    .word (_trampoline_gdtr - _trampoline_gdt - 1)
+    .long 0
+_trampoline_far32:
+    .long 0
+    .word 0x08
+_trampoline_far64:
+    .long 0
+    .word 0x18
+
+.set T_PARAMS,  _trampoline_params - _trampoline_start
+.set T_GDT,     _trampoline_gdt - _trampoline_start
+.set T_GDTR,    _trampoline_gdtr - _trampoline_start
+.set T_FAR32,   _trampoline_far32 - _trampoline_start
+.set T_FAR64,   _trampoline_far64 - _trampoline_start
+.set P_PML4,    T_PARAMS + {off_pml4}
+.set P_CR3,     T_PARAMS + {off_cr3}
+.set P_EFER,    T_PARAMS + {off_efer}
+.set P_ENTRY,   T_PARAMS + {off_entry}
+.set P_STACK,   T_PARAMS + {off_stack}
+.set P_ARG,     T_PARAMS + {off_arg}
+
+_trampoline_16:
+    // Address everything relative to CS, and keep the physical address of
+    // the trampoline in EBX
+    mov ax, cs
+    mov ds, ax
+    xor ebx, ebx
+    mov bx, ax
+    shl ebx, 4
+
+    // Fill in the physical addresses of the GDT and the far pointers
+    lea eax, [ebx + T_GDT]
+    mov dword ptr [T_GDTR + 2], eax
+    lea eax, [ebx + T_32]
+    mov dword ptr [T_FAR32], eax
+    lea eax, [ebx + T_64]
+    mov dword ptr [T_FAR64], eax
+
+    // Load the provisional GDT, and enable protected mode
+    lgdt [T_GDTR]
+    mov eax, cr0
+    or eax, 1
+    mov cr0, eax
+    jmp fword ptr ds:[T_FAR32]
+
+.code32
+_trampoline_32:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov gs, ax
+    mov ss, ax
+
+    // Enable PAE (and global pages), and use the temporary PML4
+    mov eax, cr4
+    or eax, (1 << 5) | (1 << 7)
+    mov cr4, eax
+    mov eax, dword ptr [ebx + P_PML4]
+    mov cr3, eax
+
+    // Use the same EFER as the bootstrap core (which enables long mode),
+    // except for LMA (which is read-only, and set by the processor)
+    mov ecx, 0xc0000080
+    mov eax, dword ptr [ebx + P_EFER]
+    and eax, ~(1 << 10)
+    mov edx, dword ptr [ebx + P_EFER + 4]
+    wrmsr
+
+    // Enable paging (and write protection)
+    mov eax, cr0
+    or eax, (1 << 31) | (1 << 16)
+    mov cr0, eax
+
+    // Switch into the 64-bit code segment
+    jmp fword ptr [ebx + T_FAR64]
+
+.code64
+_trampoline_64:
+    // Switch to the real page tables, then jump into the kernel
+    mov ebx, ebx
+    mov rax, qword ptr [rbx + P_CR3]
+    mov cr3, rax
+    mov rsp, qword ptr [rbx + P_STACK]
+    mov rdi, qword ptr [rbx + P_ARG]
+    jmp qword ptr [rbx + P_ENTRY]
+
+.global _trampoline_end
+_trampoline_end:
+
+.set T_32,      _trampoline_32 - _trampoline_start
+.set T_64,      _trampoline_64 - _trampoline_start
+"#,
+params_off = const Trampoline::PARAMS_OFF,
+params_size = const size_of::<TrampolineParams>(),
+off_pml4 = const offset_of!(TrampolineParams, temp_pml4),
+off_cr3 = const offset_of!(TrampolineParams, cr3),
+off_efer = const offset_of!(TrampolineParams, efer),
+off_entry = const offset_of!(TrampolineParams, entry),
+off_stack = const offset_of!(TrampolineParams, stack),
+off_arg = const offset_of!(TrampolineParams, arg),
+);
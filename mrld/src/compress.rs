@@ -0,0 +1,238 @@
+//! Compressed kernel images.
+//!
+//! `cargo xtask build --compress <lz4|zstd>` publishes kernels in `pxe/`
+//! with a small header in front of the compressed data:
+//!
+//! ```text
+//! 0x00: magic ("MRLDCMP\0")
+//! 0x08: format (u32, 1 = LZ4 block, 2 = zstd frame)
+//! 0x0c: reserved (u32, zero)
+//! 0x10: size of the uncompressed kernel ELF (u64, in bytes)
+//! 0x18: compressed data
+//! ```
+//!
+//! An ELF never starts with the magic, so uncompressed kernels are detected
+//! automatically and loaded as-is.
+//!
+//! xtask writes the header with [`CompressedHeader::to_bytes`], and the
+//! bootloader reads it with [`CompressedHeader::parse`]. Decompression needs
+//! the `decompress` feature (which pulls in the LZ4 and zstd decoders).
+
+use core::fmt;
+#[cfg(feature = "decompress")]
+use ruzstd::decoding::FrameDecoder;
+
+/// Magic bytes at the start of a compressed kernel image
+pub const HEADER_MAGIC: [u8; 8] = *b"MRLDCMP\0";
+
+/// Size of the compressed image header (in bytes)
+pub const HEADER_SIZE: usize = 0x18;
+
+/// Largest uncompressed image we're willing to allocate for
+pub const MAX_IMAGE_SIZE: usize = 256 * 1024 * 1024;
+
+/// Compression formats
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// LZ4 block format
+    Lz4,
+    /// Zstandard frame format
+    Zstd,
+}
+impl Compression {
+    /// Return the format ID used in the header.
+    pub const fn id(&self) -> u32 {
+        match self {
+            Self::Lz4 => 1,
+            Self::Zstd => 2,
+        }
+    }
+
+    /// Return the format with the given ID (if there is one).
+    pub const fn from_id(id: u32) -> Option<Self> {
+        match id {
+            1 => Some(Self::Lz4),
+            2 => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+}
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Lz4 => write!(f, "lz4"),
+            Self::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// The header of a compressed kernel image.
+#[derive(Clone, Copy, Debug)]
+pub struct CompressedHeader {
+    /// Compression format
+    pub format: Compression,
+    /// Size of the uncompressed image (in bytes)
+    pub size: usize,
+}
+impl CompressedHeader {
+    /// Parse the header at the start of 'data'.
+    /// Returns `None` if the image isn't compressed.
+    pub fn parse(data: &[u8]) -> Result<Option<Self>, DecompressError> {
+        if !data.starts_with(&HEADER_MAGIC) {
+            return Ok(None);
+        }
+        if data.len() < HEADER_SIZE {
+            return Err(DecompressError::Header);
+        }
+        let format = u32::from_le_bytes(data[0x08..0x0c].try_into().unwrap());
+        let format = Compression::from_id(format)
+            .ok_or(DecompressError::Format(format))?;
+        let size = u64::from_le_bytes(data[0x10..0x18].try_into().unwrap());
+        let size = usize::try_from(size).map_err(|_| DecompressError::Header)?;
+        if size == 0 || size > MAX_IMAGE_SIZE {
+            return Err(DecompressError::Header);
+        }
+        Ok(Some(Self { format, size }))
+    }
+
+    /// Return the bytes of this header.
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut res = [0u8; HEADER_SIZE];
+        res[0x00..0x08].copy_from_slice(&HEADER_MAGIC);
+        res[0x08..0x0c].copy_from_slice(&self.format.id().to_le_bytes());
+        res[0x10..0x18].copy_from_slice(&(self.size as u64).to_le_bytes());
+        res
+    }
+
+    /// Decompress the image 'data' (including the header) into 'out', which
+    /// must be exactly [`CompressedHeader::size`] bytes.
+    #[cfg(feature = "decompress")]
+    pub fn decompress(&self, data: &[u8], out: &mut [u8])
+        -> Result<(), DecompressError>
+    {
+        let input = &data[HEADER_SIZE..];
+        let actual = match self.format {
+            Compression::Lz4 => {
+                lz4_flex::block::decompress_into(input, out)
+                    .map_err(|_| DecompressError::Corrupt(self.format))?
+            },
+            Compression::Zstd => {
+                FrameDecoder::new().decode_all(input, out)
+                    .map_err(|_| DecompressError::Corrupt(self.format))?
+            },
+        };
+        if actual != self.size {
+            return Err(DecompressError::Size {
+                expected: self.size, actual
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum DecompressError {
+    /// The header is truncated or has a bad size
+    Header,
+    /// Unknown compression format
+    Format(u32),
+    /// The compressed data is malformed
+    Corrupt(Compression),
+    /// The uncompressed size doesn't match the header
+    Size { expected: usize, actual: usize },
+}
+impl fmt::Display for DecompressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Header => write!(f, "malformed compressed image header"),
+            Self::Format(x) => write!(f, "unknown compression format {}", x),
+            Self::Corrupt(x) => write!(f, "corrupt {} data", x),
+            Self::Size { expected, actual } => {
+                write!(f, "decompressed to {}B (expected {}B)", actual, expected)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use std::vec::Vec;
+    use super::*;
+
+    /// Build a header (followed by a few bytes of "compressed data").
+    fn header(format: u32, size: u64) -> Vec<u8> {
+        let mut res = Vec::new();
+        res.extend_from_slice(&HEADER_MAGIC);
+        res.extend_from_slice(&format.to_le_bytes());
+        res.extend_from_slice(&0u32.to_le_bytes());
+        res.extend_from_slice(&size.to_le_bytes());
+        res.extend_from_slice(&[0xaa; 4]);
+        res
+    }
+
+    #[test]
+    fn parse_header() {
+        let hdr = CompressedHeader::parse(&header(1, 0x1234)).unwrap().unwrap();
+        assert_eq!(hdr.format, Compression::Lz4);
+        assert_eq!(hdr.size, 0x1234);
+
+        let hdr = CompressedHeader::parse(&header(2, MAX_IMAGE_SIZE as u64))
+            .unwrap().unwrap();
+        assert_eq!(hdr.format, Compression::Zstd);
+        assert_eq!(hdr.size, MAX_IMAGE_SIZE);
+    }
+
+    #[test]
+    fn header_to_bytes() {
+        for format in [Compression::Lz4, Compression::Zstd] {
+            let hdr = CompressedHeader { format, size: 0x1234 };
+            let bytes = hdr.to_bytes();
+            assert_eq!(bytes.as_slice(),
+                &header(format.id(), 0x1234)[..HEADER_SIZE]
+            );
+            let parsed = CompressedHeader::parse(&bytes).unwrap().unwrap();
+            assert_eq!(parsed.format, format);
+            assert_eq!(parsed.size, 0x1234);
+        }
+    }
+
+    #[test]
+    fn uncompressed_image() {
+        assert!(matches!(CompressedHeader::parse(b"\x7fELF\x02\x01\x01\0"),
+            Ok(None)
+        ));
+        assert!(matches!(CompressedHeader::parse(&[]), Ok(None)));
+        // Only part of the magic
+        assert!(matches!(CompressedHeader::parse(b"MRLDCMP"), Ok(None)));
+    }
+
+    #[test]
+    fn truncated_header() {
+        let data = header(1, 0x1234);
+        for len in HEADER_MAGIC.len()..HEADER_SIZE {
+            assert!(matches!(CompressedHeader::parse(&data[..len]),
+                Err(DecompressError::Header)
+            ));
+        }
+        assert!(CompressedHeader::parse(&data[..HEADER_SIZE]).is_ok());
+    }
+
+    #[test]
+    fn unknown_format() {
+        for format in [0, 3, u32::MAX] {
+            assert!(matches!(CompressedHeader::parse(&header(format, 1)),
+                Err(DecompressError::Format(x)) if x == format
+            ));
+        }
+    }
+
+    #[test]
+    fn bad_size() {
+        for size in [0, MAX_IMAGE_SIZE as u64 + 1, u64::MAX] {
+            assert!(matches!(CompressedHeader::parse(&header(1, size)),
+                Err(DecompressError::Header)
+            ));
+        }
+    }
+}
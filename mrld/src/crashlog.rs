@@ -0,0 +1,272 @@
+//! Persistent crash log.
+//!
+//! The kernel keeps a copy of its log output in a ring buffer at a fixed
+//! physical address (see [`CRASHLOG_PHYS_BASE`]). The contents of memory
+//! usually survive a warm reboot, so when the kernel panics (or hangs and
+//! someone resets the machine), the bootloader can find the log on the next
+//! boot and upload it to the host.
+//!
+//! The region starts with a [`CrashLogHeader`], followed by the ring buffer.
+//! The header records what the kernel was doing when the log was last
+//! written (see [`CrashLogState`]).
+//!
+//! NOTE: The bootloader reserves this region with the
+//! [`MrldMemoryKind::CrashLog`] memory type before doing anything else,
+//! so that neither firmware allocations nor the kernel can reuse it.
+//!
+//! [`MrldMemoryKind::CrashLog`]: crate::physmem::MrldMemoryKind::CrashLog
+
+use crate::MrldBuildId;
+
+/// Physical base address of the crash log region
+pub const CRASHLOG_PHYS_BASE: u64 = 0x0080_0000;
+
+/// Size of the crash log region (in bytes)
+pub const CRASHLOG_SIZE: usize = 0x0004_0000;
+
+/// What the kernel was doing when the crash log was last written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum CrashLogState {
+    /// Nothing has been written
+    Empty = 0,
+    /// The kernel was running (and never finished)
+    Running = 1,
+    /// The kernel panicked
+    Panic = 2,
+    /// The kernel shut down cleanly
+    Clean = 3,
+}
+impl CrashLogState {
+    pub fn from_u32(x: u32) -> Self {
+        match x {
+            1 => Self::Running,
+            2 => Self::Panic,
+            3 => Self::Clean,
+            _ => Self::Empty,
+        }
+    }
+
+    /// Returns 'true' if a log in this state should be uploaded.
+    pub fn is_crash(&self) -> bool {
+        matches!(self, Self::Running | Self::Panic)
+    }
+}
+
+/// Header at the start of the crash log region.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct CrashLogHeader {
+    /// Must be [`CrashLog::MAGIC`]
+    pub magic: u64,
+    /// Must be [`CrashLog::VERSION`]
+    pub version: u32,
+    /// See [`CrashLogState`]
+    pub state: u32,
+    /// Total number of bytes ever written to the ring buffer
+    pub written: u64,
+    /// Build ID of the kernel that wrote this log
+    pub build_id: MrldBuildId,
+}
+
+/// Helper for reading/writing the crash log region.
+///
+/// NOTE: This assumes that the region is identity-mapped.
+pub struct CrashLog {
+    ptr: *mut u8,
+}
+unsafe impl Send for CrashLog {}
+impl CrashLog {
+    pub const MAGIC: u64 = u64::from_le_bytes(*b"MRLDCLOG");
+    pub const VERSION: u32 = 1;
+
+    /// Offset of the ring buffer in the region
+    pub const DATA_OFFSET: usize =
+        size_of::<CrashLogHeader>().next_multiple_of(64);
+    /// Size of the ring buffer (in bytes)
+    pub const DATA_SIZE: usize = CRASHLOG_SIZE - Self::DATA_OFFSET;
+
+    /// Use the region at [`CRASHLOG_PHYS_BASE`].
+    ///
+    /// # Safety
+    ///
+    /// The region must be identity-mapped and writable, and nothing else may
+    /// use it (see [`MrldMemoryKind::CrashLog`]).
+    ///
+    /// [`MrldMemoryKind::CrashLog`]: crate::physmem::MrldMemoryKind::CrashLog
+    pub const unsafe fn new() -> Self {
+        Self::from_ptr(CRASHLOG_PHYS_BASE as *mut u8)
+    }
+
+    /// Use the region at 'ptr'.
+    ///
+    /// # Safety
+    ///
+    /// 'ptr' must be valid for reads and writes of [`CRASHLOG_SIZE`] bytes
+    /// for as long as the [`CrashLog`] is used, and aligned for
+    /// [`CrashLogHeader`]. Nothing else may access the region meanwhile.
+    pub const unsafe fn from_ptr(ptr: *mut u8) -> Self {
+        Self { ptr }
+    }
+
+    fn header_ptr(&self) -> *mut CrashLogHeader {
+        self.ptr as *mut CrashLogHeader
+    }
+
+    fn data_ptr(&self) -> *mut u8 {
+        unsafe { self.ptr.add(Self::DATA_OFFSET) }
+    }
+
+    /// Return a copy of the header.
+    pub fn header(&self) -> CrashLogHeader {
+        unsafe { self.header_ptr().read_volatile() }
+    }
+
+    /// Returns 'true' if the region contains a crash log.
+    pub fn is_valid(&self) -> bool {
+        let hdr = self.header();
+        hdr.magic == Self::MAGIC && hdr.version == Self::VERSION
+    }
+
+    /// Return the state of the log (or [`CrashLogState::Empty`] if the
+    /// region doesn't contain a log).
+    pub fn state(&self) -> CrashLogState {
+        if !self.is_valid() {
+            return CrashLogState::Empty;
+        }
+        CrashLogState::from_u32(self.header().state)
+    }
+
+    /// Start a new log.
+    pub fn init(&mut self, build_id: MrldBuildId) {
+        unsafe {
+            self.header_ptr().write_volatile(CrashLogHeader {
+                magic: Self::MAGIC,
+                version: Self::VERSION,
+                state: CrashLogState::Running as u32,
+                written: 0,
+                build_id,
+            });
+        }
+    }
+
+    /// Invalidate the log.
+    pub fn clear(&mut self) {
+        unsafe {
+            core::ptr::addr_of_mut!((*self.header_ptr()).magic)
+                .write_volatile(0);
+        }
+    }
+
+    pub fn set_state(&mut self, state: CrashLogState) {
+        unsafe {
+            core::ptr::addr_of_mut!((*self.header_ptr()).state)
+                .write_volatile(state as u32);
+        }
+    }
+
+    /// Append to the ring buffer (overwriting the oldest data).
+    pub fn write(&mut self, bytes: &[u8]) {
+        let mut hdr = self.header();
+        for b in bytes {
+            let off = (hdr.written % Self::DATA_SIZE as u64) as usize;
+            unsafe { self.data_ptr().add(off).write_volatile(*b); }
+            hdr.written += 1;
+        }
+        unsafe {
+            core::ptr::addr_of_mut!((*self.header_ptr()).written)
+                .write_volatile(hdr.written);
+        }
+    }
+
+    /// Copy the contents of the ring buffer into 'buf' (oldest data first).
+    /// Returns the number of bytes copied.
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        let written = self.header().written;
+        let len = (written.min(Self::DATA_SIZE as u64) as usize).min(buf.len());
+        let start = written - len as u64;
+        for (idx, b) in buf[..len].iter_mut().enumerate() {
+            let off = ((start + idx as u64) % Self::DATA_SIZE as u64) as usize;
+            *b = unsafe { self.data_ptr().add(off).read_volatile() };
+        }
+        len
+    }
+}
+impl core::fmt::Write for CrashLog {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use std::vec;
+    use std::vec::Vec;
+    use super::*;
+
+    /// Make a crash log backed by 'region'.
+    fn crashlog(region: &mut Vec<u64>) -> CrashLog {
+        assert_eq!(region.len() * 8, CRASHLOG_SIZE);
+        unsafe { CrashLog::from_ptr(region.as_mut_ptr() as *mut u8) }
+    }
+
+    #[test]
+    fn init_and_clear() {
+        let mut region = vec![0u64; CRASHLOG_SIZE / 8];
+        let mut log = crashlog(&mut region);
+        assert!(!log.is_valid());
+        assert_eq!(log.state(), CrashLogState::Empty);
+
+        log.init(MrldBuildId::new("test"));
+        assert!(log.is_valid());
+        assert_eq!(log.state(), CrashLogState::Running);
+        assert_eq!(log.header().build_id.as_str(), "test");
+        assert_eq!(log.read(&mut [0; 16]), 0);
+
+        log.set_state(CrashLogState::Panic);
+        assert!(log.state().is_crash());
+        log.clear();
+        assert_eq!(log.state(), CrashLogState::Empty);
+    }
+
+    #[test]
+    fn read_without_wraparound() {
+        let mut region = vec![0u64; CRASHLOG_SIZE / 8];
+        let mut log = crashlog(&mut region);
+        log.init(MrldBuildId::new_empty());
+        log.write(b"hello, ");
+        log.write(b"world");
+
+        let mut buf = [0u8; 64];
+        let len = log.read(&mut buf);
+        assert_eq!(&buf[..len], b"hello, world");
+
+        // A short buffer gets the newest data
+        let mut buf = [0u8; 5];
+        assert_eq!(log.read(&mut buf), 5);
+        assert_eq!(&buf, b"world");
+    }
+
+    #[test]
+    fn read_with_wraparound() {
+        let mut region = vec![0u64; CRASHLOG_SIZE / 8];
+        let mut log = crashlog(&mut region);
+        log.init(MrldBuildId::new_empty());
+
+        // Write 1.5 times the size of the ring buffer
+        let total = CrashLog::DATA_SIZE + CrashLog::DATA_SIZE / 2;
+        let data: Vec<u8> = (0..total).map(|idx| (idx % 251) as u8).collect();
+        for chunk in data.chunks(1000) {
+            log.write(chunk);
+        }
+        assert_eq!(log.header().written, total as u64);
+
+        // Only the last DATA_SIZE bytes survive, oldest first
+        let mut buf = vec![0u8; CRASHLOG_SIZE];
+        let len = log.read(&mut buf);
+        assert_eq!(len, CrashLog::DATA_SIZE);
+        assert_eq!(&buf[..len], &data[total - CrashLog::DATA_SIZE..]);
+    }
+}
@@ -0,0 +1,351 @@
+//! Kernel image layout.
+//!
+//! The kernel ELF is linked at [`KERNEL_VIRT_BASE`] (see `mrld-kernel.ld`),
+//! and both the bootloader (`boot/src/pxe.rs`) and kexec
+//! (`kernel/src/kexec.rs`) load it with the same conventions:
+//!
+//! - The physical load addresses in the ELF are ignored. The image is
+//!   loaded at some [`KERNEL_ALIGN`]-aligned physical address, and each
+//!   segment keeps its offset from the base of the image
+//!
+//! - Loadable segments must fit inside the first [`KERNEL_WINDOW_SIZE`]
+//!   bytes above [`KERNEL_VIRT_BASE`], and can't share any 4KiB pages
+//!
+//! - Non-loadable segments are ignored
+//!
+//! - The entrypoint has the type [`crate::MrldKernelEntrypoint`], and
+//!   must reside in an executable segment
+//!
+//! Both sides check these with [`KernelElf::parse`] before loading anything.
+
+use crate::paging::PageSize;
+use crate::{ MrldKernelSegment, MAX_KERNEL_SEGMENTS };
+use core::fmt;
+use elf::{ ElfBytes, endian::LittleEndian, abi::*, file::Class };
+
+/// Virtual address the kernel is linked at (see `mrld-kernel.ld`).
+pub const KERNEL_VIRT_BASE: u64 = 0xffff_ffff_8000_0000;
+
+/// Required alignment for the physical/virtual base of the kernel image.
+/// The kernel is always mapped with 2MiB pages.
+pub const KERNEL_ALIGN: u64 = 1 << 21;
+
+/// Maximum size of the kernel image (in bytes).
+/// The kernel ELF must be linked to fit entirely inside this region.
+pub const KERNEL_WINDOW_SIZE: u64 = 32 * KERNEL_ALIGN;
+
+/// A validated kernel ELF.
+pub struct KernelElf<'a> {
+    /// The parsed ELF
+    pub elf: ElfBytes<'a, LittleEndian>,
+    /// Virtual address of the entrypoint
+    pub entrypt: u64,
+    /// Loadable segments. Physical addresses are offsets from the base of
+    /// the image until the caller decides where to put it.
+    segments: [MrldKernelSegment; MAX_KERNEL_SEGMENTS],
+    /// File offset of the contents of each segment
+    offsets: [u64; MAX_KERNEL_SEGMENTS],
+    /// Number of valid entries in 'segments' and 'offsets'
+    num_segments: usize,
+}
+impl<'a> KernelElf<'a> {
+    /// Parse and validate a kernel ELF.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ImageError> {
+        let elf = ElfBytes::<LittleEndian>::minimal_parse(data)
+            .map_err(ImageError::Parse)?;
+        if elf.ehdr.class != Class::ELF64 {
+            return Err(ImageError::BadClass);
+        }
+        if elf.ehdr.e_machine != EM_X86_64 {
+            return Err(ImageError::BadMachine(elf.ehdr.e_machine));
+        }
+        if elf.ehdr.e_type != ET_EXEC {
+            return Err(ImageError::BadType(elf.ehdr.e_type));
+        }
+
+        let mut res = Self {
+            entrypt: elf.ehdr.e_entry,
+            segments: [MrldKernelSegment::new_empty(); MAX_KERNEL_SEGMENTS],
+            offsets: [0; MAX_KERNEL_SEGMENTS],
+            num_segments: 0,
+            elf,
+        };
+
+        // Collect and validate all of the loadable segments
+        let phdrs = res.elf.segments().ok_or(ImageError::NoSegments)?;
+        for seg in phdrs.iter() {
+            if seg.p_type != PT_LOAD || seg.p_memsz == 0 {
+                continue;
+            }
+            let idx = res.num_segments;
+            if idx >= MAX_KERNEL_SEGMENTS {
+                return Err(ImageError::TooManySegments);
+            }
+            if seg.p_filesz > seg.p_memsz {
+                return Err(ImageError::SegmentSize {
+                    idx, filesz: seg.p_filesz, memsz: seg.p_memsz
+                });
+            }
+            let in_file = seg.p_offset.checked_add(seg.p_filesz)
+                .is_some_and(|end| end <= data.len() as u64);
+            if !in_file {
+                return Err(ImageError::SegmentBounds {
+                    idx, offset: seg.p_offset, filesz: seg.p_filesz
+                });
+            }
+            let off = seg.p_vaddr.wrapping_sub(KERNEL_VIRT_BASE);
+            let in_window = seg.p_vaddr >= KERNEL_VIRT_BASE &&
+                off.checked_add(seg.p_memsz)
+                    .is_some_and(|end| end <= KERNEL_WINDOW_SIZE);
+            if !in_window {
+                return Err(ImageError::SegmentWindow {
+                    idx, vaddr: seg.p_vaddr, memsz: seg.p_memsz
+                });
+            }
+
+            res.segments[idx] = MrldKernelSegment {
+                paddr: off,
+                vaddr: seg.p_vaddr,
+                memsz: seg.p_memsz,
+                filesz: seg.p_filesz,
+                flags: seg.p_flags,
+            };
+            res.offsets[idx] = seg.p_offset;
+            res.num_segments += 1;
+        }
+        if res.num_segments == 0 {
+            return Err(ImageError::NoSegments);
+        }
+
+        // Segments cannot share any physical pages
+        for (idx, seg) in res.segments().iter().enumerate() {
+            let (lo, hi) = page_range(seg);
+            for (other, oseg) in res.segments()[..idx].iter().enumerate() {
+                let (olo, ohi) = page_range(oseg);
+                if lo < ohi && olo < hi {
+                    return Err(ImageError::SegmentOverlap { idx, other });
+                }
+            }
+        }
+
+        // The entrypoint must be inside an executable segment
+        let entrypt = res.entrypt;
+        let entry_ok = res.segments().iter().any(|seg| {
+            (seg.flags & MrldKernelSegment::PF_X) != 0 &&
+            entrypt >= seg.vaddr && entrypt < seg.vaddr + seg.memsz
+        });
+        if !entry_ok {
+            return Err(ImageError::BadEntrypoint(entrypt));
+        }
+        Ok(res)
+    }
+
+    /// Return the list of loadable segments.
+    pub fn segments(&self) -> &[MrldKernelSegment] {
+        &self.segments[..self.num_segments]
+    }
+
+    /// Return the file offset of the contents of each segment.
+    pub fn offsets(&self) -> &[u64] {
+        &self.offsets[..self.num_segments]
+    }
+
+    /// Size of the loaded image (in bytes, rounded up to [`KERNEL_ALIGN`]).
+    pub fn size(&self) -> u64 {
+        self.segments().iter()
+            .map(|seg| page_range(seg).1)
+            .max().unwrap()
+            .next_multiple_of(KERNEL_ALIGN)
+    }
+}
+
+/// Return the page-aligned physical range [lo, hi) covered by a segment.
+pub fn page_range(seg: &MrldKernelSegment) -> (u64, u64) {
+    let pg = u64::from(PageSize::Size4KiB);
+    let lo = seg.paddr & !(pg - 1);
+    let hi = (seg.paddr + seg.memsz).next_multiple_of(pg);
+    (lo, hi)
+}
+
+/// Reasons why a kernel ELF can't be loaded.
+#[derive(Debug)]
+pub enum ImageError {
+    /// The image could not be parsed as an ELF
+    Parse(elf::ParseError),
+    /// The image is not a 64-bit ELF
+    BadClass,
+    /// The image is not built for x86_64
+    BadMachine(u16),
+    /// The image is not a static executable
+    BadType(u16),
+    /// The image has no loadable segments
+    NoSegments,
+    /// The image has more than [`MAX_KERNEL_SEGMENTS`] loadable segments
+    TooManySegments,
+    /// A segment is larger in the file than in memory
+    SegmentSize { idx: usize, filesz: u64, memsz: u64 },
+    /// The contents of a segment lie outside of the image
+    SegmentBounds { idx: usize, offset: u64, filesz: u64 },
+    /// A segment lies outside of the region mapped for the kernel
+    SegmentWindow { idx: usize, vaddr: u64, memsz: u64 },
+    /// Two segments share physical pages
+    SegmentOverlap { idx: usize, other: usize },
+    /// The entrypoint is not inside an executable segment
+    BadEntrypoint(u64),
+}
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Parse(e) => write!(f, "couldn't parse ELF: {}", e),
+            Self::BadClass => write!(f, "not a 64-bit ELF"),
+            Self::BadMachine(m) => {
+                write!(f, "unexpected machine {} (expected x86_64)", m)
+            },
+            Self::BadType(t) => {
+                write!(f, "unexpected ELF type {} (expected ET_EXEC)", t)
+            },
+            Self::NoSegments => write!(f, "no loadable segments"),
+            Self::TooManySegments => {
+                write!(f, "more than {} loadable segments", MAX_KERNEL_SEGMENTS)
+            },
+            Self::SegmentSize { idx, filesz, memsz } => {
+                write!(f, "segment {}: filesz {:x} exceeds memsz {:x}",
+                    idx, filesz, memsz)
+            },
+            Self::SegmentBounds { idx, offset, filesz } => {
+                write!(f, "segment {}: offset {:x} size {:x} is out of bounds",
+                    idx, offset, filesz)
+            },
+            Self::SegmentWindow { idx, vaddr, memsz } => {
+                write!(f, "segment {}: v={:016x} memsz={:x} is outside the \
+                    kernel mapping (v={:016x} size={:x})",
+                    idx, vaddr, memsz, KERNEL_VIRT_BASE, KERNEL_WINDOW_SIZE)
+            },
+            Self::SegmentOverlap { idx, other } => {
+                write!(f, "segment {} overlaps segment {}", idx, other)
+            },
+            Self::BadEntrypoint(e) => {
+                write!(f, "entrypoint {:016x} is not in an executable segment", e)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use std::vec::Vec;
+
+    /// A loadable segment for [`build_elf`]: (vaddr, filesz, memsz, flags)
+    type Seg = (u64, u64, u64, u32);
+
+    /// Build a minimal ELF64 executable with the given segments. The
+    /// contents of each segment follow the program headers.
+    fn build_elf(machine: u16, entry: u64, segs: &[Seg]) -> Vec<u8> {
+        let phoff = 0x40u64;
+        let mut data_off = phoff + 0x38 * segs.len() as u64;
+        let mut res = Vec::new();
+        res.extend_from_slice(b"\x7fELF\x02\x01\x01");
+        res.resize(0x10, 0);
+        res.extend_from_slice(&ET_EXEC.to_le_bytes());
+        res.extend_from_slice(&machine.to_le_bytes());
+        res.extend_from_slice(&1u32.to_le_bytes());
+        res.extend_from_slice(&entry.to_le_bytes());
+        res.extend_from_slice(&phoff.to_le_bytes());
+        res.extend_from_slice(&0u64.to_le_bytes());
+        res.extend_from_slice(&0u32.to_le_bytes());
+        for val in [0x40u16, 0x38, segs.len() as u16, 0x40, 0, 0] {
+            res.extend_from_slice(&val.to_le_bytes());
+        }
+        for (vaddr, filesz, memsz, flags) in segs {
+            res.extend_from_slice(&PT_LOAD.to_le_bytes());
+            res.extend_from_slice(&flags.to_le_bytes());
+            for val in [data_off, *vaddr, *vaddr, *filesz, *memsz, 0x1000] {
+                res.extend_from_slice(&val.to_le_bytes());
+            }
+            data_off += filesz;
+        }
+        let len = data_off as usize;
+        res.resize(len, 0xcc);
+        res
+    }
+
+    const TEXT: u64 = KERNEL_VIRT_BASE;
+    const DATA: u64 = KERNEL_VIRT_BASE + 0x10_0000;
+    const RX: u32 = MrldKernelSegment::PF_R | MrldKernelSegment::PF_X;
+    const RW: u32 = MrldKernelSegment::PF_R | MrldKernelSegment::PF_W;
+
+    #[test]
+    fn parse_kernel() {
+        let data = build_elf(EM_X86_64, TEXT + 0x10, &[
+            (TEXT, 0x100, 0x100, RX),
+            (DATA, 0x80, 0x3000, RW),
+        ]);
+        let img = KernelElf::parse(&data).unwrap();
+        assert_eq!(img.entrypt, TEXT + 0x10);
+        assert_eq!(img.segments().len(), 2);
+        assert_eq!(img.segments()[1].paddr, 0x10_0000);
+        assert_eq!(img.segments()[1].memsz, 0x3000);
+        assert_eq!(img.offsets()[1], img.offsets()[0] + 0x100);
+        assert_eq!(img.size(), KERNEL_ALIGN);
+    }
+
+    #[test]
+    fn reject_bad_header() {
+        let data = build_elf(EM_386, TEXT, &[(TEXT, 0x100, 0x100, RX)]);
+        assert!(matches!(KernelElf::parse(&data),
+            Err(ImageError::BadMachine(EM_386))
+        ));
+        assert!(matches!(KernelElf::parse(b"MRLDCMP\0"),
+            Err(ImageError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn reject_bad_segments() {
+        let data = build_elf(EM_X86_64, TEXT, &[]);
+        assert!(matches!(KernelElf::parse(&data),
+            Err(ImageError::NoSegments)
+        ));
+
+        let data = build_elf(EM_X86_64, TEXT, &[(TEXT, 0x200, 0x100, RX)]);
+        assert!(matches!(KernelElf::parse(&data),
+            Err(ImageError::SegmentSize { idx: 0, .. })
+        ));
+
+        let mut data = build_elf(EM_X86_64, TEXT, &[(TEXT, 0x100, 0x100, RX)]);
+        data.truncate(data.len() - 1);
+        assert!(matches!(KernelElf::parse(&data),
+            Err(ImageError::SegmentBounds { idx: 0, .. })
+        ));
+
+        let data = build_elf(EM_X86_64, TEXT, &[
+            (TEXT, 0x100, 0x100, RX),
+            (TEXT + KERNEL_WINDOW_SIZE - 0x1000, 0, 0x2000, RW),
+        ]);
+        assert!(matches!(KernelElf::parse(&data),
+            Err(ImageError::SegmentWindow { idx: 1, .. })
+        ));
+
+        let data = build_elf(EM_X86_64, TEXT, &[
+            (TEXT, 0x100, 0x100, RX),
+            (TEXT + 0x800, 0x100, 0x100, RW),
+        ]);
+        assert!(matches!(KernelElf::parse(&data),
+            Err(ImageError::SegmentOverlap { idx: 1, other: 0 })
+        ));
+    }
+
+    #[test]
+    fn reject_bad_entrypoint() {
+        // Inside a segment, but not an executable one
+        let data = build_elf(EM_X86_64, DATA, &[
+            (TEXT, 0x100, 0x100, RX),
+            (DATA, 0x100, 0x100, RW),
+        ]);
+        assert!(matches!(KernelElf::parse(&data),
+            Err(ImageError::BadEntrypoint(DATA))
+        ));
+    }
+}
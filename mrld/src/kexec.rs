@@ -0,0 +1,37 @@
+//! The kexec serial protocol.
+//!
+//! When the kernel is booted with the `kexec` command line option, it waits
+//! for a new kernel ELF on COM2 (see `kernel/src/kexec.rs`), which
+//! `cargo xtask kexec` sends (see `xtask/src/kexec.rs`). The request is:
+//!
+//! ```text
+//! magic    : 8 bytes, "MRLDKEXC"
+//! size     : u64 (little-endian), size of the ELF in bytes
+//! checksum : u32 (little-endian), FNV-1a hash of the ELF (see [`fnv1a32`])
+//! data     : 'size' bytes
+//! ```
+
+/// Magic bytes at the start of a kexec request
+pub const KEXEC_MAGIC: [u8; 8] = *b"MRLDKEXC";
+
+/// FNV-1a hash (used to check images sent over serial).
+pub fn fnv1a32(data: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for b in data {
+        hash ^= *b as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fnv1a32_vectors() {
+        assert_eq!(fnv1a32(b""), 0x811c_9dc5);
+        assert_eq!(fnv1a32(b"a"), 0xe40c_292c);
+        assert_eq!(fnv1a32(b"foobar"), 0xbf9c_f968);
+    }
+}
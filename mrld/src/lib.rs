@@ -9,6 +9,11 @@ pub mod paging;
 pub mod physmem;
 pub mod x86; 
 pub mod mmio; 
+pub mod crashlog;
+pub mod manifest;
+pub mod compress;
+pub mod image;
+pub mod kexec;
 
 use core::ops::Range;
 use core::ptr::NonNull;
@@ -21,15 +26,6 @@ use core::mem::MaybeUninit;
 /// Is there any way for us to declare this *in one place*? 
 pub type MrldKernelEntrypoint = extern "sysv64" fn(*const MrldBootArgs) -> !;
 
-// NOTE: These symbols are defined in the kernel linkerscript.
-unsafe extern "C" { 
-    #[link_name = "_kernel_phys_base"]
-    pub static KERNEL_PHYS_BASE: u64;
-
-    #[link_name = "_kernel_virt_base"]
-    pub static KERNEL_VIRT_BASE: u64;
-}
-
 /// Arguments passed from the UEFI bootloader to the kernel. 
 #[repr(C)]
 pub struct MrldBootArgs { 
@@ -42,6 +38,49 @@ pub struct MrldBootArgs {
     pub uefi_map_size: usize,
     /// Reported descriptor size in the UEFI memory map
     pub uefi_map_desc_size: usize,
+    /// Reported descriptor version in the UEFI memory map
+    pub uefi_map_desc_version: u32,
+
+    /// Physical address of the UEFI runtime services table
+    pub uefi_runtime: u64,
+
+    /// Linear framebuffer (from the UEFI Graphics Output Protocol)
+    pub framebuffer: MrldFramebuffer,
+
+    /// Physical base address of the kernel image
+    pub kernel_phys_base: u64,
+    /// Virtual base address of the kernel image
+    pub kernel_virt_base: u64,
+    /// Size of the kernel image mapping (in bytes, a multiple of 2MiB)
+    pub kernel_size: u64,
+
+    /// Segments loaded from the kernel ELF
+    pub kernel_segments: [MrldKernelSegment; MAX_KERNEL_SEGMENTS],
+    /// Number of valid entries in 'kernel_segments'
+    pub num_kernel_segments: usize,
+
+    /// Symbol table from the kernel ELF
+    pub kernel_symbols: MrldKernelSymbols,
+
+    /// Build ID of the kernel (from the kernel manifest)
+    pub kernel_build_id: MrldBuildId,
+
+    /// Kernel command line (from the boot menu)
+    pub cmdline: MrldCmdline,
+
+    /// Firmware identity and SMBIOS entry points
+    pub firmware: MrldFirmwareInfo,
+
+    /// Boot modules
+    pub modules: [MrldBootModule; MAX_BOOT_MODULES],
+    /// Number of valid entries in 'modules'
+    pub num_modules: usize,
+
+    /// Physical address of the crash log region (zero if unavailable)
+    pub crashlog: u64,
+
+    /// Timestamps for each stage of the bootloader
+    pub timeline: MrldBootTimeline,
 }
 impl MrldBootArgs { 
     pub fn as_ptr(&self) -> *const Self { 
@@ -53,7 +92,453 @@ impl MrldBootArgs {
             uefi_map: 0,
             uefi_map_size: 0,
             uefi_map_desc_size: 0,
+            uefi_map_desc_version: 0,
+            uefi_runtime: 0,
+            framebuffer: MrldFramebuffer::new_empty(),
+            kernel_phys_base: 0,
+            kernel_virt_base: 0,
+            kernel_size: 0,
+            kernel_segments: [MrldKernelSegment::new_empty(); MAX_KERNEL_SEGMENTS],
+            num_kernel_segments: 0,
+            kernel_symbols: MrldKernelSymbols::new_empty(),
+            kernel_build_id: MrldBuildId::new_empty(),
+            cmdline: MrldCmdline::new_empty(),
+            firmware: MrldFirmwareInfo::new_empty(),
+            modules: [MrldBootModule::new_empty(); MAX_BOOT_MODULES],
+            num_modules: 0,
+            crashlog: 0,
+            timeline: MrldBootTimeline::new_empty(),
+        }
+    }
+
+    /// Return the list of segments loaded from the kernel ELF.
+    pub fn kernel_segments(&self) -> &[MrldKernelSegment] { 
+        &self.kernel_segments[..self.num_kernel_segments]
+    }
+
+    /// Return the list of boot modules.
+    pub fn modules(&self) -> &[MrldBootModule] { 
+        &self.modules[..self.num_modules]
+    }
+}
+
+/// Maximum number of loadable segments in the kernel ELF. 
+pub const MAX_KERNEL_SEGMENTS: usize = 8;
+
+/// Describes a segment loaded from the kernel ELF by the bootloader. 
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct MrldKernelSegment { 
+    /// Physical address of the segment
+    pub paddr: u64,
+    /// Virtual address of the segment
+    pub vaddr: u64,
+    /// Size of the segment in memory (in bytes)
+    pub memsz: u64,
+    /// Size of the segment in the ELF (in bytes)
+    pub filesz: u64,
+    /// ELF segment flags (PF_R, PF_W, PF_X)
+    pub flags: u32,
+}
+impl MrldKernelSegment { 
+    pub const PF_X: u32 = 1 << 0;
+    pub const PF_W: u32 = 1 << 1;
+    pub const PF_R: u32 = 1 << 2;
+
+    pub const fn new_empty() -> Self { 
+        Self { paddr: 0, vaddr: 0, memsz: 0, filesz: 0, flags: 0 }
+    }
+}
+
+/// Describes the symbol table from the kernel ELF (copied into memory by
+/// the bootloader). 
+///
+/// Symbol values have already been adjusted to match the virtual base of 
+/// the kernel image. 
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct MrldKernelSymbols { 
+    /// Physical address of the symbol table (an array of 'Elf64_Sym')
+    pub symtab: u64,
+    /// Size of the symbol table (in bytes)
+    pub symtab_size: u64,
+    /// Physical address of the associated string table
+    pub strtab: u64,
+    /// Size of the string table (in bytes)
+    pub strtab_size: u64,
+}
+impl MrldKernelSymbols { 
+    pub const fn new_empty() -> Self { 
+        Self { symtab: 0, symtab_size: 0, strtab: 0, strtab_size: 0 }
+    }
+
+    /// Returns 'true' if this describes a symbol table
+    pub fn is_valid(&self) -> bool { 
+        self.symtab != 0 && self.strtab != 0
+    }
+}
+
+/// Describes the platform firmware (from the UEFI system table).
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct MrldFirmwareInfo { 
+    /// Firmware vendor (converted to ASCII, NUL-padded)
+    pub vendor: [u8; Self::VENDOR_LEN],
+    /// Firmware revision (the format is vendor-specific)
+    pub revision: u32,
+    /// UEFI specification revision (major in the upper 16 bits)
+    pub uefi_revision: u32,
+    /// Physical address of the SMBIOS 2.x entry point (or zero)
+    pub smbios_addr: u64,
+    /// Physical address of the SMBIOS 3.x entry point (or zero)
+    pub smbios3_addr: u64,
+}
+impl MrldFirmwareInfo { 
+    /// Maximum length of the vendor string (in bytes)
+    pub const VENDOR_LEN: usize = 64;
+
+    pub const fn new_empty() -> Self { 
+        Self { 
+            vendor: [0; Self::VENDOR_LEN],
+            revision: 0,
+            uefi_revision: 0,
+            smbios_addr: 0,
+            smbios3_addr: 0,
+        }
+    }
+
+    /// Return the firmware vendor string.
+    pub fn vendor(&self) -> &str { 
+        let len = self.vendor.iter().position(|b| *b == 0)
+            .unwrap_or(Self::VENDOR_LEN);
+        core::str::from_utf8(&self.vendor[..len]).unwrap_or("")
+    }
+}
+
+/// Maximum number of boot modules.
+pub const MAX_BOOT_MODULES: usize = 16;
+
+/// Describes a file loaded into memory by the bootloader alongside the 
+/// kernel (ie. an experiment payload, test inputs, or configuration). 
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct MrldBootModule { 
+    /// Name of the module (NUL-padded)
+    pub name: [u8; Self::NAME_LEN],
+    /// Physical address of the module
+    pub addr: u64,
+    /// Size of the module (in bytes)
+    pub size: u64,
+}
+impl MrldBootModule { 
+    /// Maximum length of a module name (in bytes)
+    pub const NAME_LEN: usize = 64;
+
+    pub const fn new_empty() -> Self { 
+        Self { name: [0; Self::NAME_LEN], addr: 0, size: 0 }
+    }
+
+    /// Create a new module, truncating the name if necessary.
+    pub fn new(name: &str, addr: u64, size: u64) -> Self { 
+        let mut res = Self { name: [0; Self::NAME_LEN], addr, size };
+        let len = name.len().min(Self::NAME_LEN);
+        res.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+        res
+    }
+
+    /// Return the name of this module.
+    pub fn name(&self) -> &str { 
+        let len = self.name.iter().position(|b| *b == 0)
+            .unwrap_or(Self::NAME_LEN);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+}
+
+/// Maximum number of entries in a boot timeline.
+pub const MAX_BOOT_STAGES: usize = 32;
+
+/// A TSC timestamp taken at the end of a boot stage. 
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct MrldBootStage { 
+    /// Name of the stage (NUL-padded)
+    pub name: [u8; Self::NAME_LEN],
+    /// Value of the TSC when the stage was finished
+    pub tsc: u64,
+}
+impl MrldBootStage { 
+    /// Maximum length of a stage name (in bytes)
+    pub const NAME_LEN: usize = 24;
+
+    pub const fn new_empty() -> Self { 
+        Self { name: [0; Self::NAME_LEN], tsc: 0 }
+    }
+
+    /// Create a new stage, truncating the name if necessary.
+    pub fn new(name: &str, tsc: u64) -> Self { 
+        let mut res = Self { name: [0; Self::NAME_LEN], tsc };
+        let len = name.len().min(Self::NAME_LEN);
+        res.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+        res
+    }
+
+    /// Return the name of this stage.
+    pub fn name(&self) -> &str { 
+        let len = self.name.iter().position(|b| *b == 0)
+            .unwrap_or(Self::NAME_LEN);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+}
+
+/// TSC timestamps for each stage of the boot process. 
+///
+/// The bootloader records its own stages (starting from the entrypoint), 
+/// and the kernel appends its stages to a copy of the timeline. 
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct MrldBootTimeline { 
+    /// Measured TSC frequency (in Hz, zero if unknown)
+    pub tsc_freq: u64,
+    /// Recorded stages
+    pub stages: [MrldBootStage; MAX_BOOT_STAGES],
+    /// Number of valid entries in 'stages'
+    pub num_stages: usize,
+}
+impl MrldBootTimeline { 
+    pub const fn new_empty() -> Self { 
+        Self { 
+            tsc_freq: 0,
+            stages: [MrldBootStage::new_empty(); MAX_BOOT_STAGES],
+            num_stages: 0,
+        }
+    }
+
+    /// Record the end of a stage with the current value of the TSC. 
+    pub fn record(&mut self, name: &str) { 
+        self.record_at(name, x86::rdtsc());
+    }
+
+    /// Record the end of a stage at the given TSC value. 
+    /// Stages are silently dropped when the timeline is full. 
+    pub fn record_at(&mut self, name: &str, tsc: u64) { 
+        if self.num_stages < MAX_BOOT_STAGES { 
+            self.stages[self.num_stages] = MrldBootStage::new(name, tsc);
+            self.num_stages += 1;
+        }
+    }
+
+    /// Return the list of recorded stages.
+    pub fn stages(&self) -> &[MrldBootStage] { 
+        &self.stages[..self.num_stages]
+    }
+
+    /// Convert a number of TSC ticks into microseconds 
+    /// (or `None` if the TSC frequency is unknown).
+    pub fn ticks_to_us(&self, ticks: u64) -> Option<u64> { 
+        if self.tsc_freq == 0 { 
+            return None;
+        }
+        Some((ticks as u128 * 1_000_000 / self.tsc_freq as u128) as u64)
+    }
+}
+
+/// Identifies the build that produced the kernel image 
+/// (ie. `<git revision>[-dirty]-<build time>`). 
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct MrldBuildId { 
+    /// Build ID string (NUL-padded)
+    pub id: [u8; Self::LEN],
+}
+impl MrldBuildId { 
+    /// Maximum length of a build ID (in bytes)
+    pub const LEN: usize = 64;
+
+    pub const fn new_empty() -> Self { 
+        Self { id: [0; Self::LEN] }
+    }
+
+    /// Create a new build ID, truncating it if necessary.
+    pub const fn new(id: &str) -> Self { 
+        let mut res = Self::new_empty();
+        let bytes = id.as_bytes();
+        let mut idx = 0;
+        while idx < bytes.len() && idx < Self::LEN { 
+            res.id[idx] = bytes[idx];
+            idx += 1;
         }
+        res
     }
+
+    /// Return the build ID as a string (empty if unknown).
+    pub fn as_str(&self) -> &str { 
+        let len = self.id.iter().position(|b| *b == 0).unwrap_or(Self::LEN);
+        core::str::from_utf8(&self.id[..len]).unwrap_or("")
+    }
+}
+
+/// The kernel command line. 
+///
+/// This is a list of whitespace-separated options, either flags (ie. `nosmp`)
+/// or `key=value` pairs (ie. `log=verbose`). 
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct MrldCmdline { 
+    /// Command line (NUL-padded)
+    pub buf: [u8; Self::LEN],
 }
+impl MrldCmdline { 
+    /// Maximum length of the command line (in bytes)
+    pub const LEN: usize = 256;
+
+    pub const fn new_empty() -> Self { 
+        Self { buf: [0; Self::LEN] }
+    }
 
+    /// Create a new command line, truncating it if necessary.
+    pub fn new(s: &str) -> Self { 
+        let mut res = Self::new_empty();
+        let len = s.len().min(Self::LEN);
+        res.buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+        res
+    }
+
+    /// Return the command line as a string.
+    pub fn as_str(&self) -> &str { 
+        let len = self.buf.iter().position(|b| *b == 0).unwrap_or(Self::LEN);
+        core::str::from_utf8(&self.buf[..len]).unwrap_or("")
+    }
+
+    /// Return an iterator over all options.
+    pub fn options(&self) -> impl Iterator<Item = &str> { 
+        self.as_str().split_whitespace()
+    }
+
+    /// Returns 'true' if the given flag (or key) is present.
+    pub fn has(&self, name: &str) -> bool { 
+        self.options().any(|opt| { 
+            opt == name || opt.split_once('=').is_some_and(|(k, _)| k == name)
+        })
+    }
+
+    /// Return the value associated with the given key (if it exists).
+    pub fn get(&self, key: &str) -> Option<&str> { 
+        self.options()
+            .filter_map(|opt| opt.split_once('='))
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v)
+    }
+}
+
+/// Pixel format of a linear framebuffer. 
+///
+/// NOTE: All of these formats use 32-bit pixels. 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum MrldPixelFormat { 
+    /// There is no framebuffer
+    None = 0,
+    /// Byte order is red, green, blue, reserved
+    Rgb = 1,
+    /// Byte order is blue, green, red, reserved
+    Bgr = 2,
+    /// Channels are described by the masks in [`MrldFramebuffer`]
+    Bitmask = 3,
+}
+
+/// Describes a linear framebuffer passed from the bootloader to the kernel.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct MrldFramebuffer { 
+    /// Physical address of the framebuffer
+    pub base: u64,
+    /// Size of the framebuffer (in bytes)
+    pub size: usize,
+    /// Horizontal resolution (in pixels)
+    pub width: usize,
+    /// Vertical resolution (in pixels)
+    pub height: usize,
+    /// Number of pixels in each scanline (which may exceed the width)
+    pub stride: usize,
+    /// Pixel format
+    pub format: MrldPixelFormat,
+
+    /// Red channel mask (only for [`MrldPixelFormat::Bitmask`])
+    pub red_mask: u32,
+    /// Green channel mask (only for [`MrldPixelFormat::Bitmask`])
+    pub green_mask: u32,
+    /// Blue channel mask (only for [`MrldPixelFormat::Bitmask`])
+    pub blue_mask: u32,
+}
+impl MrldFramebuffer { 
+    pub const fn new_empty() -> Self { 
+        Self { 
+            base: 0,
+            size: 0,
+            width: 0,
+            height: 0,
+            stride: 0,
+            format: MrldPixelFormat::None,
+            red_mask: 0,
+            green_mask: 0,
+            blue_mask: 0,
+        }
+    }
+
+    /// Returns 'true' if this describes a usable framebuffer
+    pub fn is_valid(&self) -> bool { 
+        self.format != MrldPixelFormat::None && self.base != 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use std::format;
+    use super::*;
+
+    #[test]
+    fn timeline_records_stages() {
+        let mut timeline = MrldBootTimeline::new_empty();
+        assert!(timeline.stages().is_empty());
+
+        timeline.record_at("entry", 100);
+        timeline.record_at("a stage name that is too long to fit", 200);
+        let stages = timeline.stages();
+        assert_eq!(stages.len(), 2);
+        assert_eq!(stages[0].name(), "entry");
+        assert_eq!(stages[0].tsc, 100);
+        assert_eq!(stages[1].name(), "a stage name that is too");
+        assert_eq!(stages[1].name().len(), MrldBootStage::NAME_LEN);
+        assert_eq!(stages[1].tsc, 200);
+    }
+
+    #[test]
+    fn timeline_drops_stages_when_full() {
+        let mut timeline = MrldBootTimeline::new_empty();
+        for idx in 0..MAX_BOOT_STAGES + 4 {
+            timeline.record_at(&format!("stage{}", idx), idx as u64);
+        }
+        let stages = timeline.stages();
+        assert_eq!(stages.len(), MAX_BOOT_STAGES);
+        assert_eq!(stages[0].name(), "stage0");
+        let last = &stages[MAX_BOOT_STAGES - 1];
+        assert_eq!(last.name(), format!("stage{}", MAX_BOOT_STAGES - 1));
+        assert_eq!(last.tsc, (MAX_BOOT_STAGES - 1) as u64);
+    }
+
+    #[test]
+    fn timeline_ticks_to_us() {
+        let mut timeline = MrldBootTimeline::new_empty();
+        assert_eq!(timeline.ticks_to_us(1000), None);
+
+        timeline.tsc_freq = 2_000_000_000;
+        assert_eq!(timeline.ticks_to_us(0), Some(0));
+        assert_eq!(timeline.ticks_to_us(2_000_000_000), Some(1_000_000));
+        assert_eq!(timeline.ticks_to_us(3_000), Some(1));
+        // Doesn't overflow for large tick counts
+        assert_eq!(timeline.ticks_to_us(u64::MAX),
+            Some((u64::MAX as u128 * 1_000_000 / 2_000_000_000) as u64)
+        );
+    }
+}
@@ -0,0 +1,262 @@
+//! Kernel manifests.
+//!
+//! `cargo xtask build` publishes a manifest next to each kernel on the PXE
+//! server (see `xtask/src/manifest.rs`). This is a text file with one
+//! `key=value` pair per line:
+//!
+//! ```text
+//! sha256=<SHA-256 of the kernel ELF, in hex>
+//! size=<size of the kernel ELF, in bytes>
+//! build_id=<git revision>[-dirty]-<build time>
+//! ```
+//!
+//! The bootloader checks the kernel against the manifest before loading it,
+//! which catches truncated transfers and stale builds before they turn into
+//! strange crashes.
+//!
+//! Both sides use this module: xtask writes manifests with the [`Display`]
+//! implementation for [`KernelManifest`], and the bootloader reads them with
+//! [`KernelManifest::parse`].
+//!
+//! [`Display`]: core::fmt::Display
+
+use crate::MrldBuildId;
+use core::fmt;
+
+/// A kernel manifest.
+pub struct KernelManifest {
+    /// Expected SHA-256 digest of the kernel ELF
+    pub sha256: [u8; 32],
+    /// Expected size of the kernel ELF (in bytes)
+    pub size: Option<usize>,
+    /// Identifies the build that produced the kernel
+    pub build_id: MrldBuildId,
+}
+impl KernelManifest {
+    /// Parse a manifest.
+    pub fn parse(data: &[u8]) -> Result<Self, ManifestError> {
+        let text = core::str::from_utf8(data)
+            .map_err(|_| ManifestError::Parse)?;
+
+        let mut sha256 = None;
+        let mut size = None;
+        let mut build_id = MrldBuildId::new_empty();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, val) = line.split_once('=')
+                .ok_or(ManifestError::Parse)?;
+            match key.trim() {
+                "sha256" => sha256 = Some(Self::parse_digest(val.trim())?),
+                "size" => {
+                    size = Some(val.trim().parse()
+                        .map_err(|_| ManifestError::Parse)?);
+                },
+                "build_id" => build_id = MrldBuildId::new(val.trim()),
+                // Ignore anything we don't understand
+                _ => {},
+            }
+        }
+
+        Ok(Self {
+            sha256: sha256.ok_or(ManifestError::NoDigest)?,
+            size,
+            build_id,
+        })
+    }
+
+    /// Parse a SHA-256 digest from a hex string.
+    ///
+    /// NOTE: This works on bytes, since a corrupt manifest can have
+    /// multi-byte characters where we expect hex digits.
+    fn parse_digest(s: &str) -> Result<[u8; 32], ManifestError> {
+        let s = s.as_bytes();
+        if s.len() != 64 || !s.iter().all(u8::is_ascii_hexdigit) {
+            return Err(ManifestError::Parse);
+        }
+        let nibble = |c: u8| (c as char).to_digit(16).unwrap() as u8;
+        let mut res = [0u8; 32];
+        for (b, pair) in res.iter_mut().zip(s.chunks_exact(2)) {
+            *b = (nibble(pair[0]) << 4) | nibble(pair[1]);
+        }
+        Ok(res)
+    }
+
+    /// Check the size and SHA-256 digest of a kernel image against this
+    /// manifest.
+    pub fn check(&self, size: usize, digest: &[u8; 32])
+        -> Result<(), ManifestError>
+    {
+        if let Some(expected) = self.size && size != expected {
+            return Err(ManifestError::Size { expected, actual: size });
+        }
+        if *digest != self.sha256 {
+            return Err(ManifestError::Digest {
+                expected: self.sha256, actual: *digest
+            });
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for KernelManifest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "sha256={}", Hex(&self.sha256))?;
+        if let Some(size) = self.size {
+            writeln!(f, "size={}", size)?;
+        }
+        if !self.build_id.as_str().is_empty() {
+            writeln!(f, "build_id={}", self.build_id.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ManifestError {
+    /// The manifest is malformed
+    Parse,
+    /// The manifest doesn't have a digest
+    NoDigest,
+    /// The image doesn't have the expected size
+    Size { expected: usize, actual: usize },
+    /// The image doesn't have the expected digest
+    Digest { expected: [u8; 32], actual: [u8; 32] },
+}
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Parse => write!(f, "malformed manifest"),
+            Self::NoDigest => write!(f, "manifest has no 'sha256' entry"),
+            Self::Size { expected, actual } => {
+                write!(f, "expected {} bytes, got {} bytes (truncated?)",
+                    expected, actual
+                )
+            },
+            Self::Digest { expected, actual } => {
+                write!(f, "SHA-256 mismatch (expected {}, got {})",
+                    Hex(expected), Hex(actual)
+                )
+            },
+        }
+    }
+}
+
+/// Formats a digest as a hex string.
+struct Hex<'a>(&'a [u8]);
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for b in self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+
+    const DIGEST: &str =
+        "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1F";
+
+    /// A manifest with a comment and an unknown key
+    const MANIFEST: &str = concat!(
+        "# comment\n",
+        "sha256=000102030405060708090a0b0c0d0e0f",
+        "101112131415161718191a1b1c1d1e1F\n",
+        "size=1234\n",
+        "build_id=abcdef-1\n",
+        "unknown=whatever\n",
+    );
+
+    #[test]
+    fn parse_manifest() {
+        let m = KernelManifest::parse(MANIFEST.as_bytes()).unwrap();
+        for (idx, b) in m.sha256.iter().enumerate() {
+            assert_eq!(*b as usize, idx);
+        }
+        assert_eq!(m.size, Some(1234));
+        assert_eq!(m.build_id.as_str(), "abcdef-1");
+        assert!(m.check(1234, &m.sha256).is_ok());
+        assert!(matches!(m.check(1233, &m.sha256),
+            Err(ManifestError::Size { expected: 1234, actual: 1233 })
+        ));
+        assert!(matches!(m.check(1234, &[0; 32]),
+            Err(ManifestError::Digest { .. })
+        ));
+    }
+
+    #[test]
+    fn format_then_parse() {
+        let mut sha256 = [0u8; 32];
+        for (idx, b) in sha256.iter_mut().enumerate() {
+            *b = (idx * 7) as u8;
+        }
+        let m = KernelManifest {
+            sha256,
+            size: Some(0x1234),
+            build_id: MrldBuildId::new("abcdef-dirty-1"),
+        };
+        let text = std::format!("{}", m);
+        let parsed = KernelManifest::parse(text.as_bytes()).unwrap();
+        assert_eq!(parsed.sha256, m.sha256);
+        assert_eq!(parsed.size, m.size);
+        assert_eq!(parsed.build_id.as_str(), m.build_id.as_str());
+
+        // Optional entries are left out
+        let m = KernelManifest {
+            sha256, size: None, build_id: MrldBuildId::new_empty()
+        };
+        let text = std::format!("{}", m);
+        assert_eq!(text.lines().count(), 1);
+        let parsed = KernelManifest::parse(text.as_bytes()).unwrap();
+        assert_eq!(parsed.sha256, m.sha256);
+        assert_eq!(parsed.size, None);
+        assert_eq!(parsed.build_id.as_str(), "");
+    }
+
+    #[test]
+    fn digest_rejects_bad_length() {
+        assert!(KernelManifest::parse_digest(&DIGEST[..62]).is_err());
+        let long = [DIGEST, "00"].concat();
+        assert!(KernelManifest::parse_digest(&long).is_err());
+        assert!(KernelManifest::parse_digest("").is_err());
+    }
+
+    #[test]
+    fn digest_rejects_non_hex() {
+        let mut bad = DIGEST.as_bytes().to_vec();
+        bad[10] = b'g';
+        let bad = core::str::from_utf8(&bad).unwrap();
+        assert!(KernelManifest::parse_digest(bad).is_err());
+
+        // Signs are accepted by from_str_radix, but aren't hex digits
+        let bad = ["+f", &DIGEST[2..]].concat();
+        assert!(KernelManifest::parse_digest(&bad).is_err());
+    }
+
+    #[test]
+    fn digest_rejects_non_ascii() {
+        // 64 bytes, but a two-byte character straddles a digit pair
+        let bad = ["0\u{e9}", &DIGEST[3..]].concat();
+        assert_eq!(bad.len(), 64);
+        assert!(KernelManifest::parse_digest(&bad).is_err());
+        assert!(KernelManifest::parse(
+            ["sha256=", &bad].concat().as_bytes()
+        ).is_err());
+    }
+
+    #[test]
+    fn manifest_without_digest() {
+        assert!(matches!(KernelManifest::parse(b"size=1\n"),
+            Err(ManifestError::NoDigest)
+        ));
+        assert!(matches!(KernelManifest::parse(b"sha256\n"),
+            Err(ManifestError::Parse)
+        ));
+    }
+}
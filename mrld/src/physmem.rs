@@ -162,12 +162,63 @@ pub enum MrldMemoryKind {
 
     KernelHeap = 10,
 
+    /// Symbol table for the kernel image (copied by the bootloader)
+    KernelSymbols = 11,
+
+    /// Boot modules (downloaded by the bootloader)
+    BootModule = 12,
+
+    /// Persistent crash log (see [`crate::crashlog`])
+    CrashLog = 13,
+
+    /// Trampoline (and temporary page tables) for starting APs
+    Trampoline = 14,
+
     /// Advertised as "reserved" by UEFI firmware
     UefiReserved = 255,
 }
+impl MrldMemoryKind { 
+    /// Base of the range of UEFI memory types reserved for OS loaders.
+    pub const UEFI_TYPE_BASE: u32 = 0x8000_0000;
+
+    /// Return the [OS-defined] UEFI memory type used by the bootloader when 
+    /// allocating memory of this kind. 
+    ///
+    /// This lets the kernel recover the purpose of regions allocated by the 
+    /// bootloader from the UEFI memory map alone. 
+    pub const fn as_uefi_type(self) -> MemoryType { 
+        MemoryType(Self::UEFI_TYPE_BASE | self as u32)
+    }
+
+    /// Convert from the raw value of a [`MrldMemoryKind`].
+    pub fn from_u32(x: u32) -> Self { 
+        match x { 
+            1 => Self::Available,
+            2 => Self::Reclaimable,
+            3 => Self::BootArgs,
+            4 => Self::KernelImage,
+            5 => Self::AcpiNonVolatile,
+            7 => Self::UefiRuntime,
+            8 => Self::Mmio,
+            9 => Self::KernelPaging,
+            10 => Self::KernelHeap,
+            11 => Self::KernelSymbols,
+            12 => Self::BootModule,
+            13 => Self::CrashLog,
+            14 => Self::Trampoline,
+            255 => Self::UefiReserved,
+            _ => Self::Invalid,
+        }
+    }
+}
+
 impl From<MemoryType> for MrldMemoryKind { 
     fn from(t: MemoryType) -> Self { 
         match t { 
+            // Allocated by the bootloader (see `as_uefi_type()`)
+            t if t.0 >= Self::UEFI_TYPE_BASE => { 
+                Self::from_u32(t.0 & !Self::UEFI_TYPE_BASE)
+            },
             MemoryType::LOADER_CODE |
             MemoryType::LOADER_DATA |
             MemoryType::BOOT_SERVICES_CODE |
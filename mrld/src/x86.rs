@@ -6,6 +6,7 @@ pub mod segment;
 pub mod dtr; 
 pub mod idt;
 pub mod gdt;
+pub mod tss;
 pub mod gpr;
 pub mod io;
 
@@ -54,6 +55,38 @@ pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
     core::arch::x86_64::__cpuid_count(leaf, subleaf)
 }
 
+/// Returns 'true' if the CPU supports 1GiB pages (CPUID Fn8000_0001 EDX[26]).
+pub fn has_1gib_pages() -> bool { 
+    cpuid(0x8000_0001, 0).edx & (1 << 26) != 0
+}
 
+#[inline(always)]
+pub fn rdtsc() -> u64 { 
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
 
-
+/// Read a random 64-bit value with RDRAND. 
+///
+/// Returns 'None' if RDRAND is unsupported, or if it repeatedly fails to
+/// produce a value. 
+pub fn rdrand() -> Option<u64> { 
+    if cpuid(1, 0).ecx & (1 << 30) == 0 { 
+        return None;
+    }
+    for _ in 0..10 { 
+        let val: u64;
+        let ok: u8;
+        unsafe { 
+            core::arch::asm!(
+                "rdrand {val}", 
+                "setc {ok}", 
+                val = out(reg) val, 
+                ok = out(reg_byte) ok
+            );
+        }
+        if ok != 0 { 
+            return Some(val);
+        }
+    }
+    None
+}
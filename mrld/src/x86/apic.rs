@@ -178,9 +178,12 @@ pub struct IntrCommand {
     pub rrs: B2,
     /// Destination shorthand
     pub dsh: B2,
-    _r20: B36,
-    /// Destination
-    pub des: B8,
+    _r20: B12,
+    /// Destination (an x2APIC ID).
+    ///
+    /// This is the layout of the x2APIC ICR. In xAPIC mode, the destination 
+    /// is only 8 bits (at bits 56-63), see `Lapic::send_ipi` in the kernel.
+    pub des: B32,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -146,6 +146,21 @@ pub enum SystemDescriptor {
     TrapGate(u64, u64),
 }
 impl SystemDescriptor {
+    /// Type for an available 64-bit TSS
+    const TSS_AVAILABLE_TYPE: u64 = 0b1001;
+
+    /// Create a new descriptor for an available 64-bit TSS.
+    pub const fn new_tss(base_addr: u64, limit: u32) -> Self { 
+        let lo = (limit as u64 & 0x0_ffff)
+            | (base_addr & 0x00ff_ffff) << 16
+            | Self::TSS_AVAILABLE_TYPE << 40
+            | DFlags::P.bits()
+            | (limit as u64 & 0xf_0000) << 32
+            | (base_addr & 0xff00_0000) << 32;
+        let hi = base_addr >> 32;
+        Self::TssAvailable(lo, hi)
+    }
+
     pub fn as_u64(&self) -> (u64, u64) { 
         match self { 
             Self::LdtPointer(x, y) | 
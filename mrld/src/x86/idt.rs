@@ -181,6 +181,12 @@ impl <K: HandlerKind> IdtEntry<K> {
         self.flags |= (dpl & 0b11) << 5;
         self
     }
+    /// Use a stack from the interrupt stack table (IST) in the TSS
+    /// (where 'ist' is 1-7, or zero to use the current stack). 
+    pub const fn with_ist(mut self, ist: u8) -> Self { 
+        self.ist = ist & 0b111;
+        self
+    }
 
     pub const fn empty() -> Self { 
         Self { 
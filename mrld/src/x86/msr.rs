@@ -8,6 +8,11 @@ impl Msr {
 
     pub const EFER: u32 = 0xc000_0080;
 
+    /// Base of the local APIC registers in x2APIC mode
+    pub const X2APIC_BASE: u32 = 0x0000_0800;
+    /// Interrupt command register in x2APIC mode
+    pub const X2APIC_ICR: u32  = 0x0000_0830;
+
     pub const FS_BASE: u32 = 0xc000_0100;
     pub const GS_BASE: u32 = 0xc000_0101;
     pub const KERNEL_GS_BASE: u32 = 0xc000_0102;
@@ -0,0 +1,74 @@
+//! Types for representing a 64-bit task state segment (TSS).
+//!
+//! In long mode, the TSS doesn't have anything to do with task switching.
+//! It only holds stack pointers:
+//!
+//! - `RSP0`-`RSP2` are used when an interrupt changes the privilege level
+//! - `IST1`-`IST7` are used for IDT entries with a nonzero IST index,
+//!   regardless of the privilege level
+//!
+
+use crate::x86::gdt::SystemDescriptor;
+
+/// A 64-bit task state segment.
+#[repr(C, packed(4))]
+pub struct TaskStateSegment {
+    _r0: u32,
+    /// Stack pointers for privilege levels 0-2
+    rsp: [u64; 3],
+    _r1: u64,
+    /// Interrupt stack table
+    ist: [u64; 7],
+    _r2: u64,
+    _r3: u16,
+    /// Offset to the I/O permission bitmap
+    iomap_base: u16,
+}
+impl TaskStateSegment {
+    const SIZE: usize = {
+        let sz = core::mem::size_of::<Self>();
+        assert!(sz == 104, "The TSS must be 104 bytes");
+        sz
+    };
+
+    /// Create a new TSS (without an I/O permission bitmap).
+    pub const fn new() -> Self {
+        Self {
+            _r0: 0,
+            rsp: [0; 3],
+            _r1: 0,
+            ist: [0; 7],
+            _r2: 0,
+            _r3: 0,
+            iomap_base: Self::SIZE as u16,
+        }
+    }
+
+    /// Set the stack pointer for an IST entry (where 'idx' is 1-7, like the
+    /// IST index in an IDT entry).
+    pub fn set_ist(&mut self, idx: usize, rsp: u64) {
+        assert!((1..=7).contains(&idx), "Invalid IST index {}", idx);
+        let mut ist = self.ist;
+        ist[idx - 1] = rsp;
+        self.ist = ist;
+    }
+
+    /// Set the stack pointer for a privilege level (0-2).
+    pub fn set_rsp(&mut self, cpl: usize, rsp: u64) {
+        let mut rsp_list = self.rsp;
+        rsp_list[cpl] = rsp;
+        self.rsp = rsp_list;
+    }
+
+    /// Return a system descriptor for this TSS.
+    pub fn descriptor(&self) -> SystemDescriptor {
+        SystemDescriptor::new_tss(self as *const Self as u64,
+            (Self::SIZE - 1) as u32
+        )
+    }
+}
+impl Default for TaskStateSegment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -27,8 +27,11 @@ shutdown_services()
 }
 trap shutdown_services SIGINT
 
-# Start TFTP server
-tftpd -i 10.200.200.1 -r -d ./pxe/
+# Start TFTP server.
+# Uploads (crash logs from the bootloader) are written to 'crashlogs/incoming/',
+# see 'cargo xtask crashlogs'.
+mkdir -p ./crashlogs/incoming/
+tftpd -i 10.200.200.1 -d ./pxe/ -rd ./crashlogs/incoming/
 
 shutdown_services()
 
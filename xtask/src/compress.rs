@@ -0,0 +1,95 @@
+//! Compressed kernel images.
+//!
+//! With `cargo xtask build --compress <lz4|zstd>`, kernels are published in
+//! `pxe/` as compressed images instead of symlinks to the ELF. Each image
+//! has a small header in front of the compressed data (see
+//! `mrld/src/compress.rs`, which the bootloader also uses to read it).
+//!
+//! The manifest still describes the uncompressed ELF, since the bootloader
+//! verifies the kernel after decompressing it.
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use mrld::compress::CompressedHeader;
+use std::path::Path;
+
+/// Compression formats
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Compression {
+    /// LZ4 (fast to decompress)
+    Lz4,
+    /// Zstandard (smaller)
+    Zstd,
+}
+impl From<Compression> for mrld::compress::Compression {
+    fn from(format: Compression) -> Self {
+        match format {
+            Compression::Lz4 => Self::Lz4,
+            Compression::Zstd => Self::Zstd,
+        }
+    }
+}
+
+/// Return a compressed image (with the header) for 'data'.
+pub fn compress(data: &[u8], format: Compression) -> Vec<u8> {
+    let body = match format {
+        Compression::Lz4 => lz4_flex::block::compress(data),
+        Compression::Zstd => ruzstd::encoding::compress_to_vec(
+            data, ruzstd::encoding::CompressionLevel::Fastest
+        ),
+    };
+    let header = CompressedHeader { format: format.into(), size: data.len() };
+    let mut res = header.to_bytes().to_vec();
+    res.extend_from_slice(&body);
+    res
+}
+
+/// Publish the kernel at 'kernel_path' as 'dest' in `pxe/`.
+///
+/// Without compression, 'dest' is a symlink to the kernel. Otherwise, it's
+/// a compressed image. Anything already at 'dest' is replaced.
+pub fn publish_kernel(kernel_path: &Path, dest: &Path,
+    format: Option<Compression>) -> Result<()>
+{
+    if dest.symlink_metadata().is_ok() {
+        std::fs::remove_file(dest)?;
+    }
+    let Some(format) = format else {
+        std::os::unix::fs::symlink(kernel_path, dest)?;
+        return Ok(());
+    };
+
+    let kernel = std::fs::read(kernel_path).map_err(|e| {
+        anyhow!("Couldn't read kernel {}: {}", kernel_path.display(), e)
+    })?;
+    let image = compress(&kernel, format);
+    std::fs::write(dest, &image)?;
+    println!("[*] Wrote {} ({:?}, {}B -> {}B)", dest.display(), format,
+        kernel.len(), image.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Compress like `cargo xtask build`, then parse and decompress like
+    /// the bootloader.
+    #[test]
+    fn compress_then_decompress() {
+        let data: Vec<u8> = (0..0x4000u32)
+            .flat_map(|x| (x / 3).to_le_bytes())
+            .collect();
+        for format in [Compression::Lz4, Compression::Zstd] {
+            let image = compress(&data, format);
+            let header = CompressedHeader::parse(&image).unwrap().unwrap();
+            assert_eq!(header.format, format.into());
+            assert_eq!(header.size, data.len());
+
+            let mut out = vec![0u8; header.size];
+            header.decompress(&image, &mut out).unwrap();
+            assert_eq!(out, data);
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! Storing crash logs uploaded by the bootloader.
+//!
+//! When the kernel panics (or never shuts down cleanly), the bootloader
+//! uploads its crash log on the next boot as `crashlog-YYYYMMDD-HHMMSS.txt`
+//! (using the target's RTC). We keep each log in `crashlogs/`, prefixed with
+//! the time it was received on the host (in seconds since the Unix epoch),
+//! so that logs from targets with a bogus RTC never overwrite each other.
+//!
+//! - With HTTP, the server stores logs directly (see [`crate::http`])
+//! - With TFTP, `tftpd` writes logs into `crashlogs/incoming/`, and
+//!   `cargo xtask crashlogs` moves them into `crashlogs/`
+
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Directory for crash logs (relative to the repository root)
+pub const CRASHLOG_DIR: &str = "crashlogs";
+
+/// Directory for crash logs uploaded with TFTP (see `start-network.sh`)
+pub const INCOMING_DIR: &str = "crashlogs/incoming";
+
+/// Returns 'true' if 'name' looks like a crash log from the bootloader.
+pub fn is_crashlog(name: &str) -> bool {
+    name.starts_with("crashlog-") && name.ends_with(".txt")
+        && !name.contains('/') && !name.contains('\\')
+}
+
+/// Return the path for storing a new crash log named 'name' in 'dir'.
+fn timestamped_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    Ok(dir.join(format!("{}-{}", time, name)))
+}
+
+/// Store a crash log in 'dir'.
+/// Returns the path to the new file.
+pub fn store(dir: &Path, name: &str, data: &[u8]) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let path = timestamped_path(dir, name)?;
+    std::fs::write(&path, data)?;
+    Ok(path)
+}
+
+/// Move any crash logs uploaded with TFTP into `crashlogs/`.
+pub fn collect(root: &Path) -> Result<()> {
+    let dir = root.join(CRASHLOG_DIR);
+    let incoming = root.join(INCOMING_DIR);
+    std::fs::create_dir_all(&incoming)?;
+
+    let mut count = 0;
+    for entry in std::fs::read_dir(&incoming)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !is_crashlog(&name) {
+            continue;
+        }
+        let path = timestamped_path(&dir, &name)?;
+        std::fs::rename(entry.path(), &path)?;
+        println!("[*] {}", path.display());
+        count += 1;
+    }
+    if count == 0 {
+        println!("[*] No new crash logs in {}", incoming.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,161 @@
+//! A tiny HTTP server for the `pxe/` directory.
+//!
+//! This only supports what the bootloader needs: `GET` and `HEAD` requests
+//! for files in a single directory, with a `Content-Length` header. Each
+//! connection handles one request.
+//!
+//! Crash logs from the bootloader are uploaded with `PUT` (or `POST`), and
+//! are stored in a separate directory (see [`crate::crashlog`]).
+
+use anyhow::Result;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::crashlog;
+
+/// Default address for the HTTP server
+pub const DEFAULT_ADDR: &str = "0.0.0.0:8080";
+
+/// Serve files from 'dir' forever.
+/// Uploaded crash logs are stored in 'upload_dir'.
+pub fn serve(dir: &Path, upload_dir: &Path, addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    println!("[*] Serving {} at http://{}/", dir.display(), addr);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("[!] HTTP connection failed: {}", e);
+                continue;
+            },
+        };
+        let dir = dir.to_path_buf();
+        let upload_dir = upload_dir.to_path_buf();
+        std::thread::spawn(move || {
+            if let Err(e) = handle(stream, &dir, &upload_dir) {
+                println!("[!] HTTP request failed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Resolve a request path to a file in 'dir'.
+/// Returns [`None`] for anything outside of 'dir'.
+fn resolve(dir: &Path, path: &str) -> Option<PathBuf> {
+    let name = path.split('?').next()?.trim_start_matches('/');
+    if name.is_empty() || name.contains('/') || name.contains('\\')
+        || name.starts_with('.')
+    {
+        return None;
+    }
+    Some(dir.join(name))
+}
+
+fn handle(stream: TcpStream, dir: &Path, upload_dir: &Path) -> Result<()> {
+    let peer = stream.peer_addr()?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut stream = stream;
+
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+
+    // Skip the rest of the headers (except for the length of the body)
+    let mut content_length: Option<usize> = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((k, v)) = line.split_once(':')
+            && k.trim().eq_ignore_ascii_case("content-length")
+        {
+            content_length = v.trim().parse().ok();
+        }
+    }
+
+    let mut parts = request.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method, path),
+        _ => {
+            write!(stream, "HTTP/1.1 400 Bad Request\r\n\
+                Content-Length: 0\r\nConnection: close\r\n\r\n")?;
+            return Ok(());
+        },
+    };
+    if method == "PUT" || method == "POST" {
+        return handle_upload(reader, stream, upload_dir, path, content_length);
+    }
+    if method != "GET" && method != "HEAD" {
+        write!(stream, "HTTP/1.1 405 Method Not Allowed\r\n\
+            Content-Length: 0\r\nConnection: close\r\n\r\n")?;
+        return Ok(());
+    }
+
+    // NOTE: This follows the symlinks created by 'cargo xtask build'
+    let data = match resolve(dir, path).map(std::fs::read) {
+        Some(Ok(data)) => data,
+        _ => {
+            println!("[!] {} {} {} (not found)", peer, method, path);
+            write!(stream, "HTTP/1.1 404 Not Found\r\n\
+                Content-Length: 0\r\nConnection: close\r\n\r\n")?;
+            return Ok(());
+        },
+    };
+
+    println!("[*] {} {} {} ({}B)", peer, method, path, data.len());
+    write!(stream, "HTTP/1.1 200 OK\r\n\
+        Content-Type: application/octet-stream\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\r\n", data.len()
+    )?;
+    if method == "GET" {
+        stream.write_all(&data)?;
+    }
+    stream.flush()?;
+    Ok(())
+}
+
+/// Store a crash log uploaded by the bootloader.
+fn handle_upload(mut reader: BufReader<TcpStream>, mut stream: TcpStream,
+    upload_dir: &Path, path: &str, content_length: Option<usize>)
+    -> Result<()>
+{
+    let peer = stream.peer_addr()?;
+    let name = path.split('?').next().unwrap_or("").trim_start_matches('/');
+    if !crashlog::is_crashlog(name) {
+        println!("[!] {} upload {} (not a crash log)", peer, path);
+        write!(stream, "HTTP/1.1 403 Forbidden\r\n\
+            Content-Length: 0\r\nConnection: close\r\n\r\n")?;
+        return Ok(());
+    }
+
+    // Without a 'Content-Length', read until the client stops sending
+    let mut data = Vec::new();
+    match content_length {
+        Some(len) => {
+            data.resize(len, 0);
+            reader.read_exact(&mut data)?;
+        },
+        None => {
+            stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+            if let Err(e) = reader.read_to_end(&mut data)
+                && !matches!(e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut)
+            {
+                return Err(e.into());
+            }
+        },
+    }
+
+    let saved = crashlog::store(upload_dir, name, &data)?;
+    println!("[*] {} upload {} ({}B) -> {}",
+        peer, path, data.len(), saved.display()
+    );
+    write!(stream, "HTTP/1.1 201 Created\r\n\
+        Content-Length: 0\r\nConnection: close\r\n\r\n")?;
+    stream.flush()?;
+    Ok(())
+}
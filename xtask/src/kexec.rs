@@ -0,0 +1,44 @@
+//! Sending a new kernel to a running kernel over serial.
+//!
+//! When the kernel is booted with the `kexec` command line option, it waits
+//! for a new kernel ELF on COM2 instead of shutting down. The request format
+//! is described in `mrld/src/kexec.rs`.
+
+use anyhow::{anyhow, Result};
+use mrld::kexec::{KEXEC_MAGIC, fnv1a32};
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+
+/// Default serial port (see 'cargo xtask console')
+pub const DEFAULT_PORT: &str = "/dev/ttyUSB0";
+
+/// Send a kernel ELF to the serial port at 'port'.
+pub fn send(kernel: &Path, port: &str) -> Result<()> {
+    let data = std::fs::read(kernel)?;
+
+    // Use the same settings as the kernel (115200 baud, 8N1, no translation)
+    let status = Command::new("stty")
+        .args(["-F", port, "115200", "raw", "-echo"])
+        .status()?;
+    if !status.success() {
+        return Err(anyhow!("Couldn't configure serial port {}", port));
+    }
+
+    let mut tty = std::fs::OpenOptions::new().write(true).open(port)?;
+    tty.write_all(&KEXEC_MAGIC)?;
+    tty.write_all(&(data.len() as u64).to_le_bytes())?;
+    tty.write_all(&fnv1a32(&data).to_le_bytes())?;
+    println!("[*] Sending {} ({} bytes) to {} ...",
+        kernel.display(), data.len(), port
+    );
+    for (idx, chunk) in data.chunks(64 * 1024).enumerate() {
+        tty.write_all(chunk)?;
+        print!("\r  {}KiB", ((idx * 64 * 1024) + chunk.len()) / 1024);
+        std::io::stdout().flush()?;
+    }
+    tty.flush()?;
+    println!();
+    println!("[*] Sent {}", kernel.display());
+    Ok(())
+}
@@ -7,24 +7,80 @@ use std::path::Path;
 use std::env;
 
 mod pxe;
+mod manifest;
+mod http;
+mod crashlog;
+mod kexec;
+mod compress;
 
 /// `mrld` hacky xtask build system
 #[derive(Parser)]
 #[command(verbatim_doc_comment)]
 enum XtaskCommand { 
     /// Build the bootloader and kernel
-    Build,
+    Build { 
+        /// Load the kernel at a random physical/virtual address
+        #[arg(long)]
+        kaslr: bool,
+
+        /// Publish compressed kernels in 'pxe/'
+        #[arg(long, value_enum)]
+        compress: Option<compress::Compression>,
+    },
 
     /// PXE boot into the bootloader with QEMU
     Qemu { 
         /// Enable GDB server and halt
         #[arg(long, short)]
         gdb: bool,
+
+        /// Open a QEMU window showing the framebuffer
+        #[arg(long, short)]
+        display: bool,
+
+        /// Boot with UEFI HTTP boot instead of PXE/TFTP
+        #[arg(long)]
+        http: bool,
+
+        /// Connect COM2 to a new pseudo-terminal instead of stdio
+        /// (for use with 'cargo xtask kexec')
+        #[arg(long)]
+        pty: bool,
+
+        /// Boot the debug kernel directly with PVH (without UEFI or the
+        /// bootloader)
+        #[arg(long, conflicts_with = "http")]
+        direct: bool,
+
+        /// Kernel command line (with '--direct')
+        #[arg(long, default_value = "", requires = "direct")]
+        cmdline: String,
+    },
+
+    /// Send a new kernel to a running kernel (booted with 'kexec') over serial
+    Kexec { 
+        /// Serial port connected to the target
+        #[arg(long, default_value = kexec::DEFAULT_PORT)]
+        port: String,
+
+        /// Send the release kernel instead of the debug kernel
+        #[arg(long)]
+        release: bool,
+    },
+
+    /// Serve 'pxe/' over HTTP (for UEFI HTTP boot)
+    Http { 
+        /// Address to listen on
+        #[arg(long, default_value = http::DEFAULT_ADDR)]
+        addr: String,
     },
 
     /// Start PXE services on the host machine
     Pxe,
 
+    /// Collect crash logs uploaded with TFTP into 'crashlogs/'
+    Crashlogs,
+
     /// Run 'picocom' (for communicating with a target over /dev/ttyUSB0)
     Console,
 
@@ -37,7 +93,7 @@ enum XtaskCommand {
 
 
 /// Build the UEFI bootloader
-fn build_boot(root: &Path) -> Result<()> {
+fn build_boot(root: &Path, kaslr: bool) -> Result<()> {
     let cmd = Command::new("cargo")
         .args([
             "build", 
@@ -46,6 +102,7 @@ fn build_boot(root: &Path) -> Result<()> {
             "-Z", "build-std=core,alloc,compiler_builtins",
             "--target=x86_64-unknown-uefi",
         ])
+        .args(kaslr.then_some("--features=kaslr"))
         .current_dir(root)
         .spawn()?
         .wait()?;
@@ -59,7 +116,7 @@ fn build_boot(root: &Path) -> Result<()> {
 }
 
 /// Build the kernel
-fn build_kernel(root: &Path) -> Result<()> {
+fn build_kernel(root: &Path, kaslr: bool) -> Result<()> {
     let cmd = Command::new("cargo")
         .args([
             "build", 
@@ -72,6 +129,7 @@ fn build_kernel(root: &Path) -> Result<()> {
             "-Z", "json-target-spec",
             "--target=mrld-kernel.json",
         ])
+        .args(kaslr.then_some("--features=kaslr"))
         .current_dir(root)
         .spawn()?
         .wait()?;
@@ -92,6 +150,7 @@ fn build_kernel(root: &Path) -> Result<()> {
             "-Z", "json-target-spec",
             "--target=mrld-kernel.json",
         ])
+        .args(kaslr.then_some("--features=kaslr"))
         .current_dir(root)
         .spawn()?
         .wait()?;
@@ -131,39 +190,61 @@ fn make_symlinks(root: &Path) -> Result<()> {
 
     let bootloader_path = root.join("target/x86_64-unknown-uefi/release/mrld-boot.efi");
     let bootloader_link = pxe_path.join("mrld-boot.efi");
-    let kernel_path = root.join("target/mrld-kernel/release/mrld-kernel");
-    let kernel_link  = pxe_path.join("mrld-kernel");
-
-    let kernel_dbg_path = root.join("target/mrld-kernel/debug/mrld-kernel");
-    let kernel_dbg_link  = pxe_path.join("mrld-kernel-debug");
-
 
     if let Err(e) = symlink(bootloader_path, bootloader_link) { 
         if e.kind() != ErrorKind::AlreadyExists {
             return Err(e.into());
         }
     };
-    if let Err(e) = symlink(kernel_path, kernel_link) { 
-        if e.kind() != ErrorKind::AlreadyExists {
-            return Err(e.into());
-        }
-    };
-    if let Err(e) = symlink(kernel_dbg_path, kernel_dbg_link) { 
-        if e.kind() != ErrorKind::AlreadyExists {
-            return Err(e.into());
-        }
-    };
 
 
     Ok(())
 }
 
+/// Publish each kernel in 'pxe/' (either as a symlink to the kernel ELF, 
+/// or as a compressed image)
+fn publish_kernels(root: &Path, format: Option<compress::Compression>) 
+    -> Result<()> 
+{
+    let pxe_path = root.join("pxe");
+    let kernels = [
+        ("target/mrld-kernel/release/mrld-kernel", "mrld-kernel"),
+        ("target/mrld-kernel/debug/mrld-kernel", "mrld-kernel-debug"),
+    ];
+    for (kernel_path, name) in kernels { 
+        compress::publish_kernel(
+            &root.join(kernel_path), &pxe_path.join(name), format
+        )?;
+    }
+    Ok(())
+}
+
+/// Publish a manifest next to each kernel in 'pxe/'
+fn write_manifests(root: &Path) -> Result<()> {
+    let pxe_path = root.join("pxe");
+    let kernels = [
+        ("target/mrld-kernel/release/mrld-kernel", "mrld-kernel.manifest"),
+        ("target/mrld-kernel/debug/mrld-kernel", "mrld-kernel-debug.manifest"),
+    ];
+    for (kernel_path, manifest_name) in kernels { 
+        manifest::write_manifest(
+            &root.join(kernel_path), &pxe_path.join(manifest_name)
+        )?;
+    }
+    Ok(())
+}
+
 // NOTE: Other users might have to change this..
 const OVMF_CODE: &'static str = "/usr/share/edk2-ovmf/x64/OVMF_CODE.4m.fd";
 const OVMF_VARS: &'static str = "/usr/share/edk2-ovmf/x64/OVMF_VARS.4m.fd";
 
+/// Address of the host machine on the QEMU user network
+const QEMU_HOST_ADDR: &'static str = "10.200.200.2";
+
 // FIXME: Maybe try to automatically make a symlink in pxe/
-fn run_qemu(root: &Path, gdb: bool) -> Result<()> { 
+fn run_qemu(root: &Path, gdb: bool, display: bool, http: bool, pty: bool) 
+    -> Result<()> 
+{ 
 
     let pxe_path = root.join("pxe");
 
@@ -177,13 +258,28 @@ fn run_qemu(root: &Path, gdb: bool) -> Result<()> {
 
     let drive0 = format!("if=pflash,unit=0,format=raw,readonly=on,file={}", OVMF_CODE);
     let drive1 = format!("if=pflash,unit=1,format=raw,readonly=on,file={}", OVMF_VARS);
+
+    // With HTTP boot, the bootfile is a URL on the host machine, and OVMF 
+    // falls back to HTTP after PXE fails to download it. 
+    let bootfile = if http { 
+        let http_path = pxe_path.clone();
+        let upload_path = root.join(crashlog::CRASHLOG_DIR);
+        std::thread::spawn(move || { 
+            if let Err(e) = http::serve(&http_path, &upload_path, http::DEFAULT_ADDR) { 
+                println!("[!] HTTP server error: {}", e);
+            }
+        });
+        let port = http::DEFAULT_ADDR.rsplit(':').next().unwrap();
+        format!("http://{}:{}/mrld-boot.efi", QEMU_HOST_ADDR, port)
+    } else { 
+        "mrld-boot.efi".to_string()
+    };
     let netdev = format!(
-        "user,id=net0,ipv6=off,net=10.200.200.0/24,tftp={},bootfile=mrld-boot.efi",
-        pxe_path.into_os_string().to_str().unwrap());
+        "user,id=net0,ipv6=off,net=10.200.200.0/24,tftp={},bootfile={}",
+        pxe_path.into_os_string().to_str().unwrap(), bootfile);
 
     let mut arghhhs: Vec<&str> = vec![
         "-nodefaults",
-        "-nographic",
         "-vga", "virtio",
         "-accel", "kvm",
         "-cpu", "host",
@@ -204,11 +300,63 @@ fn run_qemu(root: &Path, gdb: bool) -> Result<()> {
 
         // Disable COM1 (0x3f8), use COM2 (0x2f8) instead
         "-serial", "none",
-        "-serial", "stdio",
+        "-serial", if pty { "pty" } else { "stdio" },
         //"-monitor", "stdio",
         "-boot", "n",
     ];
 
+    // NOTE: Without a window, the framebuffer console is still drawn, 
+    // but you'll only be able to see the serial output.
+    if !display { 
+        arghhhs.push("-nographic");
+    }
+
+    if gdb { 
+        arghhhs.append(&mut vec![ 
+            "-gdb", "tcp::1234", "-S",
+        ]);
+    }
+
+    Command::new("qemu-system-x86_64")
+        .args(arghhhs)
+        .current_dir(root)
+        .spawn()?
+        .wait()?;
+
+    Ok(())
+}
+
+/// Boot the debug kernel directly with QEMU, using the PVH entrypoint
+/// (see `kernel/src/pvh.rs`).
+fn run_qemu_direct(root: &Path, gdb: bool, display: bool, pty: bool,
+    cmdline: &str) -> Result<()>
+{
+    let kernel_path = root.join("target/mrld-kernel/debug/mrld-kernel");
+    if !kernel_path.exists() {
+        return Err(anyhow!("Couldn't find kernel.\n\
+            Run 'cargo xtask build' before using QEMU."
+        ));
+    }
+
+    let mut arghhhs: Vec<&str> = vec![
+        "-nodefaults",
+        "-vga", "virtio",
+        "-accel", "kvm",
+        "-cpu", "host",
+        "-smp", "4",
+        "-m", "4096M",
+        "-kernel", kernel_path.to_str().unwrap(),
+        "-append", cmdline,
+
+        // Disable COM1 (0x3f8), use COM2 (0x2f8) instead
+        "-serial", "none",
+        "-serial", if pty { "pty" } else { "stdio" },
+    ];
+
+    if !display { 
+        arghhhs.push("-nographic");
+    }
+
     if gdb { 
         arghhhs.append(&mut vec![ 
             "-gdb", "tcp::1234", "-S",
@@ -254,21 +402,39 @@ fn main() -> Result<()> {
     let root = Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap();
     let cmd = XtaskCommand::parse();
     match cmd { 
-        XtaskCommand::Build => { 
-            build_boot(&root)?;
-            build_kernel(&root)?;
+        XtaskCommand::Build { kaslr, compress } => { 
+            build_boot(&root, kaslr)?;
+            build_kernel(&root, kaslr)?;
             make_symlinks(&root)?;
+            publish_kernels(&root, compress)?;
+            write_manifests(&root)?;
         },
         XtaskCommand::Test => { 
             run_tests(&root)?;
         },
 
-        XtaskCommand::Qemu { gdb } => {
-            run_qemu(&root, gdb)?;
+        XtaskCommand::Qemu { gdb, display, http, pty, direct, cmdline } => {
+            if direct { 
+                run_qemu_direct(&root, gdb, display, pty, &cmdline)?;
+            } else { 
+                run_qemu(&root, gdb, display, http, pty)?;
+            }
+        },
+        XtaskCommand::Kexec { port, release } => { 
+            let profile = if release { "release" } else { "debug" };
+            let kernel = root.join("target/mrld-kernel")
+                .join(profile).join("mrld-kernel");
+            kexec::send(&kernel, &port)?;
+        },
+        XtaskCommand::Http { addr } => { 
+            http::serve(&root.join("pxe"), &root.join(crashlog::CRASHLOG_DIR), &addr)?;
         },
         XtaskCommand::Pxe => {
             //pxe::start(&root)?;
         }
+        XtaskCommand::Crashlogs => {
+            crashlog::collect(&root)?;
+        },
         XtaskCommand::Console => {
             run_picocom()?;
         },
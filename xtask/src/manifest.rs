@@ -0,0 +1,45 @@
+//! Kernel manifests.
+//!
+//! After building, we publish a manifest next to each kernel in `pxe/`
+//! (ie. `pxe/mrld-kernel-debug.manifest`). The bootloader downloads it and
+//! uses it to verify the kernel before loading it.
+//!
+//! The format is defined in `mrld/src/manifest.rs`, and we write manifests
+//! with the same [`KernelManifest`] that the bootloader parses.
+//!
+//! The build ID is generated when building the kernel (see `kernel/build.rs`)
+//! and embedded in the `.mrld_build_id` section of the ELF.
+
+use anyhow::{anyhow, Result};
+use elf::{ElfBytes, endian::AnyEndian};
+use mrld::MrldBuildId;
+use mrld::manifest::KernelManifest;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+/// Return the build ID embedded in a kernel ELF.
+pub fn build_id(kernel: &[u8]) -> Result<String> {
+    let file = ElfBytes::<AnyEndian>::minimal_parse(kernel)?;
+    let shdr = file.section_header_by_name(".mrld_build_id")?
+        .ok_or(anyhow!("Kernel has no build ID"))?;
+    let (data, _) = file.section_data(&shdr)?;
+    let len = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    Ok(String::from_utf8(data[..len].to_vec())?)
+}
+
+/// Write a manifest for the kernel at 'kernel_path' to 'manifest_path'.
+pub fn write_manifest(kernel_path: &Path, manifest_path: &Path) -> Result<()> {
+    let kernel = std::fs::read(kernel_path).map_err(|e| {
+        anyhow!("Couldn't read kernel {}: {}", kernel_path.display(), e)
+    })?;
+    let build_id = build_id(&kernel)?;
+
+    let manifest = KernelManifest {
+        sha256: Sha256::digest(&kernel).into(),
+        size: Some(kernel.len()),
+        build_id: MrldBuildId::new(&build_id),
+    };
+    std::fs::write(manifest_path, manifest.to_string())?;
+    println!("[*] Wrote {} (build {})", manifest_path.display(), build_id);
+    Ok(())
+}